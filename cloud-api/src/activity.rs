@@ -0,0 +1,177 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use cloud_proto::proto::{ActivityKind, ListActivityRequest};
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    ClientSession, Database,
+};
+use tonic::Status;
+
+use crate::{
+    listing,
+    models::{to_date_time, DbActivity, DbActivityKind},
+};
+
+/// activities in a page if the request does not specify a page size
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// records an operation on a file in the transaction of the operation, so an operation
+/// that is rolled back leaves no activity
+pub async fn record(
+    db: &Database,
+    activity: &DbActivity,
+    session: &mut ClientSession,
+) -> Result<(), Status> {
+    db.collection::<DbActivity>("activities")
+        .insert_one_with_session(activity, None, session)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(())
+}
+
+/// The query of a page of a `ListActivity` request, activities are listed newest first.
+#[derive(Debug)]
+pub struct ActivityQuery {
+    pub filter: Document,
+    pub sort: Document,
+    pub page_size: usize,
+}
+
+impl ActivityQuery {
+    pub fn new(owner_id: ObjectId, request: &ListActivityRequest) -> Result<Self, Status> {
+        let mut filters = vec![doc! { "owner_id": owner_id }];
+
+        if !request.file_id.is_empty() {
+            let file_id = ObjectId::parse_str(&request.file_id)
+                .map_err(|_| Status::invalid_argument("invalid file id"))?;
+            filters.push(doc! { "file_id": file_id });
+        }
+
+        if !request.directory.is_empty() {
+            if let Some(regex) = listing::path_regex(&request.directory, "", true)? {
+                filters.push(doc! {
+                    "$or": [
+                        { "path": { "$regex": &regex } },
+                        { "old_path": { "$regex": &regex } },
+                    ]
+                });
+            }
+        }
+
+        if !request.kinds.is_empty() {
+            let kinds = request
+                .kinds
+                .iter()
+                .map(|&k| kind(k).and_then(|k| bson::to_bson(&k).ok()))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(Status::invalid_argument("invalid activity kind"))?;
+            filters.push(doc! { "kind": { "$in": kinds } });
+        }
+
+        if let Some(after) = &request.after {
+            filters.push(doc! { "occurred_at": { "$gte": to_date_time(after) } });
+        }
+
+        if let Some(before) = &request.before {
+            filters.push(doc! { "occurred_at": { "$lt": to_date_time(before) } });
+        }
+
+        if !request.page_token.is_empty() {
+            let before_id = page_token_id(&request.page_token)?;
+            filters.push(doc! { "_id": { "$lt": before_id } });
+        }
+
+        let page_size = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+
+        Ok(Self {
+            filter: doc! { "$and": filters },
+            sort: doc! { "_id": -1 },
+            page_size: page_size as usize,
+        })
+    }
+
+    /// the token of the page following `last`, the last activity of the current page
+    pub fn next_page_token(&self, last: &DbActivity) -> String {
+        URL_SAFE_NO_PAD.encode(bson::to_vec(&doc! { "id": last.id }).unwrap())
+    }
+}
+
+fn kind(kind: i32) -> Option<DbActivityKind> {
+    match ActivityKind::from_i32(kind)? {
+        ActivityKind::Upload => Some(DbActivityKind::Upload),
+        ActivityKind::Replace => Some(DbActivityKind::Replace),
+        ActivityKind::Delete => Some(DbActivityKind::Delete),
+        ActivityKind::Move => Some(DbActivityKind::Move),
+    }
+}
+
+fn page_token_id(page_token: &str) -> Result<ObjectId, Status> {
+    let invalid = || Status::invalid_argument("invalid page token");
+
+    let bytes = URL_SAFE_NO_PAD.decode(page_token).map_err(|_| invalid())?;
+    let token = bson::from_slice::<Document>(&bytes).map_err(|_| invalid())?;
+
+    token.get_object_id("id").map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud_proto::proto::{ActivityKind, ListActivityRequest};
+    use mongodb::bson::{doc, oid::ObjectId};
+
+    use crate::{
+        activity::ActivityQuery,
+        models::{DbActivity, DbActivityKind},
+    };
+
+    #[test]
+    fn filter_and_continue_activity() {
+        let owner_id = ObjectId::new();
+        let mut request = ListActivityRequest {
+            directory: "/docs".to_owned(),
+            kinds: vec![ActivityKind::Replace as i32, ActivityKind::Move as i32],
+            ..Default::default()
+        };
+
+        let query = ActivityQuery::new(owner_id, &request).unwrap();
+        assert_eq!(100, query.page_size);
+
+        let filters = query.filter.get_array("$and").unwrap();
+        assert_eq!(
+            Some(&doc! { "kind": { "$in": ["replace", "move"] } }),
+            filters[2].as_document()
+        );
+
+        let last = DbActivity {
+            id: ObjectId::new(),
+            owner_id,
+            file_id: ObjectId::new(),
+            kind: DbActivityKind::Move,
+            path: "/docs/b".to_owned(),
+            old_path: Some("/docs/a".to_owned()),
+            old_hash: None,
+            new_hash: None,
+            occurred_at: mongodb::bson::DateTime::now(),
+            session: ObjectId::new(),
+        };
+        request.page_token = query.next_page_token(&last);
+
+        let query = ActivityQuery::new(owner_id, &request).unwrap();
+        let filters = query.filter.get_array("$and").unwrap();
+        assert_eq!(
+            Some(&doc! { "_id": { "$lt": last.id } }),
+            filters.last().unwrap().as_document()
+        );
+
+        request.page_token = "invalid".to_owned();
+        assert!(ActivityQuery::new(owner_id, &request).is_err());
+
+        request.page_token = String::new();
+        request.kinds = vec![42];
+        assert!(ActivityQuery::new(owner_id, &request).is_err());
+    }
+}
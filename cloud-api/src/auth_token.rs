@@ -1,17 +1,55 @@
 use chrono::{Duration, Utc};
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use tonic::{Request, Status};
+
+use crate::models::DbUser;
+
+pub const SCOPE_ADMIN: &str = "admin";
+pub const SCOPE_FILES: &str = "files";
+pub const SCOPE_USER: &str = "user";
+
+const DEFAULT_SCOPES: &[&str] = &[SCOPE_FILES, SCOPE_USER];
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    /// tokens issued before sessions were introduced have none
+    #[serde(default)]
+    pub sid: Option<String>,
+    /// tokens issued before scopes were introduced are granted the default scopes
+    #[serde(default = "default_scopes")]
+    pub scopes: Vec<String>,
+}
+
+/// The caller of a request, inserted into the request extensions by [`authenticate`].
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub id: ObjectId,
+    pub scopes: Vec<String>,
+    /// identifies the sign-in the access token was issued for
+    pub session: ObjectId,
+}
+
+impl AuthenticatedUser {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
 }
 
-pub fn create_access_token(user_id: ObjectId) -> Option<String> {
+pub fn create_access_token(user: &DbUser) -> Option<String> {
+    let mut scopes = default_scopes();
+
+    if user.admin {
+        scopes.push(SCOPE_ADMIN.to_owned());
+    }
+
     let claims = Claims {
-        sub: user_id.to_string(),
+        sub: user.id.to_string(),
         exp: (Utc::now() + Duration::weeks(1)).timestamp() as usize,
+        sid: Some(ObjectId::new().to_string()),
+        scopes,
     };
 
     jsonwebtoken::encode(
@@ -32,20 +70,183 @@ pub fn validate_access_token(
     )
 }
 
-pub fn get_user_id_from_request<T>(request: &tonic::Request<T>) -> Result<ObjectId, tonic::Status> {
-    match request.metadata().get("authorization") {
-        Some(token) => match token.to_str() {
-            Ok(token) => {
-                let token = token.trim_start_matches("Baerer ");
-                let data = validate_access_token(token.to_owned())
-                    .map_err(|e| tonic::Status::unauthenticated(e.to_string()))?;
-                ObjectId::parse_str(data.claims.sub)
-                    .map_err(|e| tonic::Status::unauthenticated(e.to_string()))
-            }
-            Err(_) => Err(tonic::Status::unauthenticated(
-                "auth token is not a valid string",
-            )),
-        },
-        None => Err(tonic::Status::unauthenticated("auth token is missing")),
+/// Creates an interceptor that validates the bearer token of every request and requires
+/// the token to grant `scope`.
+///
+/// On success an [`AuthenticatedUser`] is inserted into the request extensions, which the
+/// services retrieve with [`authenticated_user`].
+pub fn authenticate(
+    scope: &'static str,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |mut request: Request<()>| {
+        let user = user_from_metadata(request.metadata())?;
+
+        if !user.has_scope(scope) {
+            return Err(Status::permission_denied(format!(
+                "auth token is missing the {} scope",
+                scope
+            )));
+        }
+
+        tracing::trace!("authenticated user {} in session {}", user.id, user.session);
+        request.extensions_mut().insert(user);
+        Ok(request)
+    }
+}
+
+pub fn authenticated_user<T>(request: &Request<T>) -> Result<&AuthenticatedUser, Status> {
+    request
+        .extensions()
+        .get::<AuthenticatedUser>()
+        .ok_or(Status::unauthenticated("request is not authenticated"))
+}
+
+fn user_from_metadata(
+    metadata: &tonic::metadata::MetadataMap,
+) -> Result<AuthenticatedUser, Status> {
+    let authorization = metadata
+        .get("authorization")
+        .ok_or(Status::unauthenticated("auth token is missing"))?
+        .to_str()
+        .map_err(|_| Status::unauthenticated("auth token is not a valid string"))?;
+
+    let token = bearer_token(authorization).ok_or(Status::unauthenticated(
+        "auth token must use the bearer scheme",
+    ))?;
+
+    let data = validate_access_token(token.to_owned())
+        .map_err(|e| Status::unauthenticated(e.to_string()))?;
+
+    let id =
+        ObjectId::parse_str(data.claims.sub).map_err(|e| Status::unauthenticated(e.to_string()))?;
+    // all tokens of a user issued without a session share the user id as their session
+    let session = match data.claims.sid {
+        Some(sid) => {
+            ObjectId::parse_str(sid).map_err(|e| Status::unauthenticated(e.to_string()))?
+        }
+        None => id,
+    };
+
+    Ok(AuthenticatedUser {
+        id,
+        scopes: data.claims.scopes,
+        session,
+    })
+}
+
+fn default_scopes() -> Vec<String> {
+    DEFAULT_SCOPES.iter().map(|s| s.to_string()).collect()
+}
+
+/// extracts the token of a `Bearer` authorization, the scheme is case-insensitive
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+
+    match scheme.eq_ignore_ascii_case("bearer") {
+        true => Some(token.trim()),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+    use tonic::Request;
+
+    use crate::{auth_token, models::DbUser};
+
+    fn user(admin: bool) -> DbUser {
+        DbUser {
+            id: ObjectId::new(),
+            email: "test@example.com".to_owned(),
+            username: "test".to_owned(),
+            passhash: None,
+            storage_quota: None,
+            storage_used: 0,
+            admin,
+        }
+    }
+
+    #[test]
+    fn bearer_token() {
+        assert_eq!(Some("abc"), auth_token::bearer_token("Bearer abc"));
+        assert_eq!(Some("abc"), auth_token::bearer_token("bearer abc"));
+        assert_eq!(None, auth_token::bearer_token("Baerer abc"));
+        assert_eq!(None, auth_token::bearer_token("abc"));
+    }
+
+    #[test]
+    fn authenticate() {
+        let db_user = user(false);
+        let token = auth_token::create_access_token(&db_user).unwrap();
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+
+        let request = auth_token::authenticate(auth_token::SCOPE_FILES)(request).unwrap();
+        let user = auth_token::authenticated_user(&request).unwrap();
+        assert_eq!(db_user.id, user.id);
+        assert!(user.has_scope(auth_token::SCOPE_FILES));
+        assert!(!user.has_scope(auth_token::SCOPE_ADMIN));
+    }
+
+    #[test]
+    fn authenticate_token_without_session_and_scopes() {
+        let user_id = ObjectId::new();
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &serde_json::json!({ "sub": user_id.to_string(), "exp": usize::MAX }),
+            &jsonwebtoken::EncodingKey::from_secret("secret".as_ref()),
+        )
+        .unwrap();
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+
+        let request = auth_token::authenticate(auth_token::SCOPE_FILES)(request).unwrap();
+        let user = auth_token::authenticated_user(&request).unwrap();
+        assert_eq!(user_id, user.id);
+        assert_eq!(user_id, user.session);
+        assert!(!user.has_scope(auth_token::SCOPE_ADMIN));
+    }
+
+    #[test]
+    fn authenticate_without_token() {
+        let request = auth_token::authenticate(auth_token::SCOPE_FILES)(Request::new(()));
+        assert_eq!(tonic::Code::Unauthenticated, request.unwrap_err().code());
+    }
+
+    #[test]
+    fn authenticate_without_scope() {
+        let token = auth_token::create_access_token(&user(false)).unwrap();
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+
+        let request = auth_token::authenticate(auth_token::SCOPE_ADMIN)(request);
+        assert_eq!(tonic::Code::PermissionDenied, request.unwrap_err().code());
+    }
+
+    #[test]
+    fn authenticate_admin() {
+        let token = auth_token::create_access_token(&user(true)).unwrap();
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+
+        let request = auth_token::authenticate(auth_token::SCOPE_ADMIN)(request);
+        assert!(request.is_ok());
     }
 }
@@ -0,0 +1,154 @@
+use std::{collections::HashMap, pin::Pin, sync::Arc};
+
+use anyhow::anyhow;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Document},
+    Database, GridFsBucket,
+};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
+
+use crate::{
+    compression,
+    crypto::{self, BlobEncryption, MasterKeys},
+    models::{DbChunk, DbFile},
+};
+
+pub type BlobStream = Pin<Box<dyn Stream<Item = Result<Vec<u8>, anyhow::Error>> + Send>>;
+
+/// opens the content of a file, which is either a single blob or the concatenation of its
+/// chunks
+pub async fn open_file(
+    db: &Database,
+    master_keys: Option<Arc<MasterKeys>>,
+    db_file: &DbFile,
+) -> Result<BlobStream, anyhow::Error> {
+    let bucket = db.gridfs_bucket(None);
+
+    let hashes = match (db_file.bucket_id, &db_file.chunks) {
+        (Some(bucket_id), _) => return open(&bucket, master_keys.as_deref(), bucket_id).await,
+        (None, Some(hashes)) => hashes.clone(),
+        (None, None) => return Err(anyhow!("file {} has no content", db_file.id)),
+    };
+
+    let mut cursor = db
+        .collection::<DbChunk>("chunks")
+        .find(
+            doc! { "owner_id": db_file.owner_id, "hash": { "$in": &hashes } },
+            None,
+        )
+        .await?;
+    let mut chunks = HashMap::new();
+
+    while let Some(db_chunk) = cursor.next().await {
+        let db_chunk = db_chunk?;
+        chunks.insert(db_chunk.hash, db_chunk.bucket_id);
+    }
+
+    // fail before anything has been sent
+    if let Some(hash) = hashes.iter().find(|h| !chunks.contains_key(*h)) {
+        return Err(anyhow!("chunk {} of file {} is missing", hash, db_file.id));
+    }
+
+    Ok(Box::pin(async_stream::try_stream! {
+        for hash in hashes {
+            let mut stream = open(&bucket, master_keys.as_deref(), chunks[&hash]).await?;
+
+            while let Some(bytes) = stream.next().await {
+                yield bytes?;
+            }
+        }
+    }))
+}
+
+/// opens the content of a blob, encrypted blobs are decrypted and compressed blobs are
+/// decompressed while they are read
+pub async fn open(
+    bucket: &GridFsBucket,
+    master_keys: Option<&MasterKeys>,
+    id: ObjectId,
+) -> Result<BlobStream, anyhow::Error> {
+    let metadata = find_metadata(bucket, id).await?;
+    let bucket_stream = bucket.open_download_stream(id.into()).await?;
+    let stream = ReaderStream::new(bucket_stream.compat());
+
+    let stream: BlobStream = match metadata.get("encryption") {
+        Some(encryption) => {
+            let encryption: BlobEncryption = bson::from_bson(encryption.clone())?;
+            let master_keys = master_keys
+                .ok_or_else(|| anyhow!("blob {} is encrypted but no master key is set", id))?;
+
+            crypto::decrypt_stream(stream, master_keys.decryptor(&encryption)?)
+        }
+        None => Box::pin(stream.map_ok(|b| b.to_vec()).err_into()),
+    };
+
+    match metadata.get_str("compression") {
+        Ok(compression::ZSTD) => Ok(compression::decompress_stream(stream)),
+        Ok(compression) => Err(anyhow!(
+            "blob {} uses the unknown compression {}",
+            id,
+            compression
+        )),
+        Err(_) => Ok(stream),
+    }
+}
+
+/// skips to `offset` in the stream and ends it after `length` bytes, the skipped bytes still
+/// have to be read, so ranges near the end of large blobs are expensive
+pub fn range(mut stream: BlobStream, offset: u64, length: u64) -> BlobStream {
+    Box::pin(async_stream::try_stream! {
+        let mut position = 0;
+        let end = offset.saturating_add(length);
+
+        while let Some(bytes) = stream.next().await {
+            let bytes = bytes?;
+            let start = position;
+            position += bytes.len() as u64;
+
+            if position <= offset {
+                continue;
+            }
+
+            let from = offset.saturating_sub(start) as usize;
+            let to = (end.min(position) - start) as usize;
+            yield bytes[from..to].to_vec();
+
+            if position >= end {
+                break;
+            }
+        }
+    })
+}
+
+async fn find_metadata(bucket: &GridFsBucket, id: ObjectId) -> Result<Document, anyhow::Error> {
+    let blob = bucket
+        .find(doc! { "_id": id }, None)
+        .await?
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("blob {} not found", id))??;
+
+    Ok(blob.metadata.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::TryStreamExt;
+
+    use crate::blob::{self, BlobStream};
+
+    #[tokio::test]
+    async fn range_spans_stream_items() {
+        let items: Vec<Result<Vec<u8>, anyhow::Error>> = vec![
+            Ok(b"hello ".to_vec()),
+            Ok(b"wide ".to_vec()),
+            Ok(b"world".to_vec()),
+        ];
+
+        let stream: BlobStream = Box::pin(futures_util::stream::iter(items));
+        let range: Vec<Vec<u8>> = blob::range(stream, 4, 9).try_collect().await.unwrap();
+
+        assert_eq!(b"o wide wo".to_vec(), range.concat());
+    }
+}
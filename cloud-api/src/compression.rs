@@ -0,0 +1,107 @@
+use std::{io::Write, path::Path};
+
+use futures_util::StreamExt;
+
+use crate::blob::BlobStream;
+
+/// value of the `compression` field of the blob metadata for zstd compressed blobs
+pub const ZSTD: &str = "zstd";
+
+/// extensions of formats that are compressed already and would only grow
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz", "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus",
+    "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// whether a file is worth compressing, judged by the extension of its path
+pub fn is_compressible(path: &str) -> bool {
+    match Path::new(path).extension() {
+        Some(extension) => {
+            let extension = extension.to_string_lossy().to_ascii_lowercase();
+            !COMPRESSED_EXTENSIONS.contains(&extension.as_str())
+        }
+        None => true,
+    }
+}
+
+/// Compresses a blob into a single zstd frame.
+pub struct Compressor(zstd::stream::write::Encoder<'static, Vec<u8>>);
+
+impl Compressor {
+    pub fn new(level: i32) -> Result<Self, std::io::Error> {
+        Ok(Self(zstd::stream::write::Encoder::new(Vec::new(), level)?))
+    }
+
+    /// returns the compressed bytes that are ready, zstd keeps the rest until it has
+    /// seen more input
+    pub fn compress(&mut self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        self.0.write_all(bytes)?;
+        Ok(std::mem::take(self.0.get_mut()))
+    }
+
+    pub fn finish(self) -> Result<Vec<u8>, std::io::Error> {
+        self.0.finish()
+    }
+}
+
+/// decompresses a zstd compressed blob while it is read
+pub fn decompress_stream(mut stream: BlobStream) -> BlobStream {
+    Box::pin(async_stream::try_stream! {
+        let mut decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+
+        while let Some(bytes) = stream.next().await {
+            decoder.write_all(&bytes?)?;
+            yield std::mem::take(decoder.get_mut());
+        }
+
+        decoder.flush()?;
+        yield std::mem::take(decoder.get_mut());
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use crate::compression::{self, Compressor};
+
+    #[test]
+    fn is_compressible() {
+        assert!(compression::is_compressible("/logs/server.log"));
+        assert!(compression::is_compressible("/data.csv"));
+        assert!(compression::is_compressible("/Makefile"));
+        assert!(!compression::is_compressible("/photos/IMG_0001.JPG"));
+        assert!(!compression::is_compressible("/backup.tar.gz"));
+    }
+
+    #[tokio::test]
+    async fn compress_decompress() {
+        let plaintext = "timestamp,level,message\n".repeat(10_000).into_bytes();
+
+        let mut compressor = Compressor::new(3).unwrap();
+        let mut compressed = Vec::new();
+
+        for chunk in plaintext.chunks(1000) {
+            compressed.extend(compressor.compress(chunk).unwrap());
+        }
+
+        compressed.extend(compressor.finish().unwrap());
+        assert!(compressed.len() * 10 < plaintext.len());
+
+        let chunks = compressed
+            .chunks(100)
+            .map(|c| Ok(c.to_vec()))
+            .collect::<Vec<_>>();
+        let decompressed =
+            compression::decompress_stream(Box::pin(futures_util::stream::iter(chunks)))
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .concat();
+
+        assert_eq!(plaintext, decompressed);
+    }
+}
@@ -1,10 +1,64 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
+use anyhow::bail;
 
 #[derive(Debug, Clone)]
 pub struct Configuration {
     pub database_url: String,
     pub server_endpoint: SocketAddr,
     pub user_storage_quota: u64,
+    /// which size of a file is charged to the storage quota of its owner
+    pub quota_charge: QuotaCharge,
+    /// zstd level blobs are compressed with, disabled if unset
+    pub compression_level: Option<i32>,
+    /// directory uploads are written to until they are verified
+    pub staging_dir: PathBuf,
+    /// `id:base64-key` entries of the master keys, blobs are stored in plaintext if neither
+    /// the keys nor a key file are set
+    pub master_keys: Option<String>,
+    pub master_key_file: Option<PathBuf>,
+    /// seconds between storage usage reconciliations, disabled if unset
+    pub storage_reconcile_interval: Option<u64>,
+    /// seconds between blob garbage collections, disabled if unset
+    pub blob_gc_interval: Option<u64>,
+    /// seconds an unreferenced blob is kept before it is collected
+    pub blob_gc_grace_period: u64,
+    /// seconds between storage scrubs, disabled if unset
+    pub scrub_interval: Option<u64>,
+    /// bytes per second a scrub reads at most, 0 for no limit
+    pub scrub_rate: u64,
+    /// directory of the full-text indexes of document content, content search is disabled
+    /// if unset
+    pub index_dir: Option<PathBuf>,
+    pub oidc: Option<OidcConfiguration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaCharge {
+    /// the size of the file as uploaded
+    Logical,
+    /// the size of the blob after compression and encryption
+    Stored,
+}
+
+impl FromStr for QuotaCharge {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logical" => Ok(QuotaCharge::Logical),
+            "stored" => Ok(QuotaCharge::Stored),
+            _ => bail!("quota charge must be logical or stored"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfiguration {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub display_name: String,
 }
 
 impl Configuration {
@@ -13,11 +67,65 @@ impl Configuration {
         let server_endpoint = dotenvy::var("API_ENDPOINT").map(|i| i.parse::<SocketAddr>())??;
         let user_storage_quota =
             dotenvy::var("API_USER_STORAGE_QUOTA").map(|i| i.parse::<u64>())??;
+        let quota_charge = dotenvy::var("API_QUOTA_CHARGE")
+            .map(|i| i.parse::<QuotaCharge>())
+            .unwrap_or(Ok(QuotaCharge::Logical))?;
+        let compression_level = dotenvy::var("API_COMPRESSION_LEVEL")
+            .ok()
+            .map(|i| i.parse::<i32>())
+            .transpose()?;
+        let staging_dir = dotenvy::var("API_STAGING_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| std::env::temp_dir().join("cloud-staging"));
+        let master_keys = dotenvy::var("API_MASTER_KEYS").ok();
+        let master_key_file = dotenvy::var("API_MASTER_KEY_FILE").ok().map(PathBuf::from);
+        let storage_reconcile_interval = dotenvy::var("API_STORAGE_RECONCILE_INTERVAL")
+            .ok()
+            .map(|i| i.parse::<u64>())
+            .transpose()?;
+        let blob_gc_interval = dotenvy::var("API_BLOB_GC_INTERVAL")
+            .ok()
+            .map(|i| i.parse::<u64>())
+            .transpose()?;
+        let blob_gc_grace_period = dotenvy::var("API_BLOB_GC_GRACE_PERIOD")
+            .map(|i| i.parse::<u64>())
+            .unwrap_or(Ok(86400))?;
+        let scrub_interval = dotenvy::var("API_SCRUB_INTERVAL")
+            .ok()
+            .map(|i| i.parse::<u64>())
+            .transpose()?;
+        let scrub_rate = dotenvy::var("API_SCRUB_RATE")
+            .map(|i| i.parse::<u64>())
+            .unwrap_or(Ok(16 * 1024 * 1024))?;
+        let index_dir = dotenvy::var("API_INDEX_DIR").ok().map(PathBuf::from);
+
+        let oidc = match dotenvy::var("API_OIDC_ISSUER") {
+            Ok(issuer) => Some(OidcConfiguration {
+                issuer,
+                client_id: dotenvy::var("API_OIDC_CLIENT_ID")?,
+                client_secret: dotenvy::var("API_OIDC_CLIENT_SECRET").ok(),
+                display_name: dotenvy::var("API_OIDC_DISPLAY_NAME")
+                    .unwrap_or_else(|_| "SSO".to_owned()),
+            }),
+            Err(_) => None,
+        };
 
         Ok(Configuration {
             database_url,
             server_endpoint,
             user_storage_quota,
+            quota_charge,
+            compression_level,
+            staging_dir,
+            master_keys,
+            master_key_file,
+            storage_reconcile_interval,
+            blob_gc_interval,
+            blob_gc_grace_period,
+            scrub_interval,
+            scrub_rate,
+            index_dir,
+            oidc,
         })
     }
 }
@@ -0,0 +1,107 @@
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+use quick_xml::{events::Event, Reader};
+
+/// extensions of files that are indexed as they are
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "text", "md", "markdown", "rst", "adoc", "org", "tex", "csv", "tsv", "log", "json",
+    "yaml", "yml", "toml", "ini", "xml", "html", "htm",
+];
+/// largest document part read from an office document
+const MAX_PART_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The formats whose text is indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Pdf,
+    Docx,
+    Odt,
+}
+
+impl Format {
+    /// the format of a file by its extension, `None` if it is not indexed
+    pub fn of(path: &str) -> Option<Self> {
+        let extension = Path::new(path)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+
+        match extension.as_str() {
+            e if TEXT_EXTENSIONS.contains(&e) => Some(Format::Text),
+            "pdf" => Some(Format::Pdf),
+            "docx" => Some(Format::Docx),
+            "odt" => Some(Format::Odt),
+            _ => None,
+        }
+    }
+
+    /// extracts the text of a document, content that is not valid in the format fails, e.g.
+    /// a file encrypted by the client
+    pub fn extract(self, content: &[u8]) -> Result<String, anyhow::Error> {
+        match self {
+            Format::Text => Ok(std::str::from_utf8(content)?.to_owned()),
+            Format::Pdf => Ok(pdf_extract::extract_text_from_mem(content)?),
+            Format::Docx => xml_text(&zip_part(content, "word/document.xml")?, &[b"w:p"]),
+            Format::Odt => xml_text(&zip_part(content, "content.xml")?, &[b"text:p", b"text:h"]),
+        }
+    }
+}
+
+fn zip_part(content: &[u8], name: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content))?;
+    let mut bytes = Vec::new();
+
+    archive
+        .by_name(name)?
+        .take(MAX_PART_SIZE)
+        .read_to_end(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// the text nodes of an xml document, a paragraph ends with a line break
+fn xml_text(xml: &[u8], paragraphs: &[&[u8]]) -> Result<String, anyhow::Error> {
+    let mut reader = Reader::from_reader(xml);
+    let mut text = String::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Text(t) => text.push_str(&t.unescape()?),
+            Event::End(e) if paragraphs.contains(&e.name().as_ref()) => text.push('\n'),
+            Event::Eof => return Ok(text),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use crate::content_index::extract::Format;
+
+    #[test]
+    fn extract_docx() {
+        let mut docx = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        docx.start_file("word/document.xml", Default::default())
+            .unwrap();
+        docx.write_all(
+            br#"<w:document><w:body><w:p><w:r><w:t>Quarterly &amp; annual</w:t></w:r></w:p><w:p><w:r><w:t>report</w:t></w:r></w:p></w:body></w:document>"#,
+        )
+        .unwrap();
+        let docx = docx.finish().unwrap().into_inner();
+
+        assert_eq!(Some(Format::Docx), Format::of("/a/Report.DOCX"));
+        assert_eq!(
+            "Quarterly & annual\nreport\n",
+            Format::Docx.extract(&docx).unwrap()
+        );
+
+        assert_eq!(None, Format::of("/a/image.png"));
+        assert!(Format::Text.extract(&[0xff, 0xfe, 0x00]).is_err());
+    }
+}
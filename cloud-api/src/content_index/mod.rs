@@ -0,0 +1,305 @@
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures_util::StreamExt;
+use mongodb::bson::{doc, oid::ObjectId};
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    query::QueryParser,
+    schema::{Field, Schema, Value, STORED, STRING, TEXT},
+    snippet::SnippetGenerator,
+    Index, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+use tokio::sync::mpsc;
+use tonic::Status;
+
+use crate::{blob, crypto::MasterKeys, models::DbFile};
+
+use self::extract::Format;
+
+mod extract;
+
+/// largest file whose content is indexed
+const MAX_INDEXED_SIZE: u64 = 32 * 1024 * 1024;
+/// text of a document beyond this many bytes is not indexed
+const MAX_TEXT_SIZE: usize = 4 * 1024 * 1024;
+const WRITER_MEMORY: usize = 32 * 1024 * 1024;
+const SNIPPET_CHARS: usize = 200;
+/// matches returned if the request does not specify a limit
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+
+/// A document matching a content search.
+#[derive(Debug)]
+pub struct ContentMatch {
+    pub file_id: ObjectId,
+    pub score: f32,
+    /// an excerpt of the text around the matched terms
+    pub snippet: String,
+    /// byte ranges of the matched terms in the snippet
+    pub highlights: Vec<Range<usize>>,
+}
+
+/// Full-text indexes of the content of text and office documents.
+///
+/// Every user has a tantivy index in a directory of its own. A document only holds the file
+/// id and the extracted text, the path is looked up when a search matches, so moving a file
+/// does not touch the index.
+#[derive(Debug)]
+pub struct ContentIndex {
+    dir: PathBuf,
+    queue: mpsc::UnboundedSender<(ObjectId, ObjectId)>,
+}
+
+impl ContentIndex {
+    /// starts the worker that indexes the files passed to [`ContentIndex::update`]
+    pub fn start(
+        dir: PathBuf,
+        mongo: mongodb::Client,
+        master_keys: Option<Arc<MasterKeys>>,
+    ) -> Self {
+        let (queue, mut rx) = mpsc::unbounded_channel();
+        let index_dir = dir.clone();
+
+        tokio::spawn(async move {
+            while let Some((owner_id, file_id)) = rx.recv().await {
+                let index_res =
+                    index_file(&index_dir, &mongo, master_keys.clone(), owner_id, file_id).await;
+
+                if let Err(e) = index_res {
+                    tracing::warn!("failed to index file {}: {:?}", file_id, e);
+                }
+            }
+        });
+
+        Self { dir, queue }
+    }
+
+    /// indexes a file again after it was committed or deleted, files are indexed one after
+    /// another in the background
+    pub fn update(&self, owner_id: ObjectId, file_id: ObjectId) {
+        self.queue.send((owner_id, file_id)).ok();
+    }
+
+    /// the documents of the owner matching `query` ordered by relevance
+    pub async fn search(
+        &self,
+        owner_id: ObjectId,
+        query: String,
+        limit: u32,
+    ) -> Result<Vec<ContentMatch>, Status> {
+        let dir = user_dir(&self.dir, owner_id);
+        let limit = match limit {
+            0 => DEFAULT_LIMIT,
+            limit => limit.min(MAX_LIMIT),
+        };
+
+        tokio::task::spawn_blocking(move || search(&dir, &query, limit as usize))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+    }
+}
+
+fn user_dir(dir: &Path, owner_id: ObjectId) -> PathBuf {
+    dir.join(owner_id.to_hex())
+}
+
+fn schema() -> (Schema, Field, Field) {
+    let mut schema = Schema::builder();
+    let file_id = schema.add_text_field("file_id", STRING | STORED);
+    // the text is stored for the snippets
+    let content = schema.add_text_field("content", TEXT | STORED);
+
+    (schema.build(), file_id, content)
+}
+
+fn open(dir: &Path) -> Result<(Index, Field, Field), tantivy::TantivyError> {
+    let (schema, file_id, content) = schema();
+
+    std::fs::create_dir_all(dir)?;
+    let index = Index::open_or_create(MmapDirectory::open(dir)?, schema)?;
+
+    Ok((index, file_id, content))
+}
+
+async fn index_file(
+    dir: &Path,
+    mongo: &mongodb::Client,
+    master_keys: Option<Arc<MasterKeys>>,
+    owner_id: ObjectId,
+    file_id: ObjectId,
+) -> Result<(), anyhow::Error> {
+    let db = mongo.database("cloud");
+    let db_file = db
+        .collection::<DbFile>("files")
+        .find_one(doc! { "_id": file_id, "owner_id": owner_id }, None)
+        .await?;
+
+    // a file that was deleted or replaced by content that is not indexed is removed
+    let text = match db_file {
+        Some(db_file) => extract_file(&db, master_keys, &db_file).await?,
+        None => None,
+    };
+
+    let dir = user_dir(dir, owner_id);
+    tokio::task::spawn_blocking(move || write(&dir, file_id, text)).await?
+}
+
+/// the text of a file, `None` if its content is not indexed
+async fn extract_file(
+    db: &mongodb::Database,
+    master_keys: Option<Arc<MasterKeys>>,
+    db_file: &DbFile,
+) -> Result<Option<String>, anyhow::Error> {
+    let format = match Format::of(&db_file.path) {
+        Some(format) if db_file.size <= MAX_INDEXED_SIZE => format,
+        _ => return Ok(None),
+    };
+
+    let mut blob_stream = blob::open_file(db, master_keys, db_file).await?;
+    let mut content = Vec::with_capacity(db_file.size as usize);
+
+    while let Some(bytes) = blob_stream.next().await {
+        content.extend(bytes?);
+    }
+
+    // the extraction of a malformed document may panic
+    let extract_res = tokio::task::spawn_blocking(move || format.extract(&content)).await;
+
+    match extract_res {
+        Ok(Ok(mut text)) => {
+            if text.len() > MAX_TEXT_SIZE {
+                let end = (0..=MAX_TEXT_SIZE)
+                    .rev()
+                    .find(|i| text.is_char_boundary(*i))
+                    .unwrap();
+                text.truncate(end);
+            }
+
+            Ok(Some(text))
+        }
+        Ok(Err(e)) => {
+            tracing::debug!("could not extract text of {}: {:?}", db_file.id, e);
+            Ok(None)
+        }
+        Err(e) => {
+            tracing::debug!("text extraction of {} panicked: {:?}", db_file.id, e);
+            Ok(None)
+        }
+    }
+}
+
+/// replaces the document of a file, `None` removes it
+fn write(dir: &Path, file_id: ObjectId, text: Option<String>) -> Result<(), anyhow::Error> {
+    if text.is_none() && !dir.exists() {
+        return Ok(());
+    }
+
+    let (index, file_id_field, content_field) = open(dir)?;
+    let mut writer: IndexWriter = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+
+    writer.delete_term(Term::from_field_text(file_id_field, &file_id.to_hex()));
+
+    if let Some(text) = text {
+        let mut document = TantivyDocument::new();
+        document.add_text(file_id_field, file_id.to_hex());
+        document.add_text(content_field, text);
+        writer.add_document(document)?;
+    }
+
+    writer.commit()?;
+    Ok(())
+}
+
+fn search(dir: &Path, query: &str, limit: usize) -> Result<Vec<ContentMatch>, Status> {
+    // users without indexed documents have no index yet
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let (index, file_id_field, content_field) =
+        open(dir).map_err(|e| Status::internal(e.to_string()))?;
+
+    let reader = index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::Manual)
+        .try_into()
+        .map_err(|e: tantivy::TantivyError| Status::internal(e.to_string()))?;
+    let searcher = reader.searcher();
+
+    let query = QueryParser::for_index(&index, vec![content_field])
+        .parse_query(query)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+    let top_docs = searcher
+        .search(&query, &TopDocs::with_limit(limit))
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    let mut snippets = SnippetGenerator::create(&searcher, &*query, content_field)
+        .map_err(|e| Status::internal(e.to_string()))?;
+    snippets.set_max_num_chars(SNIPPET_CHARS);
+
+    let mut matches = Vec::with_capacity(top_docs.len());
+
+    for (score, address) in top_docs {
+        let document = searcher
+            .doc::<TantivyDocument>(address)
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let file_id = document
+            .get_first(file_id_field)
+            .and_then(|v| v.as_str())
+            .and_then(|id| ObjectId::parse_str(id).ok())
+            .ok_or(Status::internal("indexed document has no file id"))?;
+        let snippet = snippets.snippet_from_doc(&document);
+
+        matches.push(ContentMatch {
+            file_id,
+            score,
+            snippet: snippet.fragment().to_owned(),
+            highlights: snippet.highlighted().to_vec(),
+        });
+    }
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::content_index;
+
+    #[test]
+    fn search_finds_replaced_and_deleted_documents() {
+        let dir = std::env::temp_dir().join(format!("cloud-index-{}", std::process::id()));
+        let report = ObjectId::new();
+        let notes = ObjectId::new();
+
+        content_index::write(&dir, report, Some("the quarterly report".to_owned())).unwrap();
+        content_index::write(&dir, notes, Some("meeting notes".to_owned())).unwrap();
+
+        let matches = content_index::search(&dir, "quarterly", 10).unwrap();
+        assert_eq!(1, matches.len());
+        assert_eq!(report, matches[0].file_id);
+        assert_eq!("the quarterly report", matches[0].snippet);
+        assert_eq!(vec![4..13], matches[0].highlights);
+
+        // a replaced document only matches its new text
+        content_index::write(&dir, report, Some("the annual report".to_owned())).unwrap();
+        assert!(content_index::search(&dir, "quarterly", 10)
+            .unwrap()
+            .is_empty());
+
+        content_index::write(&dir, notes, None).unwrap();
+        assert!(content_index::search(&dir, "notes", 10).unwrap().is_empty());
+
+        assert!(content_index::search(&dir, "report AND (", 10).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::{fmt::Debug, pin::Pin};
+
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit,
+    },
+    Key, XChaCha20Poly1305, XNonce,
+};
+use futures_util::{Stream, StreamExt};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Configuration;
+
+/// plaintext bytes sealed together, every stored segment is followed by its tag
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const KEY_SIZE: usize = 32;
+const KEY_NONCE_SIZE: usize = 24;
+/// the stream construction uses 5 bytes of the nonce for the segment counter
+const STREAM_NONCE_SIZE: usize = 19;
+
+/// How the data key of a blob is wrapped, stored in the `encryption` field of the blob
+/// metadata. Blobs without it are stored in plaintext.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobEncryption {
+    pub key_id: String,
+    pub key_nonce: String,
+    pub wrapped_key: String,
+    pub stream_nonce: String,
+}
+
+/// The master keys that wrap the data keys of blobs.
+///
+/// The first key wraps new data keys, the others are kept to unwrap data keys until
+/// they have been rotated.
+pub struct MasterKeys {
+    keys: Vec<(String, XChaCha20Poly1305)>,
+}
+
+impl Debug for MasterKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field(
+                "ids",
+                &self.keys.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    /// parses `id:base64-key` entries separated by commas or new lines
+    pub fn parse(s: &str) -> Result<Self, anyhow::Error> {
+        let mut keys = Vec::new();
+
+        for entry in s.split([',', '\n']) {
+            let entry = entry.trim();
+
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| anyhow!("master key must have the format id:base64-key"))?;
+            let key = STANDARD.decode(key)?;
+
+            if key.len() != KEY_SIZE {
+                bail!("master key {} must be {} bytes long", id, KEY_SIZE);
+            }
+
+            if keys.iter().any(|(i, _)| i == id) {
+                bail!("master key {} is defined twice", id);
+            }
+
+            keys.push((id.to_owned(), XChaCha20Poly1305::new(Key::from_slice(&key))));
+        }
+
+        if keys.is_empty() {
+            bail!("no master key defined");
+        }
+
+        Ok(Self { keys })
+    }
+
+    /// loads the master keys if encryption at rest is configured
+    pub fn from_config(config: &Configuration) -> Result<Option<Self>, anyhow::Error> {
+        if let Some(master_keys) = &config.master_keys {
+            return Ok(Some(Self::parse(master_keys)?));
+        }
+
+        match &config.master_key_file {
+            Some(path) => Ok(Some(Self::parse(&std::fs::read_to_string(path)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn active_id(&self) -> &str {
+        &self.keys[0].0
+    }
+
+    /// creates the data key of a new blob
+    pub fn encryptor(&self) -> Result<(SegmentEncryptor, BlobEncryption), anyhow::Error> {
+        let mut key = [0; KEY_SIZE];
+        let mut stream_nonce = [0; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut stream_nonce);
+
+        let encryption = self.wrap(Key::from_slice(&key), STANDARD.encode(stream_nonce))?;
+        let encryptor = EncryptorBE32::from_aead(
+            XChaCha20Poly1305::new(Key::from_slice(&key)),
+            GenericArray::from_slice(&stream_nonce),
+        );
+
+        Ok((SegmentEncryptor(Some(encryptor)), encryption))
+    }
+
+    pub fn decryptor(
+        &self,
+        encryption: &BlobEncryption,
+    ) -> Result<SegmentDecryptor, anyhow::Error> {
+        let key = self.unwrap(encryption)?;
+        let stream_nonce = STANDARD.decode(&encryption.stream_nonce)?;
+
+        if stream_nonce.len() != STREAM_NONCE_SIZE {
+            bail!("invalid stream nonce");
+        }
+
+        let decryptor = DecryptorBE32::from_aead(
+            XChaCha20Poly1305::new(&key),
+            GenericArray::from_slice(&stream_nonce),
+        );
+
+        Ok(SegmentDecryptor(Some(decryptor)))
+    }
+
+    /// wraps the data key of a blob with the active master key
+    pub fn rewrap(&self, encryption: &BlobEncryption) -> Result<BlobEncryption, anyhow::Error> {
+        let key = self.unwrap(encryption)?;
+        self.wrap(&key, encryption.stream_nonce.to_owned())
+    }
+
+    fn wrap(&self, key: &Key, stream_nonce: String) -> Result<BlobEncryption, anyhow::Error> {
+        let (key_id, master_key) = &self.keys[0];
+
+        let mut key_nonce = [0; KEY_NONCE_SIZE];
+        OsRng.fill_bytes(&mut key_nonce);
+
+        let wrapped_key = master_key
+            .encrypt(XNonce::from_slice(&key_nonce), key.as_slice())
+            .map_err(|_| anyhow!("failed to wrap data key"))?;
+
+        Ok(BlobEncryption {
+            key_id: key_id.to_owned(),
+            key_nonce: STANDARD.encode(key_nonce),
+            wrapped_key: STANDARD.encode(wrapped_key),
+            stream_nonce,
+        })
+    }
+
+    fn unwrap(&self, encryption: &BlobEncryption) -> Result<Key, anyhow::Error> {
+        let master_key = self
+            .keys
+            .iter()
+            .find(|(id, _)| *id == encryption.key_id)
+            .map(|(_, k)| k)
+            .ok_or_else(|| anyhow!("unknown master key {}", encryption.key_id))?;
+
+        let key_nonce = STANDARD.decode(&encryption.key_nonce)?;
+        let wrapped_key = STANDARD.decode(&encryption.wrapped_key)?;
+
+        if key_nonce.len() != KEY_NONCE_SIZE {
+            bail!("invalid data key nonce");
+        }
+
+        let key = master_key
+            .decrypt(XNonce::from_slice(&key_nonce), wrapped_key.as_slice())
+            .map_err(|_| anyhow!("failed to unwrap data key"))?;
+
+        if key.len() != KEY_SIZE {
+            bail!("invalid data key");
+        }
+
+        Ok(*Key::from_slice(&key))
+    }
+}
+
+pub struct SegmentEncryptor(Option<EncryptorBE32<XChaCha20Poly1305>>);
+
+impl SegmentEncryptor {
+    /// seals the next segment, `last` has to be set on the final segment only
+    pub fn encrypt(&mut self, segment: &[u8], last: bool) -> Result<Vec<u8>, anyhow::Error> {
+        let encrypt_res = match last {
+            true => self
+                .0
+                .take()
+                .ok_or_else(|| anyhow!("blob is already finished"))?
+                .encrypt_last(segment),
+            false => self
+                .0
+                .as_mut()
+                .ok_or_else(|| anyhow!("blob is already finished"))?
+                .encrypt_next(segment),
+        };
+
+        encrypt_res.map_err(|_| anyhow!("failed to encrypt segment"))
+    }
+}
+
+pub struct SegmentDecryptor(Option<DecryptorBE32<XChaCha20Poly1305>>);
+
+impl SegmentDecryptor {
+    pub fn decrypt(&mut self, segment: &[u8], last: bool) -> Result<Vec<u8>, anyhow::Error> {
+        let decrypt_res = match last {
+            true => self
+                .0
+                .take()
+                .ok_or_else(|| anyhow!("blob is already finished"))?
+                .decrypt_last(segment),
+            false => self
+                .0
+                .as_mut()
+                .ok_or_else(|| anyhow!("blob is already finished"))?
+                .decrypt_next(segment),
+        };
+
+        decrypt_res.map_err(|_| anyhow!("blob failed authentication"))
+    }
+}
+
+/// decrypts a stream of sealed segments, truncated or modified blobs fail authentication
+pub fn decrypt_stream<S, B, E>(
+    mut stream: S,
+    mut decryptor: SegmentDecryptor,
+) -> Pin<Box<dyn Stream<Item = Result<Vec<u8>, anyhow::Error>> + Send>>
+where
+    S: Stream<Item = Result<B, E>> + Send + Unpin + 'static,
+    B: AsRef<[u8]> + Send,
+    E: Into<anyhow::Error> + Send,
+{
+    Box::pin(async_stream::try_stream! {
+        let sealed_size = SEGMENT_SIZE + TAG_SIZE;
+        let mut buf = Vec::with_capacity(sealed_size * 2);
+
+        while let Some(bytes) = stream.next().await {
+            buf.extend_from_slice(bytes.map_err(Into::into)?.as_ref());
+
+            // a segment is only known not to be the last one once more bytes follow it
+            while buf.len() > sealed_size {
+                let rest = buf.split_off(sealed_size);
+                yield decryptor.decrypt(&buf, false)?;
+                buf = rest;
+            }
+        }
+
+        yield decryptor.decrypt(&buf, true)?;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use futures_util::StreamExt;
+
+    use crate::crypto::{self, MasterKeys};
+
+    fn master_key(id: &str, byte: u8) -> String {
+        format!("{}:{}", id, STANDARD.encode([byte; 32]))
+    }
+
+    async fn roundtrip(keys: &MasterKeys, plaintext: &[u8]) -> Vec<u8> {
+        let (mut encryptor, encryption) = keys.encryptor().unwrap();
+        let segments = plaintext.chunks(crypto::SEGMENT_SIZE).collect::<Vec<_>>();
+        let mut sealed = Vec::new();
+
+        match segments.len() {
+            0 => sealed.extend(encryptor.encrypt(&[], true).unwrap()),
+            len => {
+                for (i, segment) in segments.into_iter().enumerate() {
+                    sealed.extend(encryptor.encrypt(segment, i + 1 == len).unwrap());
+                }
+            }
+        }
+
+        // split the sealed blob at positions unrelated to the segments
+        let parts = sealed
+            .chunks(1000)
+            .map(|c| Ok::<_, std::io::Error>(c.to_vec()))
+            .collect::<Vec<_>>();
+        let decryptor = keys.decryptor(&encryption).unwrap();
+        let stream = crypto::decrypt_stream(futures_util::stream::iter(parts), decryptor);
+
+        stream
+            .map(|s| s.unwrap())
+            .collect::<Vec<_>>()
+            .await
+            .concat()
+    }
+
+    #[test]
+    fn parse() {
+        let keys = MasterKeys::parse(&format!("{},{}", master_key("a", 1), master_key("b", 2)));
+        assert_eq!("a", keys.unwrap().active_id());
+
+        assert!(MasterKeys::parse("").is_err());
+        assert!(MasterKeys::parse("a:AAAA").is_err());
+        assert!(
+            MasterKeys::parse(&format!("{}\n{}", master_key("a", 1), master_key("a", 2))).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypt_decrypt() {
+        let keys = MasterKeys::parse(&master_key("a", 1)).unwrap();
+
+        for len in [0, 10, crypto::SEGMENT_SIZE, crypto::SEGMENT_SIZE * 2 + 7] {
+            let plaintext = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            assert_eq!(plaintext, roundtrip(&keys, &plaintext).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_blob_fails() {
+        let keys = MasterKeys::parse(&master_key("a", 1)).unwrap();
+        let (mut encryptor, encryption) = keys.encryptor().unwrap();
+
+        let first = encryptor
+            .encrypt(&[1; crypto::SEGMENT_SIZE], false)
+            .unwrap();
+        encryptor.encrypt(&[2; 10], true).unwrap();
+
+        let decryptor = keys.decryptor(&encryption).unwrap();
+        let stream = crypto::decrypt_stream(
+            futures_util::stream::iter(vec![Ok::<_, std::io::Error>(first)]),
+            decryptor,
+        );
+
+        let res = stream.collect::<Vec<_>>().await;
+        assert!(res.last().unwrap().is_err());
+    }
+
+    #[test]
+    fn rewrap() {
+        let old_keys = MasterKeys::parse(&master_key("a", 1)).unwrap();
+        let (_, encryption) = old_keys.encryptor().unwrap();
+
+        let keys = MasterKeys::parse(&format!("{},{}", master_key("b", 2), master_key("a", 1)));
+        let keys = keys.unwrap();
+        let rewrapped = keys.rewrap(&encryption).unwrap();
+        assert_eq!("b", rewrapped.key_id);
+
+        let new_keys = MasterKeys::parse(&master_key("b", 2)).unwrap();
+        assert!(new_keys.decryptor(&rewrapped).is_ok());
+        assert!(new_keys.decryptor(&encryption).is_err());
+    }
+}
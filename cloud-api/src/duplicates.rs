@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+
+use cloud_proto::proto;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    Database,
+};
+use serde::Deserialize;
+use tonic::Status;
+
+use crate::models::DbFile;
+
+/// groups returned if the request does not specify a limit
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// Files of an owner with the same content, as found by the aggregation in [`find`].
+#[derive(Debug, Deserialize)]
+struct DuplicateGroup {
+    #[serde(rename = "_id")]
+    content: Content,
+    file_ids: Vec<ObjectId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Content {
+    hash: String,
+    size: u64,
+}
+
+/// the groups of files of an owner that have the same hash and size and are at least
+/// `min_size` bytes large, those wasting the most bytes first
+pub async fn find(
+    db: &Database,
+    owner_id: ObjectId,
+    min_size: u64,
+    limit: u32,
+) -> Result<Vec<proto::DuplicateGroup>, Status> {
+    let limit = match limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit.min(MAX_LIMIT),
+    };
+    let min_size = i64::try_from(min_size.max(1)).unwrap_or(i64::MAX);
+
+    let db_files = db.collection::<DbFile>("files");
+
+    let groups = db_files
+        .aggregate(
+            [
+                doc! { "$match": { "owner_id": owner_id, "size": { "$gte": min_size } } },
+                doc! { "$group": {
+                    "_id": { "hash": "$hash", "size": "$size" },
+                    "file_ids": { "$push": "$_id" },
+                } },
+                doc! { "$match": { "file_ids.1": { "$exists": true } } },
+                doc! { "$addFields": {
+                    "wasted_bytes": {
+                        "$multiply": ["$_id.size", { "$subtract": [{ "$size": "$file_ids" }, 1] }]
+                    },
+                } },
+                doc! { "$sort": { "wasted_bytes": -1, "_id.hash": 1 } },
+                doc! { "$limit": limit as i64 },
+            ],
+            None,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .and_then(|d| async { Ok(bson::from_document::<DuplicateGroup>(d)?) })
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    let file_ids = groups
+        .iter()
+        .flat_map(|g| g.file_ids.iter())
+        .collect::<Vec<_>>();
+
+    let group_files = db_files
+        .find(
+            doc! { "_id": { "$in": file_ids }, "owner_id": owner_id },
+            None,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .try_collect::<Vec<_>>()
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(to_proto(groups, group_files))
+}
+
+/// fills the groups with their files, the oldest first, groups that lost their copies since
+/// they were found are left out
+fn to_proto(groups: Vec<DuplicateGroup>, db_files: Vec<DbFile>) -> Vec<proto::DuplicateGroup> {
+    let mut db_files = db_files
+        .into_iter()
+        .map(|f| (f.id, f))
+        .collect::<HashMap<_, _>>();
+
+    groups
+        .into_iter()
+        .filter_map(|group| {
+            let mut files = group
+                .file_ids
+                .iter()
+                .filter_map(|id| db_files.remove(id))
+                // a copy that was replaced since has other content
+                .filter(|f| f.hash == group.content.hash && f.size == group.content.size)
+                .collect::<Vec<_>>();
+
+            if files.len() < 2 {
+                return None;
+            }
+
+            files.sort_by_key(|f| (f.created_at, f.id));
+
+            Some(proto::DuplicateGroup {
+                wasted_bytes: group.content.size * (files.len() as u64 - 1),
+                hash: group.content.hash,
+                size: group.content.size,
+                files: files.iter().map(|f| f.to_proto()).collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{self, oid::ObjectId};
+
+    use crate::{
+        duplicates::{self, Content, DuplicateGroup},
+        models::DbFile,
+    };
+
+    fn db_file(path: &str, hash: &str, created_at: i64) -> DbFile {
+        DbFile {
+            id: ObjectId::new(),
+            owner_id: ObjectId::new(),
+            bucket_id: None,
+            chunks: None,
+            outboard_id: None,
+            path: path.to_owned(),
+            hash: hash.to_owned(),
+            size: 10,
+            stored_size: None,
+            created_at: bson::DateTime::from_millis(created_at),
+            modified_at: bson::DateTime::from_millis(created_at),
+            mtime: None,
+            mode: None,
+            mime_type: "application/octet-stream".to_owned(),
+            tags: Vec::new(),
+            favorite: false,
+            metadata: Default::default(),
+            media: None,
+        }
+    }
+
+    #[test]
+    fn groups_hold_remaining_copies_oldest_first() {
+        let mut db_files = vec![
+            db_file("/b", "x", 2),
+            db_file("/a", "x", 1),
+            db_file("/c", "x", 3),
+            db_file("/d", "y", 1),
+            db_file("/e", "z", 1),
+            db_file("/f", "z", 2),
+        ];
+        let group = |hash: &str, file_ids: Vec<ObjectId>| DuplicateGroup {
+            content: Content {
+                hash: hash.to_owned(),
+                size: 10,
+            },
+            file_ids,
+        };
+
+        let ids = db_files.iter().map(|f| f.id).collect::<Vec<_>>();
+        let groups = vec![
+            group("x", ids[..3].to_vec()),
+            // the other copy has been deleted
+            group("y", vec![ids[3], ObjectId::new()]),
+            group("z", vec![ids[4], ids[5]]),
+        ];
+        // the other copy of z has been replaced since
+        db_files[5].hash = "w".to_owned();
+
+        let groups = duplicates::to_proto(groups, db_files);
+        assert_eq!(1, groups.len());
+        assert_eq!(20, groups[0].wasted_bytes);
+
+        let paths = groups[0].files.iter().map(|f| f.path.as_str());
+        assert_eq!(vec!["/a", "/b", "/c"], paths.collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,179 @@
+//! A minimal OpenID Connect provider that runs on the loopback interface, so the oidc
+//! flow can be tested without an external identity provider.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{Duration, Utc};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use url::Url;
+
+use crate::identity;
+
+#[derive(Debug)]
+struct PendingCode {
+    challenge: String,
+    nonce: String,
+    redirect_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+struct MockClaims {
+    iss: String,
+    aud: String,
+    sub: String,
+    exp: usize,
+    nonce: String,
+    email: String,
+    email_verified: bool,
+    preferred_username: String,
+}
+
+type PendingCodes = Arc<Mutex<HashMap<String, PendingCode>>>;
+
+pub struct MockIdp {
+    pub issuer: String,
+}
+
+impl MockIdp {
+    pub const CLIENT_ID: &str = "cloud-desktop";
+    pub const CLIENT_SECRET: &str = "mock-secret";
+    pub const SUBJECT: &str = "mock-subject";
+    pub const EMAIL: &str = "mock@example.com";
+
+    pub fn start() -> MockIdp {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let codes = PendingCodes::default();
+
+        let make_svc = {
+            let issuer = issuer.clone();
+            make_service_fn(move |_| {
+                let issuer = issuer.clone();
+                let codes = codes.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        handle(issuer.clone(), codes.clone(), req)
+                    }))
+                }
+            })
+        };
+
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        tokio::spawn(server);
+
+        MockIdp { issuer }
+    }
+}
+
+async fn handle(
+    issuer: String,
+    codes: PendingCodes,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let url = Url::parse(&format!("{}{}", issuer, req.uri())).unwrap();
+
+    let res = match (method, url.path()) {
+        (Method::GET, "/.well-known/openid-configuration") => json(
+            StatusCode::OK,
+            serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            }),
+        ),
+        (Method::GET, "/authorize") => {
+            let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+            let code = identity::random_token(16);
+
+            codes.lock().unwrap().insert(
+                code.to_owned(),
+                PendingCode {
+                    challenge: params["code_challenge"].to_owned(),
+                    nonce: params["nonce"].to_owned(),
+                    redirect_uri: params["redirect_uri"].to_owned(),
+                },
+            );
+
+            let mut location = Url::parse(&params["redirect_uri"]).unwrap();
+            location
+                .query_pairs_mut()
+                .append_pair("code", &code)
+                .append_pair("state", &params["state"]);
+
+            Response::builder()
+                .status(StatusCode::FOUND)
+                .header("location", location.as_str())
+                .body(Body::empty())
+                .unwrap()
+        }
+        (Method::POST, "/token") => {
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let params = url::form_urlencoded::parse(&body)
+                .into_owned()
+                .collect::<HashMap<_, _>>();
+
+            let pending = codes.lock().unwrap().remove(&params["code"]);
+
+            match pending {
+                Some(pending)
+                    if pending.redirect_uri == params["redirect_uri"]
+                        && pending.challenge
+                            == identity::pkce_challenge(&params["code_verifier"])
+                        && params.get("client_secret").map(String::as_str)
+                            == Some(MockIdp::CLIENT_SECRET) =>
+                {
+                    let claims = MockClaims {
+                        iss: issuer,
+                        aud: MockIdp::CLIENT_ID.to_owned(),
+                        sub: MockIdp::SUBJECT.to_owned(),
+                        exp: (Utc::now() + Duration::minutes(5)).timestamp() as usize,
+                        nonce: pending.nonce,
+                        email: MockIdp::EMAIL.to_owned(),
+                        email_verified: true,
+                        preferred_username: "mock".to_owned(),
+                    };
+
+                    let id_token = jsonwebtoken::encode(
+                        &jsonwebtoken::Header::default(),
+                        &claims,
+                        &jsonwebtoken::EncodingKey::from_secret(MockIdp::CLIENT_SECRET.as_ref()),
+                    )
+                    .unwrap();
+
+                    json(
+                        StatusCode::OK,
+                        serde_json::json!({ "token_type": "Bearer", "id_token": id_token }),
+                    )
+                }
+                _ => json(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "error": "invalid_grant" }),
+                ),
+            }
+        }
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap(),
+    };
+
+    Ok(res)
+}
+
+fn json(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
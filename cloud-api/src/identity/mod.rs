@@ -0,0 +1,79 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+#[cfg(test)]
+mod mock;
+pub mod oidc;
+
+/// An identity asserted by an external provider after a successful sign-in.
+#[derive(Debug, Clone)]
+pub struct ExternalIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+/// A sign-in that has been started but not yet completed by the client.
+///
+/// The verifier and nonce never leave the server; only the url and the state are handed
+/// to the client.
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub pkce_verifier: String,
+    pub nonce: String,
+}
+
+#[tonic::async_trait]
+pub trait IdentityProvider: std::fmt::Debug + Send + Sync {
+    /// Stable identifier used to key external subjects in the `identities` collection.
+    fn id(&self) -> &str;
+
+    fn display_name(&self) -> &str;
+
+    async fn authorize(&self, redirect_uri: &str) -> Result<AuthorizationRequest, anyhow::Error>;
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity, anyhow::Error>;
+}
+
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::identity;
+
+    #[test]
+    fn pkce_challenge() {
+        // RFC 7636, appendix B
+        assert_eq!(
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM",
+            identity::pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk")
+        );
+    }
+
+    #[test]
+    fn random_token() {
+        let token = identity::random_token(64);
+        assert_eq!(64, token.len());
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+}
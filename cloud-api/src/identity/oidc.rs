@@ -0,0 +1,300 @@
+use anyhow::anyhow;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Header, Validation};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::{
+    config::OidcConfiguration,
+    identity::{self, AuthorizationRequest, ExternalIdentity, IdentityProvider},
+};
+
+const SCOPES: &str = "openid email profile";
+
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+/// OpenID Connect sign-in using the authorization code flow with PKCE.
+#[derive(Debug)]
+pub struct OidcProvider {
+    config: OidcConfiguration,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+}
+
+impl OidcProvider {
+    pub fn new(config: OidcConfiguration) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+        }
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata, anyhow::Error> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+
+                let metadata = self
+                    .http
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<ProviderMetadata>()
+                    .await?;
+
+                Ok(metadata)
+            })
+            .await
+    }
+
+    async fn decoding_key(
+        &self,
+        metadata: &ProviderMetadata,
+        header: &Header,
+    ) -> Result<DecodingKey, anyhow::Error> {
+        match header.alg {
+            // symmetric id tokens are signed with the client secret
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secret = self.config.client_secret.as_ref().ok_or(anyhow!(
+                    "id token is signed with a client secret, but none is configured"
+                ))?;
+
+                Ok(DecodingKey::from_secret(secret.as_bytes()))
+            }
+            _ => {
+                let jwks = self
+                    .http
+                    .get(&metadata.jwks_uri)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<JwkSet>()
+                    .await?;
+
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None => jwks.keys.first(),
+                }
+                .ok_or(anyhow!("no matching key in the provider key set"))?;
+
+                Ok(DecodingKey::from_jwk(jwk)?)
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl IdentityProvider for OidcProvider {
+    fn id(&self) -> &str {
+        "oidc"
+    }
+
+    fn display_name(&self) -> &str {
+        &self.config.display_name
+    }
+
+    async fn authorize(&self, redirect_uri: &str) -> Result<AuthorizationRequest, anyhow::Error> {
+        let metadata = self.metadata().await?;
+
+        let state = identity::random_token(32);
+        let nonce = identity::random_token(32);
+        let pkce_verifier = identity::random_token(64);
+
+        let mut url = Url::parse(&metadata.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", SCOPES)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &identity::pkce_challenge(&pkce_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(AuthorizationRequest {
+            url: url.to_string(),
+            state,
+            pkce_verifier,
+            nonce,
+        })
+    }
+
+    async fn exchange_code(
+        &self,
+        code: &str,
+        redirect_uri: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity, anyhow::Error> {
+        let metadata = self.metadata().await?;
+
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", pkce_verifier),
+        ];
+
+        if let Some(client_secret) = &self.config.client_secret {
+            params.push(("client_secret", client_secret));
+        }
+
+        let token_res = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&params)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await?;
+
+        let header = jsonwebtoken::decode_header(&token_res.id_token)?;
+        let key = self.decoding_key(metadata, &header).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_issuer(&[&metadata.issuer]);
+
+        let claims =
+            jsonwebtoken::decode::<IdTokenClaims>(&token_res.id_token, &key, &validation)?.claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(anyhow!("id token nonce does not match"));
+        }
+
+        Ok(ExternalIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+            username: claims.preferred_username.or(claims.name),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use url::Url;
+
+    use crate::{
+        config::OidcConfiguration,
+        identity::{mock::MockIdp, oidc::OidcProvider, IdentityProvider},
+    };
+
+    const REDIRECT_URI: &str = "http://127.0.0.1:4000/callback";
+
+    fn provider(idp: &MockIdp) -> OidcProvider {
+        OidcProvider::new(OidcConfiguration {
+            issuer: idp.issuer.to_owned(),
+            client_id: MockIdp::CLIENT_ID.to_owned(),
+            client_secret: Some(MockIdp::CLIENT_SECRET.to_owned()),
+            display_name: "Mock".to_owned(),
+        })
+    }
+
+    /// follows the authorization url like a browser would and returns the redirect parameters
+    async fn authorize(url: &str) -> HashMap<String, String> {
+        let http = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let res = http.get(url).send().await.unwrap();
+        let location = res.headers()["location"].to_str().unwrap();
+
+        Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sign_in() {
+        let idp = MockIdp::start();
+        let provider = provider(&idp);
+
+        let auth_req = provider.authorize(REDIRECT_URI).await.unwrap();
+        let redirect = authorize(&auth_req.url).await;
+        assert_eq!(auth_req.state, redirect["state"]);
+
+        let identity = provider
+            .exchange_code(
+                &redirect["code"],
+                REDIRECT_URI,
+                &auth_req.pkce_verifier,
+                &auth_req.nonce,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(MockIdp::SUBJECT, identity.subject);
+        assert_eq!(Some(MockIdp::EMAIL.to_owned()), identity.email);
+        assert!(identity.email_verified);
+    }
+
+    #[tokio::test]
+    async fn sign_in_with_wrong_verifier() {
+        let idp = MockIdp::start();
+        let provider = provider(&idp);
+
+        let auth_req = provider.authorize(REDIRECT_URI).await.unwrap();
+        let redirect = authorize(&auth_req.url).await;
+
+        let identity = provider
+            .exchange_code(&redirect["code"], REDIRECT_URI, "wrong", &auth_req.nonce)
+            .await;
+
+        assert!(identity.is_err());
+    }
+
+    #[tokio::test]
+    async fn sign_in_with_wrong_nonce() {
+        let idp = MockIdp::start();
+        let provider = provider(&idp);
+
+        let auth_req = provider.authorize(REDIRECT_URI).await.unwrap();
+        let redirect = authorize(&auth_req.url).await;
+
+        let identity = provider
+            .exchange_code(
+                &redirect["code"],
+                REDIRECT_URI,
+                &auth_req.pkce_verifier,
+                "wrong",
+            )
+            .await;
+
+        assert!(identity.is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
+
+/// collections and fields that reference blobs of the default bucket, a blob that is not
+/// referenced by any of them is garbage
+const BLOB_REFERENCES: &[(&str, &str)] = &[
+    ("files", "bucket_id"),
+    ("files", "outboard_id"),
+    ("chunks", "bucket_id"),
+    ("thumbnails", "bucket_id"),
+];
+
+#[derive(Debug, Default)]
+pub struct GarbageReport {
+    pub blobs_removed: u64,
+    pub bytes_reclaimed: u64,
+    pub chunks_removed: u64,
+}
+
+/// removes chunks that are not part of any file and blobs that are not referenced anymore,
+/// e.g. after a failed or aborted upload
+///
+/// blobs and chunks younger than `grace_period` are kept, because an upload writes them
+/// before the file referencing them is committed
+pub async fn collect(
+    mongo: &mongodb::Client,
+    grace_period: Duration,
+) -> Result<GarbageReport, anyhow::Error> {
+    let db = mongo.database("cloud");
+    let bucket = db.gridfs_bucket(None);
+    let cutoff =
+        DateTime::from_millis(DateTime::now().timestamp_millis() - grace_period.as_millis() as i64);
+
+    let mut report = GarbageReport::default();
+
+    // the blobs of removed chunks are unreferenced and collected below
+    let mut unused_chunks = db
+        .collection::<Document>("chunks")
+        .aggregate(
+            [
+                doc! { "$match": { "last_used_at": { "$lt": cutoff } } },
+                // files of other owners with the same chunk keep it as well, which only
+                // delays its removal
+                doc! { "$lookup": { "from": "files", "localField": "hash", "foreignField": "chunks", "as": "files" } },
+                doc! { "$match": { "files": { "$size": 0 } } },
+                doc! { "$project": { "_id": 1 } },
+            ],
+            None,
+        )
+        .await?;
+
+    while let Some(chunk) = unused_chunks.next().await {
+        let id = chunk?.get("_id").cloned().unwrap_or_default();
+
+        // a chunk used by a commit since the lookup is kept
+        let delete_res = db
+            .collection::<Document>("chunks")
+            .delete_one(doc! { "_id": &id, "last_used_at": { "$lt": cutoff } }, None)
+            .await;
+
+        match delete_res {
+            Ok(r) => report.chunks_removed += r.deleted_count,
+            Err(e) => tracing::warn!("failed to delete unused chunk {}: {:?}", id, e),
+        }
+    }
+
+    let mut pipeline = vec![doc! { "$match": { "uploadDate": { "$lt": cutoff } } }];
+
+    for (i, (collection, field)) in BLOB_REFERENCES.iter().enumerate() {
+        let refs = format!("refs_{}", i);
+        pipeline.push(doc! {
+            "$lookup": { "from": collection, "localField": "_id", "foreignField": field, "as": &refs }
+        });
+        pipeline.push(doc! { "$match": { &refs: { "$size": 0 } } });
+    }
+
+    pipeline.push(doc! { "$project": { "length": 1 } });
+
+    let mut orphans = db
+        .collection::<Document>("fs.files")
+        .aggregate(pipeline, None)
+        .await?;
+
+    while let Some(orphan) = orphans.next().await {
+        let orphan = orphan?;
+        let id = orphan.get("_id").cloned().unwrap_or_default();
+        let length = length_of(&orphan);
+
+        match bucket.delete(id.clone()).await {
+            Ok(_) => {
+                report.blobs_removed += 1;
+                report.bytes_reclaimed += length;
+            }
+            Err(e) => tracing::warn!("failed to delete orphaned blob {}: {:?}", id, e),
+        }
+    }
+
+    // chunks of uploads that were interrupted before their files document was written
+    let chunk_cutoff = ObjectId::from_bytes(object_id_prefix(cutoff));
+
+    let mut dangling_chunks = db
+        .collection::<Document>("fs.chunks")
+        .aggregate(
+            [
+                doc! { "$group": {
+                    "_id": "$files_id",
+                    "first_chunk": { "$min": "$_id" },
+                    "length": { "$sum": { "$binarySize": "$data" } },
+                } },
+                doc! { "$match": { "first_chunk": { "$lt": chunk_cutoff } } },
+                doc! { "$lookup": { "from": "fs.files", "localField": "_id", "foreignField": "_id", "as": "files" } },
+                doc! { "$match": { "files": { "$size": 0 } } },
+            ],
+            None,
+        )
+        .await?;
+
+    while let Some(chunks) = dangling_chunks.next().await {
+        let chunks = chunks?;
+        let files_id = chunks.get("_id").cloned().unwrap_or_default();
+        let length = length_of(&chunks);
+
+        let delete_res = db
+            .collection::<Document>("fs.chunks")
+            .delete_many(doc! { "files_id": &files_id }, None)
+            .await;
+
+        match delete_res {
+            Ok(_) => {
+                report.blobs_removed += 1;
+                report.bytes_reclaimed += length;
+            }
+            Err(e) => tracing::warn!("failed to delete dangling chunks of {}: {:?}", files_id, e),
+        }
+    }
+
+    tracing::info!(
+        "collected {} unused chunks and {} orphaned blobs, {} bytes reclaimed",
+        report.chunks_removed,
+        report.blobs_removed,
+        report.bytes_reclaimed
+    );
+
+    Ok(report)
+}
+
+/// the smallest object id created at `time`, object ids start with their creation time
+fn object_id_prefix(time: DateTime) -> [u8; 12] {
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&((time.timestamp_millis() / 1000) as u32).to_be_bytes());
+    bytes
+}
+
+fn length_of(doc: &Document) -> u64 {
+    match doc.get("length") {
+        Some(Bson::Int32(l)) => *l as u64,
+        Some(Bson::Int64(l)) => *l as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{oid::ObjectId, DateTime};
+
+    use crate::jobs::blob_gc;
+
+    #[test]
+    fn object_id_prefix() {
+        let time = DateTime::from_millis(1_677_931_200_000);
+        let prefix = ObjectId::from_bytes(blob_gc::object_id_prefix(time));
+
+        assert_eq!(time, prefix.timestamp());
+        assert!(prefix < ObjectId::new());
+    }
+}
@@ -0,0 +1,66 @@
+use futures_util::StreamExt;
+use mongodb::bson::{self, doc, Document};
+
+use crate::crypto::{BlobEncryption, MasterKeys};
+
+#[derive(Debug, Default)]
+pub struct KeyRotationReport {
+    pub blobs_rewrapped: u64,
+}
+
+/// wraps the data keys of all blobs that use an older master key with the active key
+///
+/// only the data keys are rewritten, the blob content stays untouched, so the old master
+/// key can be removed from the configuration afterwards
+pub async fn rotate(
+    mongo: &mongodb::Client,
+    master_keys: &MasterKeys,
+) -> Result<KeyRotationReport, anyhow::Error> {
+    let db = mongo.database("cloud");
+    let db_blobs = db.collection::<Document>("fs.files");
+
+    let mut report = KeyRotationReport::default();
+    let mut cursor = db_blobs
+        .find(
+            doc! {
+                "metadata.encryption.key_id": {
+                    "$exists": true,
+                    "$ne": master_keys.active_id(),
+                },
+            },
+            None,
+        )
+        .await?;
+
+    while let Some(blob) = cursor.next().await {
+        let blob = blob?;
+        let id = blob.get_object_id("_id")?;
+
+        let encryption: BlobEncryption = bson::from_bson(
+            blob.get_document("metadata")?
+                .get("encryption")
+                .cloned()
+                .unwrap_or_default(),
+        )?;
+        let rewrapped = master_keys.rewrap(&encryption)?;
+
+        // skip blobs that have been rewrapped concurrently
+        let update_res = db_blobs
+            .update_one(
+                doc! { "_id": id, "metadata.encryption.key_id": &encryption.key_id },
+                doc! { "$set": { "metadata.encryption": bson::to_bson(&rewrapped)? } },
+                None,
+            )
+            .await?;
+
+        report.blobs_rewrapped += update_res.modified_count;
+    }
+
+    tracing::info!(
+        "rewrapped the data keys of {} blobs with master key {}",
+        report.blobs_rewrapped,
+        master_keys.active_id()
+    );
+
+    Ok(report)
+}
@@ -0,0 +1,29 @@
+use std::{future::Future, time::Duration};
+
+pub mod blob_gc;
+pub mod key_rotation;
+pub mod scrub;
+pub mod storage_usage;
+
+/// runs `job` every `period` for the lifetime of the server, failures are logged and the job
+/// is retried in the next period
+pub fn spawn_periodic<F, Fut>(name: &'static str, period: Duration, job: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), anyhow::Error>> + Send,
+{
+    tracing::info!("Scheduling {} every {:?}", name, period);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+            tracing::debug!("running {}", name);
+
+            if let Err(e) = job().await {
+                tracing::error!("{} failed: {:?}", name, e);
+            }
+        }
+    });
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use futures_util::StreamExt;
+use mongodb::{
+    bson::{self, doc},
+    options::ReplaceOptions,
+    Database,
+};
+
+use crate::{
+    blob,
+    crypto::MasterKeys,
+    models::{DbCorruptedFile, DbFile},
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct ScrubStatus {
+    pub running: bool,
+    pub started_at: Option<bson::DateTime>,
+    pub finished_at: Option<bson::DateTime>,
+    pub files_checked: u64,
+    pub bytes_checked: u64,
+    pub files_corrupted: u64,
+}
+
+/// Verifies that the stored blobs still match the hashes of their files.
+///
+/// Corrupted files are recorded in the `corrupted_files` collection and removed from it
+/// again once they pass a later scrub.
+#[derive(Debug)]
+pub struct Scrubber {
+    mongo: mongodb::Client,
+    /// read limit so scrubbing does not starve regular downloads, 0 disables the limit
+    bytes_per_second: u64,
+    master_keys: Option<Arc<MasterKeys>>,
+    status: Mutex<ScrubStatus>,
+}
+
+impl Scrubber {
+    pub fn new(
+        mongo: mongodb::Client,
+        bytes_per_second: u64,
+        master_keys: Option<Arc<MasterKeys>>,
+    ) -> Self {
+        Self {
+            mongo,
+            bytes_per_second,
+            master_keys,
+            status: Mutex::new(ScrubStatus::default()),
+        }
+    }
+
+    /// the progress of the running scrub, or the result of the last one
+    pub fn status(&self) -> ScrubStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub async fn run(&self) -> Result<ScrubStatus, anyhow::Error> {
+        {
+            let mut status = self.status.lock().unwrap();
+
+            if status.running {
+                return Err(anyhow!("scrub is already running"));
+            }
+
+            *status = ScrubStatus {
+                running: true,
+                started_at: Some(bson::DateTime::now()),
+                ..Default::default()
+            };
+        }
+
+        let scrub_res = self.scrub_files().await;
+
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.running = false;
+            status.finished_at = Some(bson::DateTime::now());
+            status.clone()
+        };
+
+        scrub_res?;
+
+        tracing::info!(
+            "scrubbed {} files ({} bytes), {} corrupted",
+            status.files_checked,
+            status.bytes_checked,
+            status.files_corrupted
+        );
+
+        Ok(status)
+    }
+
+    async fn scrub_files(&self) -> Result<(), anyhow::Error> {
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+        let db_corrupted_files = db.collection::<DbCorruptedFile>("corrupted_files");
+
+        let mut throttle = Throttle::new(self.bytes_per_second);
+        let mut cursor = db_files.find(doc! {}, None).await?;
+
+        while let Some(db_file) = cursor.next().await {
+            let db_file = db_file?;
+            let verify_res = self.verify(&db, &db_file, &mut throttle).await;
+
+            let (actual_hash, error) = match verify_res {
+                Ok(hash) if hash == db_file.hash => {
+                    db_corrupted_files
+                        .delete_one(doc! { "_id": db_file.id }, None)
+                        .await?;
+
+                    self.status.lock().unwrap().files_checked += 1;
+                    continue;
+                }
+                Ok(hash) => (Some(hash), None),
+                Err(e) => (None, Some(e.to_string())),
+            };
+
+            // the file may have been replaced or deleted while it was read
+            let current_file = db_files.find_one(doc! { "_id": db_file.id }, None).await?;
+
+            let replaced = match current_file {
+                Some(f) => f.bucket_id != db_file.bucket_id || f.chunks != db_file.chunks,
+                None => true,
+            };
+
+            // the report on the old content is stale, new content is verified by the next scrub
+            if replaced {
+                db_corrupted_files
+                    .delete_one(doc! { "_id": db_file.id }, None)
+                    .await?;
+                continue;
+            }
+
+            tracing::warn!(
+                "file {} of user {} is corrupted",
+                db_file.id,
+                db_file.owner_id
+            );
+
+            db_corrupted_files
+                .replace_one(
+                    doc! { "_id": db_file.id },
+                    DbCorruptedFile {
+                        file_id: db_file.id,
+                        owner_id: db_file.owner_id,
+                        bucket_id: db_file.bucket_id,
+                        path: db_file.path,
+                        expected_hash: db_file.hash,
+                        actual_hash,
+                        error,
+                        detected_at: bson::DateTime::now(),
+                    },
+                    ReplaceOptions::builder().upsert(true).build(),
+                )
+                .await?;
+
+            let mut status = self.status.lock().unwrap();
+            status.files_checked += 1;
+            status.files_corrupted += 1;
+        }
+
+        Ok(())
+    }
+
+    /// streams the content of the file back from storage and returns its hash, the bytes
+    /// read are reported as they are read
+    async fn verify(
+        &self,
+        db: &Database,
+        db_file: &DbFile,
+        throttle: &mut Throttle,
+    ) -> Result<String, anyhow::Error> {
+        let mut stream = blob::open_file(db, self.master_keys.clone(), db_file).await?;
+
+        let mut hasher = blake3::Hasher::new();
+
+        while let Some(bytes) = stream.next().await {
+            let bytes = bytes?;
+            hasher.update(&bytes);
+            throttle.consume(bytes.len()).await;
+            self.status.lock().unwrap().bytes_checked = throttle.bytes;
+        }
+
+        Ok(hasher.finalize().to_string())
+    }
+}
+
+struct Throttle {
+    bytes_per_second: u64,
+    started_at: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            started_at: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// sleeps until reading `len` more bytes stays within the rate limit
+    async fn consume(&mut self, len: usize) {
+        self.bytes += len as u64;
+
+        if self.bytes_per_second == 0 {
+            return;
+        }
+
+        let expected = Duration::from_secs_f64(self.bytes as f64 / self.bytes_per_second as f64);
+        let elapsed = self.started_at.elapsed();
+
+        if expected > elapsed {
+            tokio::time::sleep(expected - elapsed).await;
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use futures_util::StreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    options::FindOptions,
+};
+use serde::Deserialize;
+
+use crate::{
+    config::QuotaCharge,
+    models::{DbFile, DbUser},
+};
+
+#[derive(Debug, Default)]
+pub struct StorageUsageReport {
+    pub users_checked: u64,
+    pub users_repaired: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct StorageUsed {
+    storage_used: u64,
+}
+
+/// recomputes the `storage_used` of every user from the sizes of their files and repairs
+/// users whose counter has drifted
+pub async fn reconcile(
+    mongo: &mongodb::Client,
+    quota_charge: QuotaCharge,
+) -> Result<StorageUsageReport, anyhow::Error> {
+    let db = mongo.database("cloud");
+    let db_users = db.collection::<Document>("users");
+
+    let mut user_ids = db_users
+        .find(
+            doc! {},
+            FindOptions::builder().projection(doc! { "_id": 1 }).build(),
+        )
+        .await?;
+
+    let mut report = StorageUsageReport::default();
+
+    while let Some(user_id) = user_ids.next().await {
+        let user_id = user_id?.get_object_id("_id")?;
+
+        match reconcile_user(mongo, user_id, quota_charge).await {
+            Ok(repaired) => {
+                report.users_checked += 1;
+
+                if repaired {
+                    report.users_repaired += 1;
+                }
+            }
+            // most likely a concurrent upload, the user is checked again in the next run
+            Err(e) => tracing::warn!("failed to reconcile storage usage of {}: {:?}", user_id, e),
+        }
+    }
+
+    tracing::info!(
+        "reconciled storage usage, {} of {} users repaired",
+        report.users_repaired,
+        report.users_checked
+    );
+
+    Ok(report)
+}
+
+/// returns whether the counter had to be repaired
+async fn reconcile_user(
+    mongo: &mongodb::Client,
+    user_id: ObjectId,
+    quota_charge: QuotaCharge,
+) -> Result<bool, mongodb::error::Error> {
+    let db = mongo.database("cloud");
+    let db_users = db.collection::<DbUser>("users");
+    let db_files = db.collection::<DbFile>("files");
+
+    let charged_size = DbFile::charged_size_expression(quota_charge);
+
+    let mut session = mongo.start_session(None).await?;
+    session.start_transaction(None).await?;
+
+    let reconcile_res: Result<bool, mongodb::error::Error> = async {
+        let db_user = match db_users
+            .find_one_with_session(doc! { "_id": user_id }, None, &mut session)
+            .await?
+        {
+            Some(u) => u,
+            None => return Ok(false),
+        };
+
+        let mut cursor = db_files
+            .aggregate_with_session(
+                [
+                    doc! { "$match": { "owner_id": user_id } },
+                    doc! { "$group": { "_id": null, "storage_used": { "$sum": charged_size } } },
+                ],
+                None,
+                &mut session,
+            )
+            .await?;
+
+        let storage_used = match cursor.next(&mut session).await.transpose()? {
+            Some(d) => mongodb::bson::from_document::<StorageUsed>(d)?.storage_used,
+            None => 0,
+        };
+
+        if db_user.storage_used == storage_used {
+            return Ok(false);
+        }
+
+        db_users
+            .update_one_with_session(
+                doc! { "_id": user_id },
+                doc! { "$set": { "storage_used": storage_used as i64 } },
+                None,
+                &mut session,
+            )
+            .await?;
+
+        tracing::info!(
+            "repaired storage usage of user {} from {} to {}",
+            user_id,
+            db_user.storage_used,
+            storage_used
+        );
+
+        Ok(true)
+    }
+    .await;
+
+    match reconcile_res {
+        Ok(repaired) => {
+            session.commit_transaction().await?;
+            Ok(repaired)
+        }
+        Err(e) => {
+            session.abort_transaction().await.ok();
+            Err(e)
+        }
+    }
+}
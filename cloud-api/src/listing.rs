@@ -0,0 +1,410 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use cloud_proto::proto::{ListFilesRequest, ListOrder, SearchFilesRequest};
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, Document};
+use tonic::Status;
+
+use crate::models::{to_date_time, DbFile};
+
+/// files in a page if the request does not specify a page size
+const DEFAULT_PAGE_SIZE: u32 = 100;
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// The query of a page of a `List` or `Search` request.
+#[derive(Debug)]
+pub struct ListQuery {
+    pub filter: Document,
+    pub sort: Document,
+    pub page_size: usize,
+    order: ListOrder,
+}
+
+impl ListQuery {
+    pub fn new(owner_id: ObjectId, request: &ListFilesRequest) -> Result<Self, Status> {
+        let mut filters = vec![doc! { "owner_id": owner_id }];
+
+        if let Some(regex) =
+            path_regex(&request.directory, &request.name_prefix, request.recursive)?
+        {
+            filters.push(doc! { "path": { "$regex": regex } });
+        }
+
+        if let Some(modified_since) = &request.modified_since {
+            filters.push(doc! { "modified_at": { "$gte": to_date_time(modified_since) } });
+        }
+
+        if !request.tag.is_empty() {
+            filters.push(doc! { "tags": &request.tag });
+        }
+
+        if request.favorites_only {
+            filters.push(doc! { "favorite": true });
+        }
+
+        Self::page(
+            filters,
+            request.page_size,
+            &request.page_token,
+            request.order,
+        )
+    }
+
+    pub fn search(owner_id: ObjectId, request: &SearchFilesRequest) -> Result<Self, Status> {
+        let mut filters = vec![doc! { "owner_id": owner_id }];
+
+        if !request.name.is_empty() {
+            let regex = format!("/[^/]*{}[^/]*$", escape(&request.name));
+            filters.push(doc! { "path": { "$regex": regex, "$options": "i" } });
+        }
+
+        if !request.glob.is_empty() {
+            filters.push(doc! { "path": { "$regex": glob_regex(&request.glob)? } });
+        }
+
+        if !request.extensions.is_empty() {
+            let extensions = request
+                .extensions
+                .iter()
+                .map(|e| escape(e.trim_start_matches('.')))
+                .collect::<Vec<_>>();
+            let regex = format!("\\.({})$", extensions.join("|"));
+            filters.push(doc! { "path": { "$regex": regex, "$options": "i" } });
+        }
+
+        if let Some(min_size) = request.min_size {
+            filters.push(doc! { "size": { "$gte": min_size as i64 } });
+        }
+
+        if let Some(max_size) = request.max_size {
+            filters.push(doc! { "size": { "$lte": max_size as i64 } });
+        }
+
+        if let Some(modified_after) = &request.modified_after {
+            filters.push(doc! { "modified_at": { "$gte": to_date_time(modified_after) } });
+        }
+
+        if let Some(modified_before) = &request.modified_before {
+            filters.push(doc! { "modified_at": { "$lt": to_date_time(modified_before) } });
+        }
+
+        if let Some(captured_after) = &request.captured_after {
+            filters.push(doc! { "media.captured_at": { "$gte": to_date_time(captured_after) } });
+        }
+
+        if let Some(captured_before) = &request.captured_before {
+            filters.push(doc! { "media.captured_at": { "$lt": to_date_time(captured_before) } });
+        }
+
+        if !request.camera.is_empty() {
+            let regex = escape(&request.camera);
+            filters.push(doc! {
+                "$or": [
+                    { "media.camera_make": { "$regex": &regex, "$options": "i" } },
+                    { "media.camera_model": { "$regex": &regex, "$options": "i" } },
+                ]
+            });
+        }
+
+        if !request.artist.is_empty() {
+            let regex = escape(&request.artist);
+            filters.push(doc! { "media.artist": { "$regex": regex, "$options": "i" } });
+        }
+
+        if !request.album.is_empty() {
+            let regex = escape(&request.album);
+            filters.push(doc! { "media.album": { "$regex": regex, "$options": "i" } });
+        }
+
+        if request.has_location {
+            filters.push(doc! { "media.latitude": { "$type": "double" } });
+        }
+
+        Self::page(
+            filters,
+            request.page_size,
+            &request.page_token,
+            request.order,
+        )
+    }
+
+    /// the query of the page of the files matching `filters` that starts after `page_token`
+    fn page(
+        mut filters: Vec<Document>,
+        page_size: u32,
+        page_token: &str,
+        order: i32,
+    ) -> Result<Self, Status> {
+        let order = ListOrder::from_i32(order).ok_or(Status::invalid_argument("invalid order"))?;
+
+        let page_size = match page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+
+        if matches!(order, ListOrder::Captured | ListOrder::CapturedDesc) {
+            filters.push(doc! { "media.captured_at": { "$type": "date" } });
+        }
+
+        if !page_token.is_empty() {
+            filters.push(after_token(order, page_token)?);
+        }
+
+        let (key, direction) = sort_key(order);
+        // paths are unique, files with the same modification time or size are ordered by id
+        let sort = match order {
+            ListOrder::Path | ListOrder::PathDesc => doc! { key: direction },
+            _ => doc! { key: direction, "_id": direction },
+        };
+
+        Ok(Self {
+            filter: doc! { "$and": filters },
+            sort,
+            page_size: page_size as usize,
+            order,
+        })
+    }
+
+    /// the token of the page following `last`, the last file of the current page
+    pub fn next_page_token(&self, last: &DbFile) -> String {
+        let token = doc! {
+            "o": self.order as i32,
+            "v": sort_value(self.order, last),
+            "id": last.id,
+        };
+
+        URL_SAFE_NO_PAD.encode(bson::to_vec(&token).unwrap())
+    }
+}
+
+fn sort_key(order: ListOrder) -> (&'static str, i32) {
+    match order {
+        ListOrder::Path => ("path", 1),
+        ListOrder::PathDesc => ("path", -1),
+        ListOrder::Modified => ("modified_at", 1),
+        ListOrder::ModifiedDesc => ("modified_at", -1),
+        ListOrder::Size => ("size", 1),
+        ListOrder::SizeDesc => ("size", -1),
+        ListOrder::Captured => ("media.captured_at", 1),
+        ListOrder::CapturedDesc => ("media.captured_at", -1),
+    }
+}
+
+fn sort_value(order: ListOrder, db_file: &DbFile) -> Bson {
+    match order {
+        ListOrder::Path | ListOrder::PathDesc => Bson::String(db_file.path.to_owned()),
+        ListOrder::Modified | ListOrder::ModifiedDesc => Bson::DateTime(db_file.modified_at),
+        ListOrder::Size | ListOrder::SizeDesc => Bson::Int64(db_file.size as i64),
+        ListOrder::Captured | ListOrder::CapturedDesc => {
+            match db_file.media.as_ref().and_then(|m| m.captured_at) {
+                Some(captured_at) => Bson::DateTime(captured_at),
+                None => Bson::Null,
+            }
+        }
+    }
+}
+
+/// the filter of the files that come after the file the page token was created from
+fn after_token(order: ListOrder, page_token: &str) -> Result<Document, Status> {
+    let invalid = || Status::invalid_argument("invalid page token");
+
+    let bytes = URL_SAFE_NO_PAD.decode(page_token).map_err(|_| invalid())?;
+    let token = bson::from_slice::<Document>(&bytes).map_err(|_| invalid())?;
+
+    if token.get_i32("o").map_err(|_| invalid())? != order as i32 {
+        return Err(Status::invalid_argument(
+            "page token was created for a different order",
+        ));
+    }
+
+    let value = token.get("v").ok_or_else(invalid)?.clone();
+    let id = token.get_object_id("id").map_err(|_| invalid())?;
+
+    let (key, direction) = sort_key(order);
+    let op = if direction == 1 { "$gt" } else { "$lt" };
+
+    Ok(match order {
+        ListOrder::Path | ListOrder::PathDesc => doc! { key: { op: value } },
+        _ => doc! {
+            "$or": [
+                { key: { op: value.clone() } },
+                { key: value, "_id": { op: id } },
+            ]
+        },
+    })
+}
+
+/// the anchored regex of the paths in `directory`, `None` if every path matches
+pub fn path_regex(
+    directory: &str,
+    name_prefix: &str,
+    recursive: bool,
+) -> Result<Option<String>, Status> {
+    if !directory.starts_with('/') {
+        return Err(Status::invalid_argument("directory is not absolute"));
+    }
+
+    if name_prefix.contains('/') {
+        return Err(Status::invalid_argument("name prefix can not contain /"));
+    }
+
+    let directory = directory.trim_end_matches('/');
+
+    if recursive && directory.is_empty() && name_prefix.is_empty() {
+        return Ok(None);
+    }
+
+    let mut regex = format!("^{}/{}", escape(directory), escape(name_prefix));
+    if !recursive {
+        regex.push_str("[^/]*$");
+    }
+
+    Ok(Some(regex))
+}
+
+/// the regex of a glob, globs without a / match the file name and others the whole path
+fn glob_regex(glob: &str) -> Result<String, Status> {
+    let mut regex = String::new();
+    let mut chars = glob.trim_start_matches('/').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.next_if_eq(&'!').is_some() {
+                    regex.push('^');
+                }
+
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c @ ('\\' | '[' | '^')) => {
+                            regex.push('\\');
+                            regex.push(c);
+                        }
+                        Some(c) => regex.push(c),
+                        None => return Err(Status::invalid_argument("unclosed [ in glob")),
+                    }
+                }
+
+                regex.push(']');
+            }
+            c => regex.push_str(&escape(&c.to_string())),
+        }
+    }
+
+    match glob.contains('/') {
+        true => Ok(format!("^/{}$", regex)),
+        false => Ok(format!("/{}$", regex)),
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud_proto::proto::{ListFilesRequest, ListOrder};
+    use mongodb::bson::{self, doc, oid::ObjectId};
+
+    use crate::{
+        listing::{self, ListQuery},
+        models::DbFile,
+    };
+
+    #[test]
+    fn path_regex() {
+        assert_eq!(None, listing::path_regex("/", "", true).unwrap());
+        assert_eq!(
+            Some("^/[^/]*$".to_owned()),
+            listing::path_regex("/", "", false).unwrap()
+        );
+        assert_eq!(
+            Some("^/a\\.b/c".to_owned()),
+            listing::path_regex("/a.b/", "c", true).unwrap()
+        );
+        assert_eq!(
+            Some("^/a/\\(1\\)[^/]*$".to_owned()),
+            listing::path_regex("/a", "(1)", false).unwrap()
+        );
+
+        assert!(listing::path_regex("a", "", true).is_err());
+        assert!(listing::path_regex("/a", "b/c", true).is_err());
+    }
+
+    #[test]
+    fn glob_regex() {
+        assert_eq!("/[^/]*\\.md$", listing::glob_regex("*.md").unwrap());
+        assert_eq!(
+            "^/docs/.*/a[^/][^0-9]$",
+            listing::glob_regex("/docs/**/a?[!0-9]").unwrap()
+        );
+        assert!(listing::glob_regex("a[b").is_err());
+    }
+
+    #[test]
+    fn page_token_continues_after_last_file() {
+        let owner_id = ObjectId::new();
+        let mut request = ListFilesRequest {
+            directory: "/".to_owned(),
+            order: ListOrder::SizeDesc as i32,
+            ..Default::default()
+        };
+
+        let query = ListQuery::new(owner_id, &request).unwrap();
+        assert_eq!(100, query.page_size);
+        assert_eq!(doc! { "size": -1, "_id": -1 }, query.sort);
+
+        let last = DbFile {
+            id: ObjectId::new(),
+            owner_id,
+            bucket_id: None,
+            chunks: None,
+            outboard_id: None,
+            path: "/a".to_owned(),
+            hash: String::new(),
+            size: 10,
+            stored_size: None,
+            created_at: bson::DateTime::now(),
+            modified_at: bson::DateTime::now(),
+            mtime: None,
+            mode: None,
+            mime_type: "application/octet-stream".to_owned(),
+            tags: Vec::new(),
+            favorite: false,
+            metadata: Default::default(),
+            media: None,
+        };
+        request.page_token = query.next_page_token(&last);
+
+        let query = ListQuery::new(owner_id, &request).unwrap();
+        let after = doc! {
+            "$or": [
+                { "size": { "$lt": 10_i64 } },
+                { "size": 10_i64, "_id": { "$lt": last.id } },
+            ]
+        };
+        assert_eq!(
+            Some(&after),
+            query.filter.get_array("$and").unwrap()[2].as_document()
+        );
+
+        // a token is only valid for the order it was created for
+        request.order = ListOrder::Size as i32;
+        assert!(ListQuery::new(owner_id, &request).is_err());
+    }
+}
@@ -1,19 +1,43 @@
+use std::{sync::Arc, time::Duration};
+
 use cloud_proto::proto::{
-    auth_service_server::AuthServiceServer, file_service_server::FileServiceServer,
-    user_service_server::UserServiceServer,
+    admin_service_server::AdminServiceServer, auth_service_server::AuthServiceServer,
+    file_service_server::FileServiceServer, user_service_server::UserServiceServer,
 };
 use mongodb::bson::doc;
 use tonic::transport::Server;
 
 use crate::{
     config::Configuration,
-    services::{auth::MyAuthService, file::MyFileService, user::MyUserService},
+    content_index::ContentIndex,
+    crypto::MasterKeys,
+    identity::{oidc::OidcProvider, IdentityProvider},
+    jobs::scrub::Scrubber,
+    media::MediaExtractor,
+    services::{
+        admin::MyAdminService, auth::MyAuthService, file::MyFileService, user::MyUserService,
+    },
+    thumbnails::Thumbnails,
 };
 
+mod activity;
 mod auth_token;
+mod blob;
+mod compression;
 mod config;
+mod content_index;
+mod crypto;
+mod duplicates;
+mod identity;
+mod jobs;
+mod listing;
+mod media;
 mod models;
+mod multipart;
 mod services;
+mod staging;
+mod thumbnails;
+mod usage;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -30,14 +54,125 @@ async fn main() -> Result<(), anyhow::Error> {
         .run_command(doc! {"ping": 1}, None)
         .await?;
 
+    let master_keys = MasterKeys::from_config(&config)?.map(Arc::new);
+
+    // runs next to a live server, so it must not touch its staged uploads
+    if std::env::args().nth(1).as_deref() == Some("rotate-keys") {
+        let master_keys =
+            master_keys.ok_or_else(|| anyhow::anyhow!("encryption at rest is not enabled"))?;
+        jobs::key_rotation::rotate(&mongo, &master_keys).await?;
+        return Ok(());
+    }
+
+    models::migrate(&mongo.database("cloud")).await?;
+    models::create_indexes(&mongo.database("cloud")).await?;
+    staging::prepare(&config.staging_dir).await?;
+
+    if let Some(level) = config.compression_level {
+        tracing::info!("Compression enabled with zstd level {}", level);
+    }
+
+    if let Some(master_keys) = &master_keys {
+        tracing::info!(
+            "Encryption at rest enabled with master key {}",
+            master_keys.active_id()
+        );
+    }
+
+    let mut identity_providers: Vec<Arc<dyn IdentityProvider>> = Vec::new();
+
+    if let Some(oidc) = &config.oidc {
+        tracing::info!("Single sign-on enabled with issuer {}", oidc.issuer);
+        identity_providers.push(Arc::new(OidcProvider::new(oidc.clone())));
+    }
+
+    if let Some(interval) = config.storage_reconcile_interval {
+        let mongo = mongo.clone();
+        let quota_charge = config.quota_charge;
+        jobs::spawn_periodic(
+            "storage usage reconciliation",
+            Duration::from_secs(interval),
+            move || {
+                let mongo = mongo.clone();
+                async move {
+                    jobs::storage_usage::reconcile(&mongo, quota_charge).await?;
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    if let Some(interval) = config.blob_gc_interval {
+        let mongo = mongo.clone();
+        let grace_period = Duration::from_secs(config.blob_gc_grace_period);
+        jobs::spawn_periodic(
+            "blob garbage collection",
+            Duration::from_secs(interval),
+            move || {
+                let mongo = mongo.clone();
+                async move {
+                    jobs::blob_gc::collect(&mongo, grace_period).await?;
+                    Ok(())
+                }
+            },
+        );
+    }
+
+    let scrubber = Arc::new(Scrubber::new(
+        mongo.clone(),
+        config.scrub_rate,
+        master_keys.clone(),
+    ));
+
+    if let Some(interval) = config.scrub_interval {
+        let scrubber = scrubber.clone();
+        jobs::spawn_periodic("storage scrub", Duration::from_secs(interval), move || {
+            let scrubber = scrubber.clone();
+            async move {
+                scrubber.run().await?;
+                Ok(())
+            }
+        });
+    }
+
+    let content_index = config.index_dir.as_ref().map(|dir| {
+        tracing::info!("Content search enabled with indexes in {:?}", dir);
+        Arc::new(ContentIndex::start(
+            dir.clone(),
+            mongo.clone(),
+            master_keys.clone(),
+        ))
+    });
+
+    let thumbnails = Arc::new(Thumbnails::start(mongo.clone(), master_keys.clone()));
+    let media_extractor = Arc::new(MediaExtractor::start(mongo.clone(), master_keys.clone()));
+
     tracing::info!("Server listening on {}", &config.server_endpoint);
     Server::builder()
         .add_service(AuthServiceServer::new(MyAuthService::new(
             config.clone(),
             mongo.clone(),
+            identity_providers,
         )))
-        .add_service(UserServiceServer::new(MyUserService::new(mongo.clone())))
-        .add_service(FileServiceServer::new(MyFileService::new(mongo.clone())))
+        .add_service(AdminServiceServer::with_interceptor(
+            MyAdminService::new(config.clone(), mongo.clone(), scrubber, master_keys.clone()),
+            auth_token::authenticate(auth_token::SCOPE_ADMIN),
+        ))
+        .add_service(UserServiceServer::with_interceptor(
+            MyUserService::new(config.clone(), mongo.clone()),
+            auth_token::authenticate(auth_token::SCOPE_USER),
+        ))
+        .add_service(FileServiceServer::with_interceptor(
+            MyFileService::new(
+                config.clone(),
+                mongo.clone(),
+                master_keys,
+                content_index,
+                thumbnails,
+                media_extractor,
+            ),
+            auth_token::authenticate(auth_token::SCOPE_FILES),
+        ))
         .serve(config.server_endpoint.clone())
         .await?;
 
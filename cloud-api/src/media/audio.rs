@@ -0,0 +1,81 @@
+use std::io::Cursor;
+
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::Hint,
+};
+
+use crate::models::DbMedia;
+
+/// the tags and duration of an audio file, the format is detected from the content
+pub fn extract(content: Vec<u8>) -> Result<DbMedia, anyhow::Error> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(content)), Default::default());
+    let mut probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+
+    let mut media = DbMedia::default();
+
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+
+        let duration = match (params.n_frames, params.time_base, params.sample_rate) {
+            (Some(frames), Some(time_base), _) => {
+                let time = time_base.calc_time(frames);
+                time.seconds
+                    .checked_mul(1000)
+                    .and_then(|millis| millis.checked_add((time.frac * 1000.0) as u64))
+            }
+            (Some(frames), None, Some(sample_rate)) => frames
+                .checked_mul(1000)
+                .and_then(|millis| millis.checked_div(sample_rate.into())),
+            _ => None,
+        };
+        media.duration = duration;
+    }
+
+    // tags in front of the container, e.g. id3, and those of the container itself
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        read_tags(&mut media, revision);
+    }
+
+    if let Some(revision) = probed.format.metadata().current() {
+        read_tags(&mut media, revision);
+    }
+
+    Ok(media)
+}
+
+/// sets the tags that are not set yet
+fn read_tags(media: &mut DbMedia, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        let value = tag.value.to_string().trim().to_owned();
+
+        if value.is_empty() {
+            continue;
+        }
+
+        let field = match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => &mut media.title,
+            Some(StandardTagKey::Artist) => &mut media.artist,
+            Some(StandardTagKey::Album) => &mut media.album,
+            Some(StandardTagKey::Genre) => &mut media.genre,
+            Some(StandardTagKey::TrackNumber) => {
+                // numbers are often stored with the total, e.g. 3/12
+                if media.track_number.is_none() {
+                    media.track_number =
+                        value.split('/').next().and_then(|n| n.trim().parse().ok());
+                }
+                continue;
+            }
+            _ => continue,
+        };
+
+        field.get_or_insert(value);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::StreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    Database,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    blob,
+    crypto::MasterKeys,
+    models::{DbFile, DbMedia},
+};
+
+mod audio;
+mod mp4;
+mod photo;
+
+/// largest photo or audio file whose metadata is extracted, videos are not read completely
+const MAX_READ_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Extracts the metadata of committed photos, audio files and videos in the background.
+///
+/// A commit clears the metadata of the file, it is set again once the new content has been
+/// read.
+#[derive(Debug)]
+pub struct MediaExtractor {
+    queue: mpsc::UnboundedSender<(ObjectId, ObjectId)>,
+    /// files in the queue, so a file is only queued once
+    pending: Arc<Mutex<HashSet<ObjectId>>>,
+}
+
+impl MediaExtractor {
+    /// starts the worker that extracts the metadata of the files passed to
+    /// [`MediaExtractor::update`]
+    pub fn start(mongo: mongodb::Client, master_keys: Option<Arc<MasterKeys>>) -> Self {
+        let (queue, mut rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let worker_pending = pending.clone();
+
+        tokio::spawn(async move {
+            let db = mongo.database("cloud");
+
+            while let Some((owner_id, file_id)) = rx.recv().await {
+                worker_pending.lock().unwrap().remove(&file_id);
+
+                let extract_res = extract_file(&db, master_keys.clone(), owner_id, file_id).await;
+
+                if let Err(e) = extract_res {
+                    tracing::warn!("failed to extract media of {}: {:?}", file_id, e);
+                }
+            }
+        });
+
+        Self { queue, pending }
+    }
+
+    /// extracts the metadata of a file after it was committed
+    pub fn update(&self, owner_id: ObjectId, file_id: ObjectId) {
+        if self.pending.lock().unwrap().insert(file_id) {
+            self.queue.send((owner_id, file_id)).ok();
+        }
+    }
+}
+
+async fn extract_file(
+    db: &Database,
+    master_keys: Option<Arc<MasterKeys>>,
+    owner_id: ObjectId,
+    file_id: ObjectId,
+) -> Result<(), anyhow::Error> {
+    let db_files = db.collection::<DbFile>("files");
+    let db_file = db_files
+        .find_one(doc! { "_id": file_id, "owner_id": owner_id }, None)
+        .await?;
+
+    let (db_file, kind) = match db_file {
+        Some(db_file) => match Kind::of(&db_file) {
+            Some(kind) => (db_file, kind),
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let mut blob_stream = blob::open_file(db, master_keys, &db_file).await?;
+
+    let extract_res = match kind {
+        // only the header of a video is read
+        Kind::Video => match mp4::read_moov(blob_stream).await {
+            Ok(moov) => parse(move || mp4::parse(&moov)).await,
+            Err(e) => Err(e),
+        },
+        Kind::Photo | Kind::Audio => {
+            let mut content = Vec::with_capacity(db_file.size as usize);
+
+            while let Some(bytes) = blob_stream.next().await {
+                content.extend(bytes?);
+            }
+
+            parse(move || match kind {
+                Kind::Photo => photo::extract(&content),
+                _ => audio::extract(content),
+            })
+            .await
+        }
+    };
+
+    let media = match extract_res {
+        Ok(media) => media,
+        Err(e) => {
+            tracing::debug!("could not extract media of {}: {:?}", file_id, e);
+            return Ok(());
+        }
+    };
+
+    // the content may have been replaced in the meantime
+    db_files
+        .update_one(
+            doc! { "_id": file_id, "hash": &db_file.hash },
+            doc! { "$set": { "media": bson::to_bson(&media)? } },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// runs a parser on a blocking thread, parsing a malformed file may panic
+async fn parse<F>(parser: F) -> Result<DbMedia, anyhow::Error>
+where
+    F: FnOnce() -> Result<DbMedia, anyhow::Error> + Send + 'static,
+{
+    tokio::task::spawn_blocking(parser)
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+}
+
+/// The kinds of files whose metadata is extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Photo,
+    Audio,
+    Video,
+}
+
+impl Kind {
+    fn of(db_file: &DbFile) -> Option<Self> {
+        let extension = Path::new(&db_file.path)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+
+        let kind = match extension.as_str() {
+            "jpg" | "jpeg" | "png" | "webp" | "tif" | "tiff" | "heic" | "heif" => Kind::Photo,
+            "mp3" | "flac" | "ogg" | "oga" | "opus" | "wav" | "m4a" | "mka" => Kind::Audio,
+            "mp4" | "m4v" | "mov" => Kind::Video,
+            _ => return None,
+        };
+
+        match kind {
+            Kind::Video => Some(kind),
+            _ if db_file.size <= MAX_READ_SIZE => Some(kind),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::anyhow;
+use futures_util::StreamExt;
+use mongodb::bson;
+
+use crate::{blob::BlobStream, models::DbMedia};
+
+/// the header of a video is rejected if it is larger
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+/// seconds between 1904, the epoch of mp4 times, and 1970
+const MP4_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// reads an mp4 or QuickTime file up to the end of its `moov` box, which holds the header
+/// of the video, and returns the content of the box
+///
+/// the boxes before it are skipped without being kept, the header of videos that are not
+/// optimized for streaming is at the end, so the whole file is read
+pub async fn read_moov(mut stream: BlobStream) -> Result<Vec<u8>, anyhow::Error> {
+    let mut buf = Vec::new();
+    let mut skip = 0;
+
+    loop {
+        let skipped = skip.min(buf.len() as u64);
+        buf.drain(..skipped as usize);
+        skip -= skipped;
+
+        if skip == 0 {
+            if let Some((header_size, size, box_type)) = box_header(&buf)? {
+                if &box_type != b"moov" {
+                    skip = size;
+                    continue;
+                }
+
+                if size > MAX_MOOV_SIZE {
+                    return Err(anyhow!("moov box of {} bytes is too large", size));
+                }
+
+                if buf.len() as u64 >= size {
+                    buf.truncate(size as usize);
+                    buf.drain(..header_size);
+                    return Ok(buf);
+                }
+            }
+        }
+
+        match stream.next().await {
+            Some(bytes) => buf.extend(bytes?),
+            None => return Err(anyhow!("video has no moov box")),
+        }
+    }
+}
+
+/// the duration, creation time and dimensions of a video from the content of its `moov` box
+pub fn parse(moov: &[u8]) -> Result<DbMedia, anyhow::Error> {
+    let mut media = DbMedia::default();
+
+    for (box_type, content) in boxes(moov)? {
+        match &box_type {
+            b"mvhd" => {
+                let (created, time_scale, duration) = match content.first() {
+                    Some(1) => (
+                        read_u64(content, 4)?,
+                        read_u32(content, 20)?,
+                        read_u64(content, 24)?,
+                    ),
+                    _ => (
+                        read_u32(content, 4)?.into(),
+                        read_u32(content, 12)?,
+                        read_u32(content, 16)?.into(),
+                    ),
+                };
+
+                // corrupt headers can hold values that overflow
+                media.duration = duration
+                    .checked_mul(1000)
+                    .and_then(|millis| millis.checked_div(time_scale.into()));
+
+                // cameras that do not know the time write 0
+                if created > 0 {
+                    media.captured_at = i64::try_from(created)
+                        .ok()
+                        .and_then(|created| created.checked_sub(MP4_EPOCH_OFFSET))
+                        .and_then(|seconds| seconds.checked_mul(1000))
+                        .map(bson::DateTime::from_millis);
+                }
+            }
+            // the first track with a size is the video, audio tracks have none
+            b"trak" if media.width.is_none() => {
+                for (box_type, content) in boxes(content)? {
+                    if &box_type != b"tkhd" {
+                        continue;
+                    }
+
+                    let offset = match content.first() {
+                        Some(1) => 88,
+                        _ => 76,
+                    };
+                    // 16.16 fixed point numbers
+                    let width = read_u32(content, offset)? >> 16;
+                    let height = read_u32(content, offset + 4)? >> 16;
+
+                    if width > 0 && height > 0 {
+                        media.width = Some(width);
+                        media.height = Some(height);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(media)
+}
+
+/// the size of the header, the size of the box including the header and its type, `None` if
+/// `buf` does not hold the whole header
+fn box_header(buf: &[u8]) -> Result<Option<(usize, u64, [u8; 4])>, anyhow::Error> {
+    if buf.len() < 8 {
+        return Ok(None);
+    }
+
+    let box_type = buf[4..8].try_into().unwrap();
+
+    let (header_size, size) = match read_u32(buf, 0)? {
+        1 if buf.len() < 16 => return Ok(None),
+        1 => (16, read_u64(buf, 8)?),
+        0 => return Err(anyhow!("boxes extending to the end are not supported")),
+        size => (8, size.into()),
+    };
+
+    if size < header_size as u64 {
+        return Err(anyhow!("box is smaller than its header"));
+    }
+
+    Ok(Some((header_size, size, box_type)))
+}
+
+/// the type and content of a box
+type Mp4Box<'a> = ([u8; 4], &'a [u8]);
+
+/// the boxes in `buf`
+fn boxes(mut buf: &[u8]) -> Result<Vec<Mp4Box<'_>>, anyhow::Error> {
+    let mut boxes = Vec::new();
+
+    while !buf.is_empty() {
+        let (header_size, size, box_type) =
+            box_header(buf)?.ok_or_else(|| anyhow!("box header is truncated"))?;
+
+        if size > buf.len() as u64 {
+            return Err(anyhow!("box is truncated"));
+        }
+
+        boxes.push((box_type, &buf[header_size..size as usize]));
+        buf = &buf[size as usize..];
+    }
+
+    Ok(boxes)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, anyhow::Error> {
+    let bytes = buf
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("box is truncated"))?;
+
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Result<u64, anyhow::Error> {
+    let bytes = buf
+        .get(offset..offset + 8)
+        .ok_or_else(|| anyhow!("box is truncated"))?;
+
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson;
+
+    use crate::{blob::BlobStream, media::mp4};
+
+    fn mp4_box(box_type: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut mp4_box = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        mp4_box.extend(box_type);
+        mp4_box.extend(content);
+        mp4_box
+    }
+
+    #[tokio::test]
+    async fn read_video_header() {
+        // version 0: created, modified, time scale, duration
+        let mut mvhd = vec![0; 4];
+        mvhd.extend((mp4::MP4_EPOCH_OFFSET as u32 + 1_700_000_000).to_be_bytes());
+        mvhd.extend(0_u32.to_be_bytes());
+        mvhd.extend(600_u32.to_be_bytes());
+        mvhd.extend(9000_u32.to_be_bytes());
+        mvhd.resize(100, 0);
+
+        // audio tracks have no size
+        let audio_tkhd = vec![0; 84];
+        let mut video_tkhd = vec![0; 84];
+        video_tkhd[76..80].copy_from_slice(&(1920_u32 << 16).to_be_bytes());
+        video_tkhd[80..84].copy_from_slice(&(1080_u32 << 16).to_be_bytes());
+
+        let mut moov = mp4_box(b"mvhd", &mvhd);
+        moov.extend(mp4_box(b"trak", &mp4_box(b"tkhd", &audio_tkhd)));
+        moov.extend(mp4_box(b"trak", &mp4_box(b"tkhd", &video_tkhd)));
+
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend(mp4_box(b"mdat", &[7; 100]));
+        file.extend(mp4_box(b"moov", &moov));
+
+        // the file arrives in small pieces
+        let pieces = file.chunks(7).map(|c| Ok(c.to_vec())).collect::<Vec<_>>();
+        let stream: BlobStream = Box::pin(futures_util::stream::iter(pieces));
+        assert_eq!(moov, mp4::read_moov(stream).await.unwrap());
+
+        let media = mp4::parse(&moov).unwrap();
+        assert_eq!(Some(15_000), media.duration);
+        assert_eq!(
+            Some(bson::DateTime::from_millis(1_700_000_000_000)),
+            media.captured_at
+        );
+        assert_eq!((Some(1920), Some(1080)), (media.width, media.height));
+
+        let stream: BlobStream =
+            Box::pin(futures_util::stream::iter(vec![Ok(file[..20].to_vec())]));
+        assert!(mp4::read_moov(stream).await.is_err());
+    }
+
+    #[test]
+    fn parse_overflowing_header() {
+        // version 1: created, modified, time scale, duration
+        let mut mvhd = vec![1, 0, 0, 0];
+        mvhd.extend(u64::MAX.to_be_bytes());
+        mvhd.extend(0_u64.to_be_bytes());
+        mvhd.extend(600_u32.to_be_bytes());
+        mvhd.extend(u64::MAX.to_be_bytes());
+        mvhd.resize(112, 0);
+
+        let media = mp4::parse(&mp4_box(b"mvhd", &mvhd)).unwrap();
+        assert_eq!(None, media.duration);
+        assert_eq!(None, media.captured_at);
+    }
+}
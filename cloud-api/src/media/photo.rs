@@ -0,0 +1,155 @@
+use std::io::Cursor;
+
+use chrono::{FixedOffset, NaiveDate, TimeZone, Utc};
+use exif::{DateTime, Exif, Field, In, Rational, Reader, Tag, Value};
+use mongodb::bson;
+
+use crate::models::DbMedia;
+
+/// the exif data of a photo and its dimensions, a photo without exif data only has its
+/// dimensions
+pub fn extract(content: &[u8]) -> Result<DbMedia, anyhow::Error> {
+    let mut media = DbMedia::default();
+
+    if let Ok((width, height)) = image::io::Reader::new(Cursor::new(content))
+        .with_guessed_format()?
+        .into_dimensions()
+    {
+        media.width = Some(width);
+        media.height = Some(height);
+    }
+
+    let exif = match Reader::new().read_from_container(&mut Cursor::new(content)) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(media),
+        Err(e) => return Err(e.into()),
+    };
+
+    media.captured_at = captured_at(&exif);
+    media.camera_make = ascii(exif.get_field(Tag::Make, In::PRIMARY));
+    media.camera_model = ascii(exif.get_field(Tag::Model, In::PRIMARY));
+    media.latitude = coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S');
+    media.longitude = coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W');
+
+    Ok(media)
+}
+
+/// the first string of an ascii field without trailing padding
+fn ascii(field: Option<&Field>) -> Option<String> {
+    match &field?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_end_matches(['\0', ' ']).trim_start();
+
+            (!value.is_empty()).then(|| value.to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// the original date and time, which is local time of the camera and taken as utc unless
+/// the photo has the offset
+fn captured_at(exif: &Exif) -> Option<bson::DateTime> {
+    let field = exif
+        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
+        .or_else(|| exif.get_field(Tag::DateTime, In::PRIMARY))?;
+
+    let mut date_time = match &field.value {
+        Value::Ascii(values) => DateTime::from_ascii(values.first()?).ok()?,
+        _ => return None,
+    };
+
+    if let Some(Field {
+        value: Value::Ascii(values),
+        ..
+    }) = exif.get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+    {
+        if let Some(offset) = values.first() {
+            date_time.parse_offset(offset).ok();
+        }
+    }
+
+    to_date_time(&date_time)
+}
+
+fn to_date_time(date_time: &DateTime) -> Option<bson::DateTime> {
+    let local = NaiveDate::from_ymd_opt(
+        date_time.year.into(),
+        date_time.month.into(),
+        date_time.day.into(),
+    )?
+    .and_hms_nano_opt(
+        date_time.hour.into(),
+        date_time.minute.into(),
+        date_time.second.into(),
+        date_time.nanosecond.unwrap_or(0),
+    )?;
+
+    let utc = match date_time.offset {
+        Some(offset) => FixedOffset::east_opt(i32::from(offset) * 60)?
+            .from_local_datetime(&local)
+            .single()?
+            .with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&local),
+    };
+
+    Some(bson::DateTime::from_millis(utc.timestamp_millis()))
+}
+
+/// a gps coordinate in degrees, negative in the direction `negative_ref`
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(parts) => degrees(parts)?,
+        _ => return None,
+    };
+
+    let negative = match exif.get_field(ref_tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values.first().and_then(|v| v.first()) == Some(&negative_ref),
+        _ => false,
+    };
+
+    Some(if negative { -degrees } else { degrees })
+}
+
+/// degrees, minutes and seconds in degrees
+fn degrees(parts: &[Rational]) -> Option<f64> {
+    let [degrees, minutes, seconds] = parts else {
+        return None;
+    };
+
+    let degrees = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+    degrees.is_finite().then_some(degrees)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use exif::{DateTime, Rational};
+    use mongodb::bson;
+
+    use crate::media::photo;
+
+    #[test]
+    fn convert_exif_values() {
+        let parts = [
+            Rational::from((52, 1)),
+            Rational::from((31, 1)),
+            Rational::from((1234, 100)),
+        ];
+        let degrees = photo::degrees(&parts).unwrap();
+        assert!((degrees - 52.520_094).abs() < 1e-6);
+        assert_eq!(None, photo::degrees(&parts[..2]));
+
+        let at = |hour| {
+            let utc = Utc.with_ymd_and_hms(2024, 5, 1, hour, 30, 0).unwrap();
+            Some(bson::DateTime::from_millis(utc.timestamp_millis()))
+        };
+
+        // local time without an offset is taken as utc
+        let mut date_time = DateTime::from_ascii(b"2024:05:01 14:30:00").unwrap();
+        assert_eq!(at(14), photo::to_date_time(&date_time));
+
+        date_time.parse_offset(b"+02:00").unwrap();
+        assert_eq!(at(12), photo::to_date_time(&date_time));
+    }
+}
@@ -1,17 +1,29 @@
-use chrono::{DateTime, Utc};
+use std::{collections::HashMap, time::Duration};
+
 use cloud_proto::{prost_types::Timestamp, proto};
-use mongodb::bson::oid::ObjectId;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{self, bson, doc, oid::ObjectId, Bson, Document},
+    options::{AggregateOptions, IndexOptions},
+    IndexModel,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::config::QuotaCharge;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DbUser {
     #[serde(rename = "_id")]
     pub id: ObjectId,
     pub email: String,
     pub username: String,
-    pub passhash: String,
+    /// `None` for users that sign in through an external identity provider
+    pub passhash: Option<String>,
     pub storage_quota: Option<u64>,
     pub storage_used: u64,
+    /// grants the admin scope, there is no rpc to change it
+    #[serde(default)]
+    pub admin: bool,
 }
 
 impl DbUser {
@@ -25,27 +37,708 @@ impl DbUser {
     }
 }
 
+/// Links a subject of an external identity provider to a user.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbIdentity {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub provider: String,
+    pub subject: String,
+    pub user_id: ObjectId,
+}
+
+/// How long a started single sign-on may take before it has to be restarted.
+pub const SSO_REQUEST_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// A single sign-on that has been started with `BeginSso`, keyed by its state.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbSsoRequest {
+    #[serde(rename = "_id")]
+    pub state: String,
+    pub provider: String,
+    pub redirect_uri: String,
+    pub pkce_verifier: String,
+    pub nonce: String,
+    pub created_at: bson::DateTime,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DbFile {
     #[serde(rename = "_id")]
     pub id: ObjectId,
     pub owner_id: ObjectId,
-    pub bucket_id: ObjectId,
+    /// the blob holding the content, `None` for files stored in chunks
+    pub bucket_id: Option<ObjectId>,
+    /// hashes of the chunks holding the content in order, `None` for files stored in a
+    /// single blob
+    pub chunks: Option<Vec<String>>,
+    /// the blob holding the bao outboard tree of the content, `None` for files stored in
+    /// chunks or before outboards were kept
+    pub outboard_id: Option<ObjectId>,
     pub path: String,
     pub hash: String,
     pub size: u64,
-    pub modified_at: DateTime<Utc>,
+    /// size of the blob or the sum of the chunk sizes, `None` for files stored before it was tracked
+    pub stored_size: Option<u64>,
+    /// when the file was first uploaded to its path
+    pub created_at: bson::DateTime,
+    /// when the content of the file was last committed
+    pub modified_at: bson::DateTime,
+    /// modification time of the local file, `None` if the client did not report it
+    pub mtime: Option<bson::DateTime>,
+    /// posix permission bits of the local file, `None` if the client did not report it
+    pub mode: Option<u32>,
+    /// guessed from the extension of the path
+    pub mime_type: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub favorite: bool,
+    /// custom key/value metadata set by the owner
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// `None` until the media metadata of the content has been extracted
+    #[serde(default)]
+    pub media: Option<DbMedia>,
 }
 
 impl DbFile {
+    /// the blobs owned by this file alone, chunks are shared and not included
+    pub fn blob_ids(&self) -> Vec<ObjectId> {
+        self.bucket_id.into_iter().chain(self.outboard_id).collect()
+    }
+
+    /// the size charged to the storage quota of the owner
+    pub fn charged_size(&self, quota_charge: QuotaCharge) -> u64 {
+        match quota_charge {
+            QuotaCharge::Logical => self.size,
+            QuotaCharge::Stored => self.stored_size.unwrap_or(self.size),
+        }
+    }
+
+    /// the aggregation expression of [`DbFile::charged_size`]
+    pub fn charged_size_expression(quota_charge: QuotaCharge) -> Bson {
+        match quota_charge {
+            QuotaCharge::Logical => bson!("$size"),
+            QuotaCharge::Stored => bson!({ "$ifNull": ["$stored_size", "$size"] }),
+        }
+    }
+
     pub fn to_proto(&self) -> proto::File {
         proto::File {
             id: self.id.to_string(),
             path: self.path.to_owned(),
             hash: self.hash.to_owned(),
             size: self.size,
-            created_at: Some(Timestamp::default()),
-            modified_at: Some(Timestamp::default()),
+            created_at: Some(from_date_time(self.created_at)),
+            modified_at: Some(from_date_time(self.modified_at)),
+            mtime: self.mtime.map(from_date_time),
+            mode: self.mode,
+            mime_type: self.mime_type.to_owned(),
+            tags: self.tags.to_owned(),
+            favorite: self.favorite,
+            metadata: self.metadata.to_owned(),
+            media: self.media.as_ref().map(|m| m.to_proto()),
+        }
+    }
+}
+
+/// Metadata of a photo, audio file or video extracted from its content.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DbMedia {
+    /// when the photo or video was taken
+    pub captured_at: Option<bson::DateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// milliseconds
+    pub duration: Option<u64>,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    pub genre: Option<String>,
+}
+
+impl DbMedia {
+    pub fn to_proto(&self) -> proto::MediaInfo {
+        proto::MediaInfo {
+            captured_at: self.captured_at.map(from_date_time),
+            camera_make: self.camera_make.to_owned(),
+            camera_model: self.camera_model.to_owned(),
+            latitude: self.latitude,
+            longitude: self.longitude,
+            width: self.width,
+            height: self.height,
+            duration: self.duration,
+            title: self.title.to_owned(),
+            artist: self.artist.to_owned(),
+            album: self.album.to_owned(),
+            track_number: self.track_number,
+            genre: self.genre.to_owned(),
+        }
+    }
+}
+
+/// the mime type of a file by the extension of its path
+pub fn mime_type(path: &str) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .essence_str()
+        .to_owned()
+}
+
+/// A content-defined chunk of the files of a user, addressed by its hash.
+///
+/// Every chunk is stored once per owner and shared by all files that contain it, chunks
+/// that are not referenced by any file are removed by the blob garbage collection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbChunk {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub hash: String,
+    pub size: u64,
+    pub stored_size: u64,
+    pub bucket_id: ObjectId,
+    /// set whenever the chunk is reported as present to a client or a file is committed
+    /// with it, so a chunk that is about to be referenced is not collected
+    pub last_used_at: bson::DateTime,
+}
+
+impl DbChunk {
+    pub fn charged_size(&self, quota_charge: QuotaCharge) -> u64 {
+        match quota_charge {
+            QuotaCharge::Logical => self.size,
+            QuotaCharge::Stored => self.stored_size,
+        }
+    }
+
+    /// the aggregation expression of [`DbChunk::charged_size`]
+    pub fn charged_size_expression(quota_charge: QuotaCharge) -> Bson {
+        match quota_charge {
+            QuotaCharge::Logical => bson!("$size"),
+            QuotaCharge::Stored => bson!("$stored_size"),
+        }
+    }
+
+    pub fn to_proto(&self) -> proto::ChunkInfo {
+        proto::ChunkInfo {
+            hash: self.hash.to_owned(),
+            size: self.size,
+        }
+    }
+}
+
+/// A thumbnail of the content of a file with the hash, stored as a jpeg blob.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbThumbnail {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub file_id: ObjectId,
+    pub hash: String,
+    /// the edge length the picture was scaled to fit
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bucket_id: ObjectId,
+}
+
+/// Content of a user no thumbnails could be rendered of, so files with the hash are not
+/// queued again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbThumbnailFailure {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub hash: String,
+}
+
+/// An advisory lock on a path, held by the session that took it until it expires.
+///
+/// Files belong to a single user, so the lock keeps the owner's other sign-ins from
+/// changing the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbFileLock {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub path: String,
+    pub holder_id: ObjectId,
+    pub holder_name: String,
+    pub session: ObjectId,
+    /// a bson date, so the ttl index removes expired locks
+    pub expires_at: bson::DateTime,
+}
+
+impl DbFileLock {
+    pub fn to_proto(&self, session: ObjectId) -> proto::FileLock {
+        proto::FileLock {
+            path: self.path.to_owned(),
+            holder_id: self.holder_id.to_string(),
+            holder_name: self.holder_name.to_owned(),
+            expires_at: Some(self.expires_at.to_system_time().into()),
+            held_by_caller: self.session == session,
+        }
+    }
+}
+
+/// An operation on a file in the activity log of its owner.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbActivity {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub file_id: ObjectId,
+    pub kind: DbActivityKind,
+    /// the path after the operation, or the path the file was deleted from
+    pub path: String,
+    /// the path a moved file had before
+    pub old_path: Option<String>,
+    pub old_hash: Option<String>,
+    pub new_hash: Option<String>,
+    pub occurred_at: bson::DateTime,
+    /// the sign-in the operation was done in
+    pub session: ObjectId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbActivityKind {
+    Upload,
+    Replace,
+    Delete,
+    Move,
+}
+
+impl DbActivity {
+    /// an operation in `session` on `db_file` as it is after the operation, the caller sets
+    /// the old path and the hashes
+    pub fn new(kind: DbActivityKind, db_file: &DbFile, session: ObjectId) -> Self {
+        Self {
+            id: ObjectId::new(),
+            owner_id: db_file.owner_id,
+            file_id: db_file.id,
+            kind,
+            path: db_file.path.to_owned(),
+            old_path: None,
+            old_hash: None,
+            new_hash: None,
+            occurred_at: bson::DateTime::now(),
+            session,
+        }
+    }
+
+    pub fn to_proto(&self, session: ObjectId) -> proto::Activity {
+        let kind = match self.kind {
+            DbActivityKind::Upload => proto::ActivityKind::Upload,
+            DbActivityKind::Replace => proto::ActivityKind::Replace,
+            DbActivityKind::Delete => proto::ActivityKind::Delete,
+            DbActivityKind::Move => proto::ActivityKind::Move,
+        };
+
+        proto::Activity {
+            id: self.id.to_string(),
+            kind: kind as i32,
+            file_id: self.file_id.to_string(),
+            path: self.path.to_owned(),
+            old_path: self.old_path.to_owned(),
+            old_hash: self.old_hash.to_owned(),
+            new_hash: self.new_hash.to_owned(),
+            occurred_at: Some(from_date_time(self.occurred_at)),
+            session: self.session.to_string(),
+            by_caller_session: self.session == session,
+        }
+    }
+}
+
+/// A file whose blob failed verification during a scrub, keyed by the file id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DbCorruptedFile {
+    #[serde(rename = "_id")]
+    pub file_id: ObjectId,
+    pub owner_id: ObjectId,
+    pub bucket_id: Option<ObjectId>,
+    pub path: String,
+    pub expected_hash: String,
+    /// `None` if the blob could not be read at all
+    pub actual_hash: Option<String>,
+    pub error: Option<String>,
+    pub detected_at: bson::DateTime,
+}
+
+impl DbCorruptedFile {
+    pub fn to_proto(&self) -> proto::CorruptedFile {
+        proto::CorruptedFile {
+            file_id: self.file_id.to_string(),
+            owner_id: self.owner_id.to_string(),
+            path: self.path.to_owned(),
+            expected_hash: self.expected_hash.to_owned(),
+            actual_hash: self.actual_hash.to_owned(),
+            error: self.error.to_owned(),
+            detected_at: Some(from_date_time(self.detected_at)),
+        }
+    }
+}
+
+pub fn from_date_time(date_time: bson::DateTime) -> Timestamp {
+    let millis = date_time.timestamp_millis();
+
+    Timestamp {
+        seconds: millis.div_euclid(1000),
+        nanos: (millis.rem_euclid(1000) * 1_000_000) as i32,
+    }
+}
+
+/// bson dates only keep milliseconds
+pub fn to_date_time(timestamp: &Timestamp) -> bson::DateTime {
+    bson::DateTime::from_millis(timestamp.seconds * 1000 + timestamp.nanos as i64 / 1_000_000)
+}
+
+/// converts documents stored by older versions, run before the server accepts requests
+pub async fn migrate(db: &mongodb::Database) -> Result<(), mongodb::error::Error> {
+    let db_files = db.collection::<Document>("files");
+
+    // modification times used to be stored as strings, which can not be filtered or sorted
+    db_files
+        .update_many(
+            doc! { "modified_at": { "$type": "string" } },
+            vec![doc! { "$set": { "modified_at": { "$toDate": "$modified_at" } } }],
+            None,
+        )
+        .await?;
+
+    // files uploaded before creation times were kept were created with their id
+    db_files
+        .update_many(
+            doc! { "created_at": { "$exists": false } },
+            vec![doc! { "$set": { "created_at": { "$toDate": "$_id" } } }],
+            None,
+        )
+        .await?;
+
+    // paths used to hold several files before they were unique, the most recently modified
+    // file keeps the path and the others are renamed so the unique index can be built
+    let mut duplicates = db_files
+        .aggregate(
+            [
+                doc! { "$sort": { "modified_at": -1, "_id": -1 } },
+                doc! { "$group": {
+                    "_id": { "owner_id": "$owner_id", "path": "$path" },
+                    "file_ids": { "$push": "$_id" },
+                } },
+                doc! { "$match": { "file_ids.1": { "$exists": true } } },
+            ],
+            AggregateOptions::builder().allow_disk_use(true).build(),
+        )
+        .await?;
+
+    while let Some(duplicate) = duplicates.try_next().await? {
+        let (Ok(path), Ok(file_ids)) = (
+            duplicate
+                .get_document("_id")
+                .and_then(|id| id.get_str("path")),
+            duplicate.get_array("file_ids"),
+        ) else {
+            continue;
+        };
+
+        for file_id in file_ids.iter().skip(1).filter_map(Bson::as_object_id) {
+            let renamed = duplicate_path(path, file_id);
+            tracing::warn!("renaming file {} at duplicate path to {}", file_id, renamed);
+
+            db_files
+                .update_one(
+                    doc! { "_id": file_id },
+                    doc! { "$set": { "path": renamed } },
+                    None,
+                )
+                .await?;
         }
     }
+
+    // corruptions used to be detected at string times, which can not be sorted
+    db.collection::<Document>("corrupted_files")
+        .update_many(
+            doc! { "detected_at": { "$type": "string" } },
+            vec![doc! { "$set": { "detected_at": { "$toDate": "$detected_at" } } }],
+            None,
+        )
+        .await?;
+
+    // sign-ins used to be started with string creation times, which do not expire
+    db.collection::<Document>("sso_requests")
+        .delete_many(doc! { "created_at": { "$type": "string" } }, None)
+        .await?;
+
+    let mut untyped = db_files
+        .find(doc! { "mime_type": { "$exists": false } }, None)
+        .await?;
+
+    while let Some(db_file) = untyped.try_next().await? {
+        let (Ok(id), Ok(path)) = (db_file.get_object_id("_id"), db_file.get_str("path")) else {
+            continue;
+        };
+
+        db_files
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "mime_type": mime_type(path) } },
+                None,
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// the path a file at a duplicate path is moved to, `/a/b.txt` becomes
+/// `/a/b (duplicate <id>).txt`
+fn duplicate_path(path: &str, file_id: ObjectId) -> String {
+    let name_start = path.rfind('/').map_or(0, |i| i + 1);
+
+    match path[name_start..].rfind('.') {
+        // names starting with a dot have no extension
+        Some(i) if i > 0 => {
+            let (stem, extension) = path.split_at(name_start + i);
+            format!("{} (duplicate {}){}", stem, file_id, extension)
+        }
+        _ => format!("{} (duplicate {})", path, file_id),
+    }
+}
+
+pub async fn create_indexes(db: &mongodb::Database) -> Result<(), mongodb::error::Error> {
+    let db_files = db.collection::<DbFile>("files");
+
+    // a path holds a single file, concurrent uploads to the same path can not both insert
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "path": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    // listings ordered by modification time or size
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "modified_at": 1, "_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "size": 1, "_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    // photo timelines
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "media.captured_at": 1, "_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    // listings by tag and of favorites
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "tags": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "favorite": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    // blob garbage collection looks up files by their blob
+    db_files
+        .create_index(
+            IndexModel::builder().keys(doc! { "bucket_id": 1 }).build(),
+            None,
+        )
+        .await?;
+    db_files
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "outboard_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    // and chunks by the files containing them
+    db_files
+        .create_index(
+            IndexModel::builder().keys(doc! { "chunks": 1 }).build(),
+            None,
+        )
+        .await?;
+
+    let db_chunks = db.collection::<DbChunk>("chunks");
+
+    db_chunks
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "hash": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    db_chunks
+        .create_index(
+            IndexModel::builder().keys(doc! { "bucket_id": 1 }).build(),
+            None,
+        )
+        .await?;
+
+    let db_thumbnails = db.collection::<DbThumbnail>("thumbnails");
+
+    db_thumbnails
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "file_id": 1, "size": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    db_thumbnails
+        .create_index(
+            IndexModel::builder().keys(doc! { "bucket_id": 1 }).build(),
+            None,
+        )
+        .await?;
+
+    db.collection::<DbThumbnailFailure>("thumbnail_failures")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "hash": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    let db_locks = db.collection::<DbFileLock>("file_locks");
+
+    db_locks
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "path": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    db_locks
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(Duration::from_secs(0))
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await?;
+
+    // an external identity belongs to a single user, concurrent first sign-ins can not
+    // both create one
+    db.collection::<DbIdentity>("identities")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "provider": 1, "subject": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+
+    // abandoned sign-ins
+    db.collection::<DbSsoRequest>("sso_requests")
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "created_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .expire_after(SSO_REQUEST_LIFETIME)
+                        .build(),
+                )
+                .build(),
+            None,
+        )
+        .await?;
+
+    let db_activities = db.collection::<DbActivity>("activities");
+
+    // the activity of an owner or of one of their files, newest first
+    db_activities
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+    db_activities
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! { "owner_id": 1, "file_id": 1, "_id": 1 })
+                .build(),
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::models;
+
+    #[test]
+    fn duplicate_path_keeps_extension() {
+        let id = ObjectId::new();
+
+        assert_eq!(
+            format!("/a.d/b (duplicate {}).txt", id),
+            models::duplicate_path("/a.d/b.txt", id)
+        );
+        assert_eq!(
+            format!("/a.d/b (duplicate {})", id),
+            models::duplicate_path("/a.d/b", id)
+        );
+        assert_eq!(
+            format!("/.env (duplicate {})", id),
+            models::duplicate_path("/.env", id)
+        );
+    }
 }
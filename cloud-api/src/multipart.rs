@@ -0,0 +1,412 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use cloud_proto::proto::UploadInfo;
+use mongodb::bson::oid::ObjectId;
+use tokio::{
+    fs::File,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use tonic::Status;
+
+use crate::staging::StagedUpload;
+
+/// smallest part size, only the last part of an upload may be smaller
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// the part size of larger uploads is raised until they have at most this many parts
+pub const MAX_PARTS: u64 = 10_000;
+/// uploads that are not completed in time are discarded by the next upload that is created
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+/// uploads a user can have in progress at once, each one allocates its whole size in the
+/// staging directory
+const MAX_OPEN_UPLOADS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PartState {
+    Missing,
+    Writing,
+    Written,
+}
+
+/// The multipart uploads in progress on this server.
+///
+/// Uploads are only kept in memory, their staging files are removed with all other staged
+/// uploads when the server starts.
+#[derive(Debug)]
+pub struct MultipartUploads {
+    staging_dir: PathBuf,
+    uploads: Mutex<HashMap<ObjectId, Arc<MultipartUpload>>>,
+}
+
+impl MultipartUploads {
+    pub fn new(staging_dir: PathBuf) -> Self {
+        Self {
+            staging_dir,
+            uploads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// creates an upload of `info.size` bytes in parts of at least `part_size` bytes, the
+    /// staging file is allocated up front so parts can be written in any order
+    pub async fn create(
+        &self,
+        owner_id: ObjectId,
+        info: UploadInfo,
+        part_size: u64,
+    ) -> Result<Arc<MultipartUpload>, Status> {
+        self.remove_expired().await;
+
+        let part_size = part_size
+            .max(MIN_PART_SIZE)
+            .max(info.size.div_ceil(MAX_PARTS));
+        let part_count = info.size.div_ceil(part_size).max(1) as usize;
+
+        let id = ObjectId::new();
+        let path = self.staging_dir.join(id.to_hex());
+        let size = info.size;
+
+        let upload = Arc::new(MultipartUpload {
+            id,
+            owner_id,
+            info,
+            part_size,
+            path,
+            parts: Mutex::new(vec![PartState::Missing; part_count]),
+            created_at: Instant::now(),
+        });
+
+        // the upload is counted before its staging file is allocated
+        {
+            let mut uploads = self.uploads.lock().unwrap();
+
+            if uploads.values().filter(|u| u.owner_id == owner_id).count() >= MAX_OPEN_UPLOADS {
+                return Err(Status::resource_exhausted(
+                    "too many multipart uploads in progress",
+                ));
+            }
+
+            uploads.insert(id, upload.clone());
+        }
+
+        let allocate_res = async {
+            let file = File::options()
+                .write(true)
+                .create_new(true)
+                .open(&upload.path)
+                .await?;
+            file.set_len(size).await
+        }
+        .await;
+
+        if let Err(e) = allocate_res {
+            self.uploads.lock().unwrap().remove(&id);
+            tokio::fs::remove_file(&upload.path).await.ok();
+            return Err(Status::internal(e.to_string()));
+        }
+
+        Ok(upload)
+    }
+
+    /// the total size of the uploads of a user that are still in progress
+    pub fn open_size(&self, owner_id: ObjectId) -> u64 {
+        self.uploads
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|u| u.owner_id == owner_id)
+            .map(|u| u.info.size)
+            .sum()
+    }
+
+    pub fn get(&self, id: ObjectId, owner_id: ObjectId) -> Result<Arc<MultipartUpload>, Status> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .get(&id)
+            .filter(|u| u.owner_id == owner_id)
+            .cloned()
+            .ok_or(Status::not_found("multipart upload not found"))
+    }
+
+    /// ends an upload whose parts have all been written, the returned staged upload is
+    /// hashed again from the staging file
+    pub async fn complete(
+        &self,
+        id: ObjectId,
+        owner_id: ObjectId,
+    ) -> Result<(Arc<MultipartUpload>, StagedUpload), Status> {
+        let upload = {
+            let mut uploads = self.uploads.lock().unwrap();
+
+            let upload = uploads
+                .get(&id)
+                .filter(|u| u.owner_id == owner_id)
+                .ok_or(Status::not_found("multipart upload not found"))?;
+
+            let parts = upload.parts.lock().unwrap();
+
+            if let Some(missing) = parts.iter().position(|p| *p != PartState::Written) {
+                return Err(Status::failed_precondition(format!(
+                    "part {} has not been uploaded",
+                    missing
+                )));
+            }
+
+            drop(parts);
+            uploads.remove(&id).unwrap()
+        };
+
+        // the staged upload removes the staging file from here on
+        match StagedUpload::open(upload.path.clone()).await {
+            Ok(staged) => Ok((upload, staged)),
+            Err(e) => {
+                remove_staging_file(&upload).await;
+                Err(Status::internal(e.to_string()))
+            }
+        }
+    }
+
+    pub async fn abort(&self, id: ObjectId, owner_id: ObjectId) -> Result<(), Status> {
+        let upload = self.get(id, owner_id)?;
+        self.uploads.lock().unwrap().remove(&id);
+
+        remove_staging_file(&upload).await;
+        Ok(())
+    }
+
+    async fn remove_expired(&self) {
+        let expired = {
+            let mut uploads = self.uploads.lock().unwrap();
+            let expired = uploads
+                .values()
+                .filter(|u| u.created_at.elapsed() > UPLOAD_EXPIRY)
+                .map(|u| u.id)
+                .collect::<Vec<_>>();
+
+            expired
+                .into_iter()
+                .filter_map(|id| uploads.remove(&id))
+                .collect::<Vec<_>>()
+        };
+
+        for upload in expired {
+            tracing::debug!("discarding expired multipart upload {}", upload.id);
+            remove_staging_file(&upload).await;
+        }
+    }
+}
+
+async fn remove_staging_file(upload: &MultipartUpload) {
+    if let Err(e) = tokio::fs::remove_file(&upload.path).await {
+        tracing::warn!("failed to remove multipart upload {:?}: {}", upload.path, e);
+    }
+}
+
+/// An upload whose parts are written concurrently by separate streams.
+#[derive(Debug)]
+pub struct MultipartUpload {
+    pub id: ObjectId,
+    pub owner_id: ObjectId,
+    pub info: UploadInfo,
+    pub part_size: u64,
+    path: PathBuf,
+    parts: Mutex<Vec<PartState>>,
+    created_at: Instant,
+}
+
+impl MultipartUpload {
+    pub fn part_count(&self) -> u32 {
+        self.parts.lock().unwrap().len() as u32
+    }
+
+    /// claims a part that has not been written yet, a part whose stream failed can be
+    /// uploaded again
+    pub async fn start_part(self: &Arc<Self>, number: u32) -> Result<PartWriter, Status> {
+        let offset = number as u64 * self.part_size;
+        let size = self.part_size.min(self.info.size.saturating_sub(offset));
+
+        {
+            let mut parts = self.parts.lock().unwrap();
+            let part = parts
+                .get_mut(number as usize)
+                .ok_or(Status::out_of_range("invalid part number"))?;
+
+            match part {
+                PartState::Missing => *part = PartState::Writing,
+                PartState::Writing => return Err(Status::already_exists("part is being uploaded")),
+                PartState::Written => return Err(Status::already_exists("part was uploaded")),
+            }
+        }
+
+        // the writer resets the part if it is dropped before it is finished
+        let mut writer = PartWriter {
+            upload: self.clone(),
+            number,
+            file: None,
+            remaining: size,
+            finished: false,
+        };
+
+        let mut file = File::options()
+            .write(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        writer.file = Some(file);
+        Ok(writer)
+    }
+}
+
+/// Writes the bytes of one part at its offset in the staging file.
+#[derive(Debug)]
+pub struct PartWriter {
+    upload: Arc<MultipartUpload>,
+    number: u32,
+    file: Option<File>,
+    remaining: u64,
+    finished: bool,
+}
+
+impl PartWriter {
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), Status> {
+        if bytes.len() as u64 > self.remaining {
+            return Err(Status::aborted("part exceeds the part size"));
+        }
+
+        self.file
+            .as_mut()
+            .unwrap()
+            .write_all(bytes)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.remaining -= bytes.len() as u64;
+
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<(), Status> {
+        if self.remaining > 0 {
+            return Err(Status::data_loss(format!(
+                "part {} is missing {} bytes",
+                self.number, self.remaining
+            )));
+        }
+
+        self.file
+            .as_mut()
+            .unwrap()
+            .flush()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        self.upload.parts.lock().unwrap()[self.number as usize] = PartState::Written;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for PartWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.upload.parts.lock().unwrap()[self.number as usize] = PartState::Missing;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud_proto::proto::UploadInfo;
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::multipart::{self, MultipartUploads};
+
+    #[tokio::test]
+    async fn parts_are_assembled_in_order() {
+        let dir = std::env::temp_dir().join(format!("cloud-multipart-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let content = (0..multipart::MIN_PART_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let info = UploadInfo {
+            path: "/a.bin".to_owned(),
+            hash: blake3::hash(&content).to_string(),
+            size: content.len() as u64,
+            expected_hash: None,
+            mtime: None,
+            mode: None,
+        };
+
+        let uploads = MultipartUploads::new(dir.clone());
+        let owner_id = ObjectId::new();
+        let upload = uploads.create(owner_id, info, 0).await.unwrap();
+        assert_eq!(multipart::MIN_PART_SIZE, upload.part_size);
+        assert_eq!(3, upload.part_count());
+
+        // parts arrive in any order, an unfinished part can be uploaded again
+        for number in [2, 0, 1] {
+            let offset = (number * upload.part_size) as usize;
+            let end = (offset + upload.part_size as usize).min(content.len());
+
+            let mut writer = upload.start_part(number as u32).await.unwrap();
+            assert!(upload.start_part(number as u32).await.is_err());
+            writer.write(&content[offset..offset + 1]).await.unwrap();
+            drop(writer);
+
+            let mut writer = upload.start_part(number as u32).await.unwrap();
+            writer.write(&content[offset..end]).await.unwrap();
+            writer.finish().await.unwrap();
+        }
+
+        assert!(uploads.complete(upload.id, ObjectId::new()).await.is_err());
+
+        let (_, staged) = uploads.complete(upload.id, owner_id).await.unwrap();
+        assert_eq!(upload.info.hash, staged.hash());
+        assert_eq!(content.len() as u64, staged.size());
+
+        drop(staged);
+        tokio::fs::remove_dir(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_uploads_are_limited() {
+        let dir = std::env::temp_dir().join(format!("cloud-multipart-open-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let info = UploadInfo {
+            path: "/a.bin".to_owned(),
+            size: 10,
+            ..Default::default()
+        };
+
+        let uploads = MultipartUploads::new(dir.clone());
+        let owner_id = ObjectId::new();
+        let mut created = Vec::new();
+
+        for _ in 0..multipart::MAX_OPEN_UPLOADS {
+            created.push(uploads.create(owner_id, info.clone(), 0).await.unwrap());
+        }
+
+        assert_eq!(10 * created.len() as u64, uploads.open_size(owner_id));
+        assert!(uploads.create(owner_id, info.clone(), 0).await.is_err());
+
+        // other users are not affected
+        created.push(uploads.create(ObjectId::new(), info, 0).await.unwrap());
+
+        for upload in created {
+            uploads.abort(upload.id, upload.owner_id).await.unwrap();
+        }
+
+        tokio::fs::remove_dir(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::{pin::Pin, sync::Arc, time::Duration};
+
+use cloud_proto::proto::{
+    admin_service_server::AdminService, CollectGarbageResponse, CorruptedFile,
+    ReconcileStorageUsageResponse, RotateKeysResponse, ScrubStatus,
+};
+use futures_util::{Stream, StreamExt};
+use mongodb::{bson::doc, options::FindOptions};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    config::Configuration,
+    crypto::MasterKeys,
+    jobs::{blob_gc, key_rotation, scrub::Scrubber, storage_usage},
+    models::{self, DbCorruptedFile},
+};
+
+#[derive(Debug)]
+pub struct MyAdminService {
+    config: Configuration,
+    mongo: mongodb::Client,
+    scrubber: Arc<Scrubber>,
+    master_keys: Option<Arc<MasterKeys>>,
+}
+
+impl MyAdminService {
+    pub fn new(
+        config: Configuration,
+        mongo: mongodb::Client,
+        scrubber: Arc<Scrubber>,
+        master_keys: Option<Arc<MasterKeys>>,
+    ) -> Self {
+        Self {
+            config,
+            mongo,
+            scrubber,
+            master_keys,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for MyAdminService {
+    type GetCorruptedFilesStream =
+        Pin<Box<dyn Stream<Item = Result<CorruptedFile, Status>> + Send>>;
+
+    async fn reconcile_storage_usage(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ReconcileStorageUsageResponse>, Status> {
+        let report = storage_usage::reconcile(&self.mongo, self.config.quota_charge)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(ReconcileStorageUsageResponse {
+            users_checked: report.users_checked,
+            users_repaired: report.users_repaired,
+        }))
+    }
+
+    async fn collect_garbage(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<CollectGarbageResponse>, Status> {
+        let grace_period = Duration::from_secs(self.config.blob_gc_grace_period);
+        let report = blob_gc::collect(&self.mongo, grace_period)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(CollectGarbageResponse {
+            blobs_removed: report.blobs_removed,
+            bytes_reclaimed: report.bytes_reclaimed,
+            chunks_removed: report.chunks_removed,
+        }))
+    }
+
+    async fn rotate_keys(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<RotateKeysResponse>, Status> {
+        let master_keys = self
+            .master_keys
+            .as_ref()
+            .ok_or(Status::failed_precondition(
+                "encryption at rest is not enabled",
+            ))?;
+
+        let report = key_rotation::rotate(&self.mongo, master_keys)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(RotateKeysResponse {
+            blobs_rewrapped: report.blobs_rewrapped,
+        }))
+    }
+
+    async fn start_scrub(&self, _request: Request<()>) -> Result<Response<()>, Status> {
+        if self.scrubber.status().running {
+            return Err(Status::already_exists("scrub is already running"));
+        }
+
+        // a scrub reads every blob and outlives the request
+        let scrubber = self.scrubber.clone();
+        tokio::spawn(async move {
+            if let Err(e) = scrubber.run().await {
+                tracing::error!("storage scrub failed: {}", e);
+            }
+        });
+
+        Ok(Response::new(()))
+    }
+
+    async fn get_scrub_status(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<ScrubStatus>, Status> {
+        let status = self.scrubber.status();
+
+        Ok(Response::new(ScrubStatus {
+            running: status.running,
+            started_at: status.started_at.map(models::from_date_time),
+            finished_at: status.finished_at.map(models::from_date_time),
+            files_checked: status.files_checked,
+            bytes_checked: status.bytes_checked,
+            files_corrupted: status.files_corrupted,
+        }))
+    }
+
+    async fn get_corrupted_files(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<Self::GetCorruptedFilesStream>, Status> {
+        let db = self.mongo.database("cloud");
+        let db_corrupted_files = db.collection::<DbCorruptedFile>("corrupted_files");
+
+        let options = FindOptions::builder()
+            .sort(doc! { "detected_at": -1 })
+            .build();
+        let cursor = db_corrupted_files
+            .find(doc! {}, options)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map(|f| match f {
+                Ok(f) => Ok(f.to_proto()),
+                Err(e) => Err(Status::internal(e.to_string())),
+            });
+
+        Ok(Response::new(Box::pin(cursor)))
+    }
+}
@@ -2,27 +2,182 @@ use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
+use std::sync::Arc;
+
 use cloud_proto::proto::{
-    auth_service_server::AuthService, AuthLoginRequest, AuthLoginResponse, AuthRegisterRequest,
-    AuthRegisterResponse,
+    self, auth_service_server::AuthService, AuthLoginRequest, AuthLoginResponse,
+    AuthRegisterRequest, AuthRegisterResponse, BeginSsoRequest, BeginSsoResponse,
+    CompleteSsoRequest, GetIdentityProvidersResponse,
+};
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    error::{ErrorKind, WriteError, WriteFailure, TRANSIENT_TRANSACTION_ERROR},
 };
-use mongodb::bson::{doc, oid::ObjectId};
 use tonic::{Request, Response, Status};
 
-use crate::{auth_token, config::Configuration, models::DbUser};
+use crate::{
+    auth_token,
+    config::Configuration,
+    identity::{ExternalIdentity, IdentityProvider},
+    models::{DbIdentity, DbSsoRequest, DbUser, SSO_REQUEST_LIFETIME},
+};
 
 #[derive(Debug)]
 pub struct MyAuthService {
     config: Configuration,
     mongo: mongodb::Client,
+    identity_providers: Vec<Arc<dyn IdentityProvider>>,
 }
 
 impl MyAuthService {
-    pub fn new(config: Configuration, mongo: mongodb::Client) -> Self {
-        Self { config, mongo }
+    pub fn new(
+        config: Configuration,
+        mongo: mongodb::Client,
+        identity_providers: Vec<Arc<dyn IdentityProvider>>,
+    ) -> Self {
+        Self {
+            config,
+            mongo,
+            identity_providers,
+        }
+    }
+
+    fn find_identity_provider(&self, id: &str) -> Result<&Arc<dyn IdentityProvider>, Status> {
+        self.identity_providers
+            .iter()
+            .find(|p| p.id() == id)
+            .ok_or(Status::not_found("identity provider not found"))
+    }
+
+    /// returns the user linked to the external identity, creating it on the first sign-in
+    async fn find_or_create_sso_user(
+        &self,
+        provider: &str,
+        identity: ExternalIdentity,
+    ) -> Result<DbUser, Status> {
+        if let Some(db_user) = self.find_sso_user(provider, &identity.subject).await? {
+            return Ok(db_user);
+        }
+
+        let db = self.mongo.database("cloud");
+        let db_users = db.collection::<DbUser>("users");
+        let db_identities = db.collection::<DbIdentity>("identities");
+
+        let email = match identity.email {
+            Some(email) if identity.email_verified => email.to_lowercase(),
+            _ => format!("{}@{}", identity.subject, provider),
+        };
+
+        // an external identity must never take over an existing password account
+        let existing_user = db_users
+            .find_one(doc! { "email": email.to_owned() }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        if existing_user.is_some() {
+            // unless a concurrent first sign-in with the same identity just created it
+            return self
+                .find_sso_user(provider, &identity.subject)
+                .await?
+                .ok_or(Status::already_exists("email is already registered"));
+        }
+
+        let db_user = DbUser {
+            id: ObjectId::new(),
+            email: email.to_owned(),
+            username: identity.username.unwrap_or(email),
+            passhash: None,
+            storage_quota: Some(self.config.user_storage_quota),
+            storage_used: 0,
+            admin: false,
+        };
+        let db_identity = DbIdentity {
+            id: ObjectId::new(),
+            provider: provider.to_owned(),
+            subject: identity.subject.to_owned(),
+            user_id: db_user.id,
+        };
+
+        let mut session = self
+            .mongo
+            .start_session(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        // a user is never left without its identity
+        let insert_res: Result<(), mongodb::error::Error> = async {
+            db_users
+                .insert_one_with_session(&db_user, None, &mut session)
+                .await?;
+            db_identities
+                .insert_one_with_session(&db_identity, None, &mut session)
+                .await?;
+
+            Ok(())
+        }
+        .await;
+
+        match insert_res {
+            Ok(()) => {
+                session
+                    .commit_transaction()
+                    .await
+                    .map_err(|e| Status::aborted(e.to_string()))?;
+            }
+            Err(e) => {
+                session.abort_transaction().await.ok();
+
+                if !is_duplicate_key(&e) && !e.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+                    return Err(Status::internal(e.to_string()));
+                }
+
+                // a concurrent first sign-in with the same identity created the user
+                return self
+                    .find_sso_user(provider, &identity.subject)
+                    .await?
+                    .ok_or(Status::aborted("concurrent sign-in, try again"));
+            }
+        }
+
+        tracing::info!("created user {} for {} sign-in", db_user.id, provider);
+        Ok(db_user)
+    }
+
+    /// returns the user linked to the external identity, if it has signed in before
+    async fn find_sso_user(&self, provider: &str, subject: &str) -> Result<Option<DbUser>, Status> {
+        let db = self.mongo.database("cloud");
+        let db_users = db.collection::<DbUser>("users");
+        let db_identities = db.collection::<DbIdentity>("identities");
+
+        let db_identity = db_identities
+            .find_one(doc! { "provider": provider, "subject": subject }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let Some(db_identity) = db_identity else {
+            return Ok(None);
+        };
+
+        db_users
+            .find_one(doc! { "_id": db_identity.user_id }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::failed_precondition("could not find user"))
+            .map(Some)
     }
 }
 
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(WriteError { code: 11000, .. }))
+    )
+}
+
 #[tonic::async_trait]
 impl AuthService for MyAuthService {
     async fn register(
@@ -45,9 +200,10 @@ impl AuthService for MyAuthService {
             id: ObjectId::new(),
             email: request.get_ref().email.to_lowercase(),
             username: request.get_ref().username.to_owned(),
-            passhash,
+            passhash: Some(passhash),
             storage_quota: Some(self.config.user_storage_quota),
             storage_used: 0,
+            admin: false,
         };
 
         let db = self.mongo.database("cloud");
@@ -57,7 +213,7 @@ impl AuthService for MyAuthService {
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let token = auth_token::create_access_token(db_user.id);
+        let token = auth_token::create_access_token(&db_user);
 
         Ok(Response::new(AuthRegisterResponse {
             access_token: token.unwrap(),
@@ -80,8 +236,13 @@ impl AuthService for MyAuthService {
             .map_err(|e| Status::internal(e.to_string()))?
             .ok_or(Status::unauthenticated("invalid credentials"))?;
 
+        let passhash = db_user
+            .passhash
+            .as_ref()
+            .ok_or(Status::unauthenticated("invalid credentials"))?;
+
         let parsed_hash =
-            PasswordHash::new(&db_user.passhash).map_err(|e| Status::internal(e.to_string()))?;
+            PasswordHash::new(passhash).map_err(|e| Status::internal(e.to_string()))?;
 
         let verified =
             Argon2::default().verify_password(request.get_ref().password.as_bytes(), &parsed_hash);
@@ -90,7 +251,107 @@ impl AuthService for MyAuthService {
             return Err(Status::unauthenticated("invalid credentials"));
         }
 
-        let token = auth_token::create_access_token(db_user.id);
+        let token = auth_token::create_access_token(&db_user);
+
+        Ok(Response::new(AuthLoginResponse {
+            access_token: token.unwrap(),
+            user_id: db_user.id.to_string(),
+        }))
+    }
+
+    async fn get_identity_providers(
+        &self,
+        _request: Request<()>,
+    ) -> Result<Response<GetIdentityProvidersResponse>, Status> {
+        let providers = self
+            .identity_providers
+            .iter()
+            .map(|p| proto::IdentityProvider {
+                id: p.id().to_owned(),
+                display_name: p.display_name().to_owned(),
+            })
+            .collect();
+
+        Ok(Response::new(GetIdentityProvidersResponse { providers }))
+    }
+
+    async fn begin_sso(
+        &self,
+        request: Request<BeginSsoRequest>,
+    ) -> Result<Response<BeginSsoResponse>, Status> {
+        let provider = self.find_identity_provider(&request.get_ref().provider)?;
+
+        let auth_req = provider
+            .authorize(&request.get_ref().redirect_uri)
+            .await
+            .map_err(|e| {
+                tracing::error!("failed to start sign-in with {}: {:?}", provider.id(), e);
+                Status::unavailable("identity provider is unavailable")
+            })?;
+
+        let db = self.mongo.database("cloud");
+        let db_sso_requests = db.collection::<DbSsoRequest>("sso_requests");
+        db_sso_requests
+            .insert_one(
+                DbSsoRequest {
+                    state: auth_req.state.to_owned(),
+                    provider: provider.id().to_owned(),
+                    redirect_uri: request.get_ref().redirect_uri.to_owned(),
+                    pkce_verifier: auth_req.pkce_verifier,
+                    nonce: auth_req.nonce,
+                    created_at: bson::DateTime::now(),
+                },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(BeginSsoResponse {
+            authorization_url: auth_req.url,
+            state: auth_req.state,
+        }))
+    }
+
+    async fn complete_sso(
+        &self,
+        request: Request<CompleteSsoRequest>,
+    ) -> Result<Response<AuthLoginResponse>, Status> {
+        let db = self.mongo.database("cloud");
+        let db_sso_requests = db.collection::<DbSsoRequest>("sso_requests");
+
+        // every state can only be redeemed once
+        let db_sso_request = db_sso_requests
+            .find_one_and_delete(doc! { "_id": request.get_ref().state.to_owned() }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::unauthenticated("unknown sign-in state"))?;
+
+        // expired requests are removed by their index, but not right away
+        let age =
+            bson::DateTime::now().timestamp_millis() - db_sso_request.created_at.timestamp_millis();
+        if age > SSO_REQUEST_LIFETIME.as_millis() as i64 {
+            return Err(Status::unauthenticated("sign-in has expired"));
+        }
+
+        let provider = self.find_identity_provider(&db_sso_request.provider)?;
+
+        let identity = provider
+            .exchange_code(
+                &request.get_ref().code,
+                &db_sso_request.redirect_uri,
+                &db_sso_request.pkce_verifier,
+                &db_sso_request.nonce,
+            )
+            .await
+            .map_err(|e| {
+                tracing::warn!("failed to complete sign-in with {}: {:?}", provider.id(), e);
+                Status::unauthenticated("invalid credentials")
+            })?;
+
+        let db_user = self
+            .find_or_create_sso_user(provider.id(), identity)
+            .await?;
+        let token = auth_token::create_access_token(&db_user);
 
         Ok(Response::new(AuthLoginResponse {
             access_token: token.unwrap(),
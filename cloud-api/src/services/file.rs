@@ -1,167 +1,364 @@
-use std::{path::Path, pin::Pin};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use chrono::Utc;
-use cloud_proto::proto::{
-    self, file_service_server::FileService, upload_file_request::Upload, DeleteFileRequest,
-    DownloadFileRequest, DownloadFileResponse, FindFileRequest, GetFileRequest, UploadFileRequest,
+use cloud_proto::{
+    prost::Message,
+    proto::{
+        self, file_service_server::FileService, upload_file_request::Upload,
+        upload_part_request::Part, BatchResponse, BatchResult, CommitChunksRequest,
+        CreateMultipartUploadRequest, DeleteFileRequest, DeleteManyRequest, DownloadChunksRequest,
+        DownloadFileRequest, DownloadFileResponse, DownloadRangeRequest, FileChunks,
+        FileTagsRequest, FindFileRequest, FindManyRequest, FindMissingChunksRequest,
+        FindMissingChunksResponse, GetDuplicatesRequest, GetDuplicatesResponse, GetFileRequest,
+        GetManyRequest, GetThumbnailRequest, ListActivityRequest, ListActivityResponse,
+        ListFilesRequest, ListFilesResponse, LockFileRequest, MoveFileRequest, MoveManyRequest,
+        MultipartUploadRequest, RemoveMetadataRequest, SearchContentRequest, SearchContentResponse,
+        SearchFilesRequest, SearchFilesResponse, SetFavoriteRequest, SetMetadataRequest,
+        UnlockFileRequest, UploadChunkRequest, UploadFileRequest, UploadInfo, UploadPartRequest,
+    },
 };
-use futures_util::{AsyncWriteExt, StreamExt};
+use futures_util::{StreamExt, TryStreamExt};
 use mongodb::{
-    bson::{doc, oid::ObjectId},
-    options::{GridFsUploadOptions, UpdateOptions},
+    bson::{self, doc, oid::ObjectId, Document},
+    options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument},
+    ClientSession, Database, GridFsBucket,
 };
-use tokio_util::{compat::FuturesAsyncReadCompatExt, io::ReaderStream};
-use tonic::{codegen::futures_core::Stream, Request, Response, Status, Streaming};
+use tonic::{codegen::futures_core::Stream, Code, Request, Response, Status, Streaming};
 
 use crate::{
-    auth_token,
-    models::{DbFile, DbUser},
+    activity::{self, ActivityQuery},
+    auth_token::{self, AuthenticatedUser},
+    blob, compression,
+    config::{Configuration, QuotaCharge},
+    content_index::ContentIndex,
+    crypto::MasterKeys,
+    duplicates,
+    listing::ListQuery,
+    media::MediaExtractor,
+    models::{self, to_date_time, DbActivity, DbActivityKind, DbChunk, DbFile, DbFileLock, DbUser},
+    multipart::MultipartUploads,
+    staging::{self, StagedUpload, StoredBlob},
+    thumbnails::{self, Thumbnails},
 };
 
+/// largest chunk accepted by `UploadChunks`, content-defined chunks are much smaller
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+/// most items accepted by the batch rpcs
+const MAX_BATCH_SIZE: usize = 1000;
+/// files sent in one response of a `Search` stream
+const SEARCH_BATCH_SIZE: usize = 100;
+
+/// seconds a lock is held if the request does not specify a duration
+const DEFAULT_LOCK_DURATION: u64 = 30 * 60;
+const MAX_LOCK_DURATION: u64 = 24 * 60 * 60;
+
+const MAX_TAGS: usize = 64;
+const MAX_METADATA_ENTRIES: usize = 64;
+/// characters of a tag or metadata key
+const MAX_KEY_LENGTH: usize = 100;
+const MAX_METADATA_VALUE_LENGTH: usize = 4096;
+
+/// The new content of a file committed by [`MyFileService::commit_upload`].
+enum FileContent<'a> {
+    Blob(&'a StoredBlob),
+    /// hashes of chunks that have been uploaded before
+    Chunks(&'a [String]),
+}
+
 #[derive(Debug)]
 pub struct MyFileService {
+    config: Configuration,
     mongo: mongodb::Client,
+    master_keys: Option<Arc<MasterKeys>>,
+    multipart_uploads: MultipartUploads,
+    content_index: Option<Arc<ContentIndex>>,
+    thumbnails: Arc<Thumbnails>,
+    media_extractor: Arc<MediaExtractor>,
 }
 
 impl MyFileService {
-    pub fn new(mongo: mongodb::Client) -> Self {
-        Self { mongo }
+    pub fn new(
+        config: Configuration,
+        mongo: mongodb::Client,
+        master_keys: Option<Arc<MasterKeys>>,
+        content_index: Option<Arc<ContentIndex>>,
+        thumbnails: Arc<Thumbnails>,
+        media_extractor: Arc<MediaExtractor>,
+    ) -> Self {
+        Self {
+            multipart_uploads: MultipartUploads::new(config.staging_dir.clone()),
+            config,
+            mongo,
+            master_keys,
+            content_index,
+            thumbnails,
+            media_extractor,
+        }
     }
-}
 
-#[tonic::async_trait]
-impl FileService for MyFileService {
-    type DownloadStream = Pin<Box<dyn Stream<Item = Result<DownloadFileResponse, Status>> + Send>>;
-    type GetAllStream = Pin<Box<dyn Stream<Item = Result<proto::File, Status>> + Send>>;
+    /// queues a committed or deleted file to be indexed, to have its thumbnails generated
+    /// and its media metadata extracted again
+    fn update_derived(&self, db_file: &DbFile) {
+        if let Some(content_index) = &self.content_index {
+            content_index.update(db_file.owner_id, db_file.id);
+        }
 
-    async fn upload(
+        self.thumbnails.update(db_file.owner_id, db_file.id);
+        self.media_extractor.update(db_file.owner_id, db_file.id);
+    }
+
+    /// points the file at `info.path` to its new content and charges the size difference to
+    /// the owner's storage, both in a single transaction
+    ///
+    /// returns the committed file and the blobs it replaced, which the caller has to release
+    async fn commit_upload(
         &self,
-        request: Request<Streaming<UploadFileRequest>>,
-    ) -> Result<Response<proto::File>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        caller: &AuthenticatedUser,
+        info: &UploadInfo,
+        content: FileContent<'_>,
+    ) -> Result<(DbFile, Vec<ObjectId>), Status> {
+        let user_id = caller.id;
+        let path = info.path.as_str();
+        let size = info.size;
+        let mtime = info.mtime.as_ref().map(to_date_time);
+        // only the permission bits, the file type is always a regular file
+        let mode = info.mode.map(|m| m & 0o7777);
 
         let db = self.mongo.database("cloud");
         let db_users = db.collection::<DbUser>("users");
         let db_files = db.collection::<DbFile>("files");
 
-        let db_user = db_users
-            .find_one(doc! { "_id": user_id }, None)
+        let mut session = self
+            .mongo
+            .start_session(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        session
+            .start_transaction(None)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let db_user = match db_user {
-            Some(u) => u,
-            None => return Err(Status::failed_precondition("could not find user")),
-        };
-
-        let bucket = db.gridfs_bucket(None);
+        let commit_res: Result<(DbFile, Vec<ObjectId>), Status> = async {
+            let db_user = db_users
+                .find_one_with_session(doc! { "_id": user_id }, None, &mut session)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or(Status::failed_precondition("could not find user"))?;
+
+            let db_file = db_files
+                .find_one_with_session(
+                    doc! { "owner_id": user_id, "path": path },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            check_precondition(info.expected_hash.as_deref(), db_file.as_ref())?;
+            check_lock(&db, caller, path, Some(&mut session)).await?;
+
+            let (bucket_id, outboard_id, chunks, stored_size) = match content {
+                FileContent::Blob(blob) => (Some(blob.id), blob.outboard_id, None, blob.size),
+                FileContent::Chunks(hashes) => {
+                    let stored_size = use_chunks(&db, user_id, hashes, size, &mut session).await?;
+                    (None, None, Some(hashes.to_vec()), stored_size)
+                }
+            };
+
+            let charged_size = match self.config.quota_charge {
+                QuotaCharge::Logical => size,
+                QuotaCharge::Stored => stored_size,
+            };
+
+            // only the difference to the replaced file is charged
+            let delta = charged_size as i64
+                - db_file
+                    .as_ref()
+                    .map_or(0, |f| f.charged_size(self.config.quota_charge) as i64);
+
+            if let Some(storage_quota) = db_user.storage_quota {
+                if delta > 0 && db_user.storage_used as i64 + delta > storage_quota as i64 {
+                    return Err(Status::resource_exhausted("user storage quota exceeded"));
+                }
+            }
 
-        let mut client_stream = request.into_inner();
-        let mut client_file_info = None;
+            db_users
+                .update_one_with_session(
+                    doc! { "_id": user_id },
+                    doc! { "$inc": { "storage_used": delta } },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            match db_file {
+                Some(mut db_file) => {
+                    let replaced_blob_ids = db_file.blob_ids();
+                    let replaced_hash = db_file.hash.to_owned();
+
+                    db_file.bucket_id = bucket_id;
+                    db_file.chunks = chunks;
+                    db_file.outboard_id = outboard_id;
+                    db_file.hash = info.hash.to_owned();
+                    db_file.size = size;
+                    db_file.stored_size = Some(stored_size);
+                    db_file.modified_at = bson::DateTime::now();
+                    db_file.mtime = mtime;
+                    db_file.mode = mode;
+                    // extracted again from the new content
+                    db_file.media = None;
+
+                    db_files
+                        .replace_one_with_session(
+                            doc! { "_id": db_file.id },
+                            &db_file,
+                            None,
+                            &mut session,
+                        )
+                        .await
+                        .map_err(|e| Status::internal(e.to_string()))?;
 
-        let mut bucket_stream = None;
+                    let activity = DbActivity {
+                        old_hash: Some(replaced_hash),
+                        new_hash: Some(db_file.hash.to_owned()),
+                        ..DbActivity::new(DbActivityKind::Replace, &db_file, caller.session)
+                    };
+                    activity::record(&db, &activity, &mut session).await?;
 
-        let mut hasher = blake3::Hasher::new();
-        let mut size = 0;
+                    Ok((db_file, replaced_blob_ids))
+                }
+                None => {
+                    let db_file = DbFile {
+                        id: ObjectId::new(),
+                        owner_id: user_id,
+                        bucket_id,
+                        chunks,
+                        outboard_id,
+                        path: path.to_owned(),
+                        hash: info.hash.to_owned(),
+                        size,
+                        stored_size: Some(stored_size),
+                        created_at: bson::DateTime::now(),
+                        modified_at: bson::DateTime::now(),
+                        mtime,
+                        mode,
+                        mime_type: models::mime_type(path),
+                        tags: Vec::new(),
+                        favorite: false,
+                        metadata: HashMap::new(),
+                        media: None,
+                    };
 
-        let mut db_file = None;
+                    // the unique path index rejects a file inserted by a concurrent upload
+                    db_files
+                        .insert_one_with_session(&db_file, None, &mut session)
+                        .await
+                        .map_err(|e| Status::aborted(e.to_string()))?;
 
-        while let Some(msg) = client_stream.message().await? {
-            let upload = msg.upload.unwrap();
+                    let activity = DbActivity {
+                        new_hash: Some(db_file.hash.to_owned()),
+                        ..DbActivity::new(DbActivityKind::Upload, &db_file, caller.session)
+                    };
+                    activity::record(&db, &activity, &mut session).await?;
 
-            match upload {
-                Upload::Info(info) => {
-                    if client_file_info.is_some() {
-                        return Err(Status::invalid_argument("file meta already sent"));
-                    }
+                    Ok((db_file, Vec::new()))
+                }
+            }
+        }
+        .await;
 
-                    if let Some(storage_quota) = db_user.storage_quota {
-                        if db_user.storage_used + info.size > storage_quota {
-                            return Err(Status::resource_exhausted("user storage quota exceeded"));
-                        }
-                    }
+        match commit_res {
+            Ok(c) => {
+                // a concurrent upload or delete of the same file makes the commit fail
+                session
+                    .commit_transaction()
+                    .await
+                    .map_err(|e| Status::aborted(e.to_string()))?;
 
-                    let path = Path::new(&info.path);
+                self.update_derived(&c.0);
+                Ok(c)
+            }
+            Err(e) => {
+                session.abort_transaction().await.ok();
+                Err(e)
+            }
+        }
+    }
 
-                    if !path.is_absolute() {
-                        return Err(Status::invalid_argument("path is not absolute"));
-                    }
+    /// checks an upload before its bytes are received, the precondition and quota are
+    /// checked again when the upload is committed
+    async fn check_upload(
+        &self,
+        caller: &AuthenticatedUser,
+        info: &UploadInfo,
+    ) -> Result<(), Status> {
+        let user_id = caller.id;
+        let db = self.mongo.database("cloud");
+        let db_users = db.collection::<DbUser>("users");
+        let db_files = db.collection::<DbFile>("files");
 
-                    match path.file_name() {
-                        Some(file_name) => {
-                            if file_name == ".sync.db" {
-                                return Err(Status::invalid_argument(
-                                    "file name can not be .sync.db",
-                                ));
-                            }
-
-                            if file_name.to_string_lossy().starts_with(".~download~") {
-                                return Err(Status::invalid_argument(
-                                    "file name can not start with .~download~",
-                                ));
-                            }
-                        }
-                        None => {
-                            return Err(Status::invalid_argument("no file name specified"));
-                        }
-                    }
+        validate_path(&info.path)?;
 
-                    db_file = db_files
-                        .find_one(
-                            Some(doc! {
-                                "owner_id": user_id.to_owned(),
-                                "path": info.path.to_owned(),
-                            }),
-                            None,
-                        )
-                        .await
-                        .map_err(|e| Status::internal(e.to_string()))?;
+        let db_user = db_users
+            .find_one(doc! { "_id": user_id }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::failed_precondition("could not find user"))?;
 
-                    bucket_stream = Some(bucket.open_upload_stream(
-                        &info.path,
-                        Some(GridFsUploadOptions::builder().metadata(None).build()),
-                    ));
-                    client_file_info = Some(info);
-                }
-                Upload::Chunk(bytes) => {
-                    let info = match &client_file_info {
-                        Some(i) => i,
-                        None => {
-                            return Err(Status::invalid_argument(
-                                "file metadata must be sent before the byte stream",
-                            ))
-                        }
-                    };
+        let db_file = db_files
+            .find_one(
+                Some(doc! {
+                    "owner_id": user_id,
+                    "path": info.path.to_owned(),
+                }),
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
 
-                    size += bytes.len();
+        check_precondition(info.expected_hash.as_deref(), db_file.as_ref())?;
+        check_lock(&db, caller, &info.path, None).await?;
 
-                    if size as u64 > info.size {
-                        return Err(Status::aborted(
-                            "uploaded file size exceeds the announced file size",
-                        ));
-                    }
+        // the stored size is only known once the blob has been written
+        let storage_quota = match self.config.quota_charge {
+            QuotaCharge::Logical => db_user.storage_quota,
+            QuotaCharge::Stored => None,
+        };
 
-                    hasher.update(&bytes);
+        if let Some(storage_quota) = storage_quota {
+            let replaced_size = db_file.as_ref().map_or(0, |f| f.size);
+            // multipart uploads in progress already hold their size in the staging directory
+            let open_size = self.multipart_uploads.open_size(user_id);
 
-                    bucket_stream
-                        .as_mut()
-                        .unwrap()
-                        .write_all(&bytes)
-                        .await
-                        .map_err(|_e| Status::internal("bucket stream"))?;
-                }
+            if db_user.storage_used.saturating_sub(replaced_size) + open_size + info.size
+                > storage_quota
+            {
+                return Err(Status::resource_exhausted("user storage quota exceeded"));
             }
         }
 
-        if bucket_stream.is_none() {
-            return Err(Status::invalid_argument("no data received"));
-        }
+        Ok(())
+    }
 
-        let hash = hasher.finalize().to_string();
+    /// verifies a staged upload against the announced hash and size, stores it and commits
+    /// it as the new content of the file
+    async fn store_upload(
+        &self,
+        caller: &AuthenticatedUser,
+        staged: StagedUpload,
+        client_file_info: &UploadInfo,
+    ) -> Result<DbFile, Status> {
+        let bucket = self.mongo.database("cloud").gridfs_bucket(None);
 
-        let client_file_info = client_file_info.unwrap();
-        let mut bucket_stream = bucket_stream.unwrap();
-        bucket_stream.close().await?;
+        // verify the staged upload before anything is written to the bucket
+        let hash = staged.hash();
+        let size = staged.size();
 
         if hash != client_file_info.hash {
             return Err(Status::data_loss(format!(
@@ -170,81 +367,393 @@ impl FileService for MyFileService {
             )));
         }
 
-        if size != client_file_info.size as usize {
+        if size != client_file_info.size {
             return Err(Status::data_loss(format!(
                 "size(server: {}, client: {}) do not match",
                 size, client_file_info.size
             )));
         }
 
-        let bucket_id = bucket_stream.id().as_object_id().unwrap();
+        let compression_level = self
+            .config
+            .compression_level
+            .filter(|_| compression::is_compressible(&client_file_info.path));
+
+        let stored_blob = staged
+            .store(
+                &bucket,
+                &client_file_info.path,
+                self.master_keys.as_deref(),
+                compression_level,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let commit_res = self
+            .commit_upload(caller, client_file_info, FileContent::Blob(&stored_blob))
+            .await;
 
-        match db_file.as_mut() {
-            Some(mut db_file) => {
-                bucket
-                    .delete(db_file.bucket_id.into())
-                    .await
-                    .map_err(|e| Status::internal(e.to_string()))?;
+        let (db_file, replaced_blob_ids) = match commit_res {
+            Ok(c) => c,
+            Err(e) => {
+                let uncommitted = std::iter::once(stored_blob.id).chain(stored_blob.outboard_id);
+                delete_blobs(&bucket, uncommitted, "uncommitted").await;
+
+                return Err(e);
+            }
+        };
+
+        delete_blobs(&bucket, replaced_blob_ids, "replaced").await;
+
+        Ok(db_file)
+    }
+
+    /// deletes a file and releases its blobs, shared by `Delete` and `DeleteMany`
+    async fn delete_file(
+        &self,
+        caller: &AuthenticatedUser,
+        request: &DeleteFileRequest,
+    ) -> Result<(), Status> {
+        let user_id = caller.id;
+
+        let file_id =
+            ObjectId::parse_str(&request.id).map_err(|_| Status::invalid_argument("invalid id"))?;
+        let expected_hash = request.expected_hash.as_deref();
+
+        let db = self.mongo.database("cloud");
+        let db_users = db.collection::<DbUser>("users");
+        let db_files = db.collection::<DbFile>("files");
 
-                db_file.bucket_id = bucket_id;
-                db_file.hash = hash;
-                db_file.size = size as u64;
-                db_file.modified_at = Utc::now();
-
-                db_files
-                    .replace_one(
-                        doc! {
-                            "_id": db_file.id,
-                        },
-                        db_file,
-                        None,
-                    )
+        let mut session = self
+            .mongo
+            .start_session(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let delete_res: Result<DbFile, Status> = async {
+            let db_file = db_files
+                .find_one_with_session(
+                    doc! { "_id": file_id, "owner_id": user_id },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or(Status::not_found("file not found"))?;
+
+            check_precondition(expected_hash, Some(&db_file))?;
+            check_lock(&db, caller, &db_file.path, Some(&mut session)).await?;
+
+            db_files
+                .delete_one_with_session(doc! { "_id": db_file.id }, None, &mut session)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            let charged_size = db_file.charged_size(self.config.quota_charge) as i64;
+
+            db_users
+                .update_one_with_session(
+                    doc! { "_id": user_id },
+                    doc! { "$inc": { "storage_used": -charged_size } },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            let activity = DbActivity {
+                old_hash: Some(db_file.hash.to_owned()),
+                ..DbActivity::new(DbActivityKind::Delete, &db_file, caller.session)
+            };
+            activity::record(&db, &activity, &mut session).await?;
+
+            Ok(db_file)
+        }
+        .await;
+
+        let db_file = match delete_res {
+            Ok(db_file) => {
+                session
+                    .commit_transaction()
                     .await
-                    .map_err(|e| Status::internal(e.to_string()))?;
+                    .map_err(|e| Status::aborted(e.to_string()))?;
+                db_file
+            }
+            Err(e) => {
+                session.abort_transaction().await.ok();
+                return Err(e);
+            }
+        };
+
+        self.update_derived(&db_file);
+
+        // chunks are shared between files and left to the garbage collection
+        delete_blobs(&db.gridfs_bucket(None), db_file.blob_ids(), "deleted").await;
+
+        Ok(())
+    }
+
+    /// moves a file to a new path, shared by `Move` and `MoveMany`
+    async fn move_file(
+        &self,
+        caller: &AuthenticatedUser,
+        request: &MoveFileRequest,
+    ) -> Result<DbFile, Status> {
+        let user_id = caller.id;
+
+        let file_id =
+            ObjectId::parse_str(&request.id).map_err(|_| Status::invalid_argument("invalid id"))?;
+        let path = request.path.as_str();
+        let expected_hash = request.expected_hash.as_deref();
+
+        validate_path(path)?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let mut session = self
+            .mongo
+            .start_session(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        session
+            .start_transaction(None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let move_res: Result<DbFile, Status> = async {
+            let mut db_file = db_files
+                .find_one_with_session(
+                    doc! { "_id": file_id, "owner_id": user_id },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or(Status::not_found("file not found"))?;
+
+            check_precondition(expected_hash, Some(&db_file))?;
+
+            if db_file.path == path {
+                return Ok(db_file);
+            }
+
+            check_lock(&db, caller, &db_file.path, Some(&mut session)).await?;
+            check_lock(&db, caller, path, Some(&mut session)).await?;
+
+            let target_file = db_files
+                .find_one_with_session(
+                    doc! { "owner_id": user_id, "path": path },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            if target_file.is_some() {
+                return Err(Status::already_exists("a file already exists at the path"));
             }
-            None => {
-                let new_db_file = DbFile {
-                    id: ObjectId::new(),
-                    owner_id: user_id,
-                    bucket_id,
-                    path: client_file_info.path.to_owned(),
-                    hash: client_file_info.hash.to_owned(),
-                    size: client_file_info.size,
-                    modified_at: Utc::now(),
-                };
-
-                db_files
-                    .insert_one(&new_db_file, None)
+
+            let old_path = std::mem::replace(&mut db_file.path, path.to_owned());
+            db_file.mime_type = models::mime_type(path);
+
+            db_files
+                .update_one_with_session(
+                    doc! { "_id": db_file.id },
+                    doc! { "$set": { "path": &db_file.path, "mime_type": &db_file.mime_type } },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::aborted(e.to_string()))?;
+
+            // the caller's lock moves with the file, so the file stays locked at its new path.
+            // a lock left at the target path has expired or is the caller's own
+            let db_locks = db.collection::<DbFileLock>("file_locks");
+            db_locks
+                .delete_one_with_session(
+                    doc! { "owner_id": user_id, "path": path },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::aborted(e.to_string()))?;
+            db_locks
+                .update_one_with_session(
+                    doc! { "owner_id": user_id, "path": &old_path },
+                    doc! { "$set": { "path": path } },
+                    None,
+                    &mut session,
+                )
+                .await
+                .map_err(|e| Status::aborted(e.to_string()))?;
+
+            // the content is unchanged
+            let activity = DbActivity {
+                old_path: Some(old_path),
+                old_hash: Some(db_file.hash.to_owned()),
+                new_hash: Some(db_file.hash.to_owned()),
+                ..DbActivity::new(DbActivityKind::Move, &db_file, caller.session)
+            };
+            activity::record(&db, &activity, &mut session).await?;
+
+            Ok(db_file)
+        }
+        .await;
+
+        match move_res {
+            Ok(db_file) => {
+                session
+                    .commit_transaction()
                     .await
-                    .map_err(|e| Status::internal(e.to_string()))?;
+                    .map_err(|e| Status::aborted(e.to_string()))?;
 
-                db_file = Some(new_db_file);
+                Ok(db_file)
+            }
+            Err(e) => {
+                session.abort_transaction().await.ok();
+                Err(e)
             }
         }
+    }
 
-        db_users
-            .update_one(
-                doc! { "_id": user_id },
-                doc! { "$inc": { "storage_used": client_file_info.size as i64 } },
-                UpdateOptions::builder().upsert(Some(true)).build(),
-            )
+    /// updates the tags, favorite flag or metadata of a file, the update is only applied if
+    /// the `$expr` of `limit` holds and fails with its message otherwise
+    async fn update_file_metadata(
+        &self,
+        user_id: ObjectId,
+        id: &str,
+        update: Document,
+        limit: Option<(Document, &str)>,
+    ) -> Result<DbFile, Status> {
+        let file_id =
+            ObjectId::parse_str(id).map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let mut filter = doc! { "_id": file_id, "owner_id": user_id };
+        if let Some((expr, _)) = &limit {
+            filter.insert("$expr", expr.clone());
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let db_file = db_files
+            .find_one_and_update(filter, update, options)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
+        if let Some(db_file) = db_file {
+            return Ok(db_file);
+        }
+
+        // nothing matched, either there is no such file or the limit would be exceeded
+        let exists = db_files
+            .count_documents(doc! { "_id": file_id, "owner_id": user_id }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            > 0;
+
+        match limit {
+            Some((_, message)) if exists => Err(Status::failed_precondition(message)),
+            _ => Err(Status::not_found("file not found")),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl FileService for MyFileService {
+    type DownloadStream = Pin<Box<dyn Stream<Item = Result<DownloadFileResponse, Status>> + Send>>;
+    type GetAllStream = Pin<Box<dyn Stream<Item = Result<proto::File, Status>> + Send>>;
+    type GetLocksStream = Pin<Box<dyn Stream<Item = Result<proto::FileLock, Status>> + Send>>;
+    type DownloadChunksStream = Pin<Box<dyn Stream<Item = Result<proto::Chunk, Status>> + Send>>;
+    type DownloadRangeStream = Self::DownloadStream;
+    type GetOutboardStream = Self::DownloadStream;
+    type SearchStream = Pin<Box<dyn Stream<Item = Result<SearchFilesResponse, Status>> + Send>>;
+
+    async fn upload(
+        &self,
+        request: Request<Streaming<UploadFileRequest>>,
+    ) -> Result<Response<proto::File>, Status> {
+        let caller = auth_token::authenticated_user(&request)?.clone();
+
+        let mut client_stream = request.into_inner();
+        let mut client_file_info = None;
+
+        let mut staged = None;
+
+        while let Some(msg) = client_stream.message().await? {
+            let upload = msg.upload.unwrap();
+
+            match upload {
+                Upload::Info(info) => {
+                    if client_file_info.is_some() {
+                        return Err(Status::invalid_argument("file meta already sent"));
+                    }
+
+                    self.check_upload(&caller, &info).await?;
+
+                    staged = Some(
+                        StagedUpload::create(&self.config.staging_dir)
+                            .await
+                            .map_err(|e| Status::internal(e.to_string()))?,
+                    );
+                    client_file_info = Some(info);
+                }
+                Upload::Chunk(bytes) => {
+                    let (info, staged) = match (&client_file_info, &mut staged) {
+                        (Some(i), Some(s)) => (i, s),
+                        _ => {
+                            return Err(Status::invalid_argument(
+                                "file metadata must be sent before the byte stream",
+                            ))
+                        }
+                    };
+
+                    if staged.size() + bytes.len() as u64 > info.size {
+                        return Err(Status::aborted(
+                            "uploaded file size exceeds the announced file size",
+                        ));
+                    }
+
+                    staged
+                        .write(&bytes)
+                        .await
+                        .map_err(|e| Status::internal(e.to_string()))?;
+                }
+            }
+        }
+
+        let (client_file_info, staged) = match (client_file_info, staged) {
+            (Some(i), Some(s)) => (i, s),
+            _ => return Err(Status::invalid_argument("no data received")),
+        };
+
+        let db_file = self
+            .store_upload(&caller, staged, &client_file_info)
+            .await?;
+
         tracing::debug!(
             "uploaded file {} with hash {}",
             client_file_info.path,
             client_file_info.hash
         );
 
-        Ok(Response::new(db_file.unwrap().to_proto()))
+        Ok(Response::new(db_file.to_proto()))
     }
 
     async fn download(
         &self,
         request: Request<DownloadFileRequest>,
     ) -> Result<Response<Self::DownloadStream>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let user_id = auth_token::authenticated_user(&request)?.id;
 
         let file_id = ObjectId::parse_str(request.get_ref().id.to_owned())
             .map_err(|_| Status::invalid_argument("invalid id"))?;
@@ -258,15 +767,12 @@ impl FileService for MyFileService {
             .map_err(|e| Status::internal(e.to_string()))?
             .ok_or(Status::not_found("file not found"))?;
 
-        let bucket = db.gridfs_bucket(None);
-
-        let bucket_stream = bucket
-            .open_download_stream(db_file.bucket_id.into())
+        let blob_stream = blob::open_file(&db, self.master_keys.clone(), &db_file)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let stream = ReaderStream::new(bucket_stream.compat()).map(|f| match f {
-            Ok(f) => Ok(DownloadFileResponse { chunk: f.to_vec() }),
+        let stream = blob_stream.map(|f| match f {
+            Ok(f) => Ok(DownloadFileResponse { chunk: f }),
             Err(e) => Err(Status::internal(e.to_string())),
         });
 
@@ -274,7 +780,7 @@ impl FileService for MyFileService {
     }
 
     async fn get(&self, request: Request<GetFileRequest>) -> Result<Response<proto::File>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let user_id = auth_token::authenticated_user(&request)?.id;
 
         let file_id = ObjectId::parse_str(request.get_ref().id.to_owned())
             .map_err(|_| Status::invalid_argument("invalid id"))?;
@@ -295,7 +801,7 @@ impl FileService for MyFileService {
         &self,
         request: Request<FindFileRequest>,
     ) -> Result<Response<proto::File>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let user_id = auth_token::authenticated_user(&request)?.id;
 
         let db = self.mongo.database("cloud");
         let db_files = db.collection::<DbFile>("files");
@@ -315,7 +821,7 @@ impl FileService for MyFileService {
     }
 
     async fn get_all(&self, request: Request<()>) -> Result<Response<Self::GetAllStream>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let user_id = auth_token::authenticated_user(&request)?.id;
 
         let db = self.mongo.database("cloud");
         let db_files = db.collection::<DbFile>("files");
@@ -333,42 +839,1480 @@ impl FileService for MyFileService {
     }
 
     async fn delete(&self, request: Request<DeleteFileRequest>) -> Result<Response<()>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let caller = auth_token::authenticated_user(&request)?;
+        self.delete_file(caller, request.get_ref()).await?;
 
-        let file_id = ObjectId::parse_str(request.get_ref().id.to_owned())
-            .map_err(|_| Status::invalid_argument("invalid id"))?;
+        Ok(Response::new(()))
+    }
+
+    async fn r#move(
+        &self,
+        request: Request<MoveFileRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let db_file = self.move_file(caller, request.get_ref()).await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn lock(
+        &self,
+        request: Request<LockFileRequest>,
+    ) -> Result<Response<proto::FileLock>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let path = request.get_ref().path.as_str();
+
+        validate_path(path)?;
+
+        let duration = match request.get_ref().duration {
+            0 => DEFAULT_LOCK_DURATION,
+            d => d.min(MAX_LOCK_DURATION),
+        };
 
         let db = self.mongo.database("cloud");
         let db_users = db.collection::<DbUser>("users");
-        let db_files = db.collection::<DbFile>("files");
+        let db_locks = db.collection::<DbFileLock>("file_locks");
 
-        let db_file = db_files
-            .find_one(doc! { "_id": file_id, "owner_id": user_id }, None)
+        let db_user = db_users
+            .find_one(doc! { "_id": caller.id }, None)
             .await
             .map_err(|e| Status::internal(e.to_string()))?
-            .ok_or(Status::not_found("file not found"))?;
-
-        let bucket = db.gridfs_bucket(None);
-
-        bucket
-            .delete(db_file.bucket_id.into())
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+            .ok_or(Status::failed_precondition("could not find user"))?;
+
+        let now = SystemTime::now();
+        let expires_at = bson::DateTime::from_system_time(now + Duration::from_secs(duration));
+
+        // takes over an expired lock or renews the caller's own, a lock held by another
+        // session makes the upsert violate the unique path index
+        let lock_res = db_locks
+            .find_one_and_update(
+                doc! {
+                    "owner_id": caller.id,
+                    "path": path,
+                    "$or": [
+                        { "session": caller.session },
+                        { "expires_at": { "$lte": bson::DateTime::from_system_time(now) } },
+                    ],
+                },
+                doc! {
+                    "$set": {
+                        "holder_id": caller.id,
+                        "holder_name": &db_user.username,
+                        "session": caller.session,
+                        "expires_at": expires_at,
+                    },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .upsert(true)
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await;
+
+        match lock_res {
+            Ok(Some(db_lock)) => Ok(Response::new(db_lock.to_proto(caller.session))),
+            Ok(None) => Err(Status::internal("lock was not created")),
+            Err(e) => {
+                check_lock(&db, caller, path, None).await?;
+                Err(Status::internal(e.to_string()))
+            }
+        }
+    }
 
-        db_files
-            .delete_one(doc! { "_id": file_id, "owner_id": user_id }, None)
-            .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+    async fn unlock(&self, request: Request<UnlockFileRequest>) -> Result<Response<()>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
 
-        db_users
-            .update_one(
-                doc! { "_id": user_id },
-                doc! { "$inc": { "storage_used": -(db_file.size as i64) } },
-                UpdateOptions::builder().upsert(Some(true)).build(),
+        let db = self.mongo.database("cloud");
+        let db_locks = db.collection::<DbFileLock>("file_locks");
+
+        let delete_res = db_locks
+            .delete_one(
+                doc! {
+                    "owner_id": caller.id,
+                    "path": &request.get_ref().path,
+                    "session": caller.session,
+                },
+                None,
             )
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
+        if delete_res.deleted_count == 0 {
+            return Err(Status::not_found("lock not found"));
+        }
+
         Ok(Response::new(()))
     }
+
+    async fn get_locks(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<Self::GetLocksStream>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let session = caller.session;
+
+        let db = self.mongo.database("cloud");
+        let db_locks = db.collection::<DbFileLock>("file_locks");
+
+        let cursor = db_locks
+            .find(
+                doc! {
+                    "owner_id": caller.id,
+                    "expires_at": { "$gt": bson::DateTime::now() },
+                },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map(move |l| match l {
+                Ok(l) => Ok(l.to_proto(session)),
+                Err(e) => Err(Status::internal(e.to_string())),
+            });
+
+        Ok(Response::new(Box::pin(cursor)))
+    }
+
+    async fn find_missing_chunks(
+        &self,
+        request: Request<FindMissingChunksRequest>,
+    ) -> Result<Response<FindMissingChunksResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let hashes = request.into_inner().hashes;
+
+        let db = self.mongo.database("cloud");
+        let db_chunks = db.collection::<DbChunk>("chunks");
+
+        let mut cursor = db_chunks
+            .find(
+                doc! { "owner_id": user_id, "hash": { "$in": &hashes } },
+                FindOptions::builder()
+                    .projection(doc! { "hash": 1 })
+                    .build(),
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let mut stored = HashSet::new();
+
+        while let Some(db_chunk) = cursor.next().await {
+            let db_chunk = db_chunk.map_err(|e| Status::internal(e.to_string()))?;
+            stored.insert(db_chunk.hash);
+        }
+
+        // the client commits the present chunks without uploading them again, so they are
+        // kept from the garbage collection until then
+        let present = stored.iter().collect::<Vec<_>>();
+        db_chunks
+            .update_many(
+                doc! { "owner_id": user_id, "hash": { "$in": present } },
+                doc! { "$set": { "last_used_at": bson::DateTime::now() } },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut missing = hashes
+            .into_iter()
+            .filter(|h| !stored.contains(h))
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+
+        Ok(Response::new(FindMissingChunksResponse { hashes: missing }))
+    }
+
+    async fn upload_chunks(
+        &self,
+        request: Request<Streaming<UploadChunkRequest>>,
+    ) -> Result<Response<()>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let mut stream = request.into_inner();
+
+        let db = self.mongo.database("cloud");
+        let db_users = db.collection::<DbUser>("users");
+        let db_chunks = db.collection::<DbChunk>("chunks");
+        let bucket = db.gridfs_bucket(None);
+
+        let db_user = db_users
+            .find_one(doc! { "_id": user_id }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::failed_precondition("could not find user"))?;
+
+        // chunks are charged once a file is committed with them, until then the chunks no
+        // file uses count against the quota as well
+        let mut pending_size = match db_user.storage_quota {
+            Some(_) => pending_chunks_size(&db, user_id, self.config.quota_charge).await?,
+            None => 0,
+        };
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+
+            if chunk.data.len() > MAX_CHUNK_SIZE {
+                return Err(Status::invalid_argument(format!(
+                    "chunks can not be larger than {} bytes",
+                    MAX_CHUNK_SIZE
+                )));
+            }
+
+            let hash = blake3::hash(&chunk.data).to_string();
+
+            if hash != chunk.hash {
+                return Err(Status::data_loss(format!(
+                    "hash(server: {}, client: {}) do not match",
+                    hash, chunk.hash
+                )));
+            }
+
+            let exists = db_chunks
+                .count_documents(doc! { "owner_id": user_id, "hash": &hash }, None)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                > 0;
+
+            if exists {
+                continue;
+            }
+
+            // chunks are compressed regardless of the file type, zstd stores
+            // incompressible blocks as they are
+            let stored_blob = staging::store_bytes(
+                &bucket,
+                &hash,
+                &chunk.data,
+                self.master_keys.as_deref(),
+                self.config.compression_level,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+            let db_chunk = DbChunk {
+                id: ObjectId::new(),
+                owner_id: user_id,
+                hash,
+                size: chunk.data.len() as u64,
+                stored_size: stored_blob.size,
+                bucket_id: stored_blob.id,
+                last_used_at: bson::DateTime::now(),
+            };
+
+            if let Some(storage_quota) = db_user.storage_quota {
+                pending_size += db_chunk.charged_size(self.config.quota_charge);
+
+                if db_user.storage_used + pending_size > storage_quota {
+                    delete_blobs(&bucket, [stored_blob.id], "uncommitted").await;
+                    return Err(Status::resource_exhausted("user storage quota exceeded"));
+                }
+            }
+
+            // the unique index rejects a chunk uploaded concurrently, which is just as good
+            if db_chunks.insert_one(&db_chunk, None).await.is_err() {
+                if let Err(e) = bucket.delete(stored_blob.id.into()).await {
+                    tracing::error!(
+                        "failed to delete duplicate chunk {}: {:?}",
+                        stored_blob.id,
+                        e
+                    );
+                }
+            }
+        }
+
+        Ok(Response::new(()))
+    }
+
+    async fn commit_chunks(
+        &self,
+        request: Request<CommitChunksRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let caller = auth_token::authenticated_user(&request)?.clone();
+        let CommitChunksRequest {
+            path,
+            hash,
+            size,
+            expected_hash,
+            chunks,
+            mtime,
+            mode,
+        } = request.into_inner();
+
+        validate_path(&path)?;
+
+        let info = UploadInfo {
+            path,
+            hash,
+            size,
+            expected_hash,
+            mtime,
+            mode,
+        };
+
+        // the chunks have been verified when they were uploaded, so the file hash is taken
+        // from the client and verified by the next scrub. Reading the file back would make
+        // an edit of a large file as expensive as uploading it again, and a wrong hash only
+        // affects the owner's own file.
+        let (db_file, replaced_blob_ids) = self
+            .commit_upload(&caller, &info, FileContent::Chunks(&chunks))
+            .await?;
+
+        let bucket = self.mongo.database("cloud").gridfs_bucket(None);
+        delete_blobs(&bucket, replaced_blob_ids, "replaced").await;
+
+        tracing::debug!("committed file {} with {} chunks", info.path, chunks.len());
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn get_chunks(
+        &self,
+        request: Request<GetFileRequest>,
+    ) -> Result<Response<FileChunks>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+
+        let file_id = ObjectId::parse_str(&request.get_ref().id)
+            .map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+        let db_chunks = db.collection::<DbChunk>("chunks");
+
+        let db_file = db_files
+            .find_one(Some(doc! { "_id": file_id, "owner_id": user_id }), None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("file not found"))?;
+
+        let hashes = match db_file.chunks {
+            Some(hashes) => hashes,
+            None => return Ok(Response::new(FileChunks { chunks: Vec::new() })),
+        };
+
+        let mut cursor = db_chunks
+            .find(
+                doc! { "owner_id": user_id, "hash": { "$in": &hashes } },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let mut sizes = HashMap::new();
+
+        while let Some(db_chunk) = cursor.next().await {
+            let db_chunk = db_chunk.map_err(|e| Status::internal(e.to_string()))?;
+            sizes.insert(db_chunk.hash.to_owned(), db_chunk.to_proto());
+        }
+
+        let chunks = hashes
+            .iter()
+            .map(|h| {
+                sizes
+                    .get(h)
+                    .cloned()
+                    .ok_or_else(|| Status::internal(format!("chunk {} is missing", h)))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Response::new(FileChunks { chunks }))
+    }
+
+    async fn download_chunks(
+        &self,
+        request: Request<DownloadChunksRequest>,
+    ) -> Result<Response<Self::DownloadChunksStream>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let hashes = request.into_inner().hashes;
+
+        let db = self.mongo.database("cloud");
+        let db_chunks = db.collection::<DbChunk>("chunks");
+        let bucket = db.gridfs_bucket(None);
+        let master_keys = self.master_keys.clone();
+
+        let stream = async_stream::try_stream! {
+            for hash in hashes {
+                let db_chunk = db_chunks
+                    .find_one(doc! { "owner_id": user_id, "hash": &hash }, None)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?
+                    .ok_or_else(|| Status::not_found(format!("chunk {} not found", hash)))?;
+
+                let mut blob_stream = blob::open(&bucket, master_keys.as_deref(), db_chunk.bucket_id)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+                let mut data = Vec::with_capacity(db_chunk.size as usize);
+
+                while let Some(bytes) = blob_stream.next().await {
+                    data.extend(bytes.map_err(|e| Status::internal(e.to_string()))?);
+                }
+
+                yield proto::Chunk { hash, data };
+            }
+        };
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn download_range(
+        &self,
+        request: Request<DownloadRangeRequest>,
+    ) -> Result<Response<Self::DownloadRangeStream>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let range = request.get_ref();
+
+        let file_id =
+            ObjectId::parse_str(&range.id).map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let db_file = db_files
+            .find_one(Some(doc! { "_id": file_id, "owner_id": user_id }), None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("file not found"))?;
+
+        if range.offset > db_file.size {
+            return Err(Status::out_of_range(
+                "range starts after the end of the file",
+            ));
+        }
+
+        let blob_stream = blob::open_file(&db, self.master_keys.clone(), &db_file)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let stream = blob::range(blob_stream, range.offset, range.length).map(|f| match f {
+            Ok(f) => Ok(DownloadFileResponse { chunk: f }),
+            Err(e) => Err(Status::internal(e.to_string())),
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_outboard(
+        &self,
+        request: Request<GetFileRequest>,
+    ) -> Result<Response<Self::GetOutboardStream>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+
+        let file_id = ObjectId::parse_str(&request.get_ref().id)
+            .map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let db_file = db_files
+            .find_one(Some(doc! { "_id": file_id, "owner_id": user_id }), None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("file not found"))?;
+
+        let outboard_id = db_file
+            .outboard_id
+            .ok_or(Status::not_found("file has no outboard"))?;
+
+        let blob_stream = blob::open(
+            &db.gridfs_bucket(None),
+            self.master_keys.as_deref(),
+            outboard_id,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+        let stream = blob_stream.map(|f| match f {
+            Ok(f) => Ok(DownloadFileResponse { chunk: f }),
+            Err(e) => Err(Status::internal(e.to_string())),
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        request: Request<CreateMultipartUploadRequest>,
+    ) -> Result<Response<proto::MultipartUpload>, Status> {
+        let caller = auth_token::authenticated_user(&request)?.clone();
+        let request = request.into_inner();
+
+        let info = request
+            .info
+            .ok_or(Status::invalid_argument("upload info is missing"))?;
+
+        self.check_upload(&caller, &info).await?;
+
+        let upload = self
+            .multipart_uploads
+            .create(caller.id, info, request.part_size)
+            .await?;
+
+        Ok(Response::new(proto::MultipartUpload {
+            id: upload.id.to_string(),
+            part_size: upload.part_size,
+            part_count: upload.part_count(),
+        }))
+    }
+
+    async fn upload_part(
+        &self,
+        request: Request<Streaming<UploadPartRequest>>,
+    ) -> Result<Response<()>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+
+        let mut client_stream = request.into_inner();
+        let mut writer = None;
+
+        while let Some(msg) = client_stream.message().await? {
+            match msg.part {
+                Some(Part::Info(info)) => {
+                    if writer.is_some() {
+                        return Err(Status::invalid_argument("part info already sent"));
+                    }
+
+                    let upload_id = ObjectId::parse_str(&info.upload_id)
+                        .map_err(|_| Status::invalid_argument("invalid upload id"))?;
+                    let upload = self.multipart_uploads.get(upload_id, user_id)?;
+
+                    writer = Some(upload.start_part(info.number).await?);
+                }
+                Some(Part::Chunk(bytes)) => {
+                    let writer = writer.as_mut().ok_or(Status::invalid_argument(
+                        "part info must be sent before the byte stream",
+                    ))?;
+
+                    writer.write(&bytes).await?;
+                }
+                None => return Err(Status::invalid_argument("empty part request")),
+            }
+        }
+
+        writer
+            .ok_or(Status::invalid_argument("no data received"))?
+            .finish()
+            .await?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        request: Request<MultipartUploadRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let caller = auth_token::authenticated_user(&request)?.clone();
+
+        let upload_id = ObjectId::parse_str(&request.get_ref().upload_id)
+            .map_err(|_| Status::invalid_argument("invalid upload id"))?;
+
+        let (upload, staged) = self
+            .multipart_uploads
+            .complete(upload_id, caller.id)
+            .await?;
+        let db_file = self.store_upload(&caller, staged, &upload.info).await?;
+
+        tracing::debug!(
+            "uploaded file {} with hash {} in {} parts",
+            upload.info.path,
+            upload.info.hash,
+            upload.part_count()
+        );
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        request: Request<MultipartUploadRequest>,
+    ) -> Result<Response<()>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+
+        let upload_id = ObjectId::parse_str(&request.get_ref().upload_id)
+            .map_err(|_| Status::invalid_argument("invalid upload id"))?;
+
+        self.multipart_uploads.abort(upload_id, user_id).await?;
+
+        Ok(Response::new(()))
+    }
+
+    async fn find_many(
+        &self,
+        request: Request<FindManyRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let paths = &request.get_ref().paths;
+
+        check_batch_size(paths.len())?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let mut cursor = db_files
+            .find(doc! { "owner_id": user_id, "path": { "$in": paths } }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let mut found = HashMap::new();
+
+        while let Some(db_file) = cursor.next().await {
+            let db_file = db_file.map_err(|e| Status::internal(e.to_string()))?;
+            found.insert(db_file.path.to_owned(), db_file.to_proto());
+        }
+
+        let results = paths
+            .iter()
+            .map(|p| match found.get(p) {
+                Some(file) => batch_result(Ok(Some(file.clone()))),
+                None => batch_result(Err(Status::not_found("file not found"))),
+            })
+            .collect();
+
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    async fn get_many(
+        &self,
+        request: Request<GetManyRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let ids = &request.get_ref().ids;
+
+        check_batch_size(ids.len())?;
+
+        let file_ids = ids
+            .iter()
+            .map(|id| ObjectId::parse_str(id).map_err(|_| Status::invalid_argument("invalid id")))
+            .collect::<Vec<_>>();
+        let valid_ids = file_ids
+            .iter()
+            .filter_map(|id| id.as_ref().ok())
+            .collect::<Vec<_>>();
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let mut cursor = db_files
+            .find(
+                doc! { "_id": { "$in": valid_ids }, "owner_id": user_id },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let mut found = HashMap::new();
+
+        while let Some(db_file) = cursor.next().await {
+            let db_file = db_file.map_err(|e| Status::internal(e.to_string()))?;
+            found.insert(db_file.id, db_file.to_proto());
+        }
+
+        let results = file_ids
+            .into_iter()
+            .map(|id| match id.map(|id| found.get(&id)) {
+                Ok(Some(file)) => batch_result(Ok(Some(file.clone()))),
+                Ok(None) => batch_result(Err(Status::not_found("file not found"))),
+                Err(e) => batch_result(Err(e)),
+            })
+            .collect();
+
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    async fn delete_many(
+        &self,
+        request: Request<DeleteManyRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let files = &request.get_ref().files;
+
+        check_batch_size(files.len())?;
+
+        let mut results = Vec::with_capacity(files.len());
+
+        // every file is deleted in its own transaction, so one conflict fails only its item
+        for file in files {
+            let delete_res = self.delete_file(caller, file).await;
+            results.push(batch_result(delete_res.map(|_| None)));
+        }
+
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    async fn move_many(
+        &self,
+        request: Request<MoveManyRequest>,
+    ) -> Result<Response<BatchResponse>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let files = &request.get_ref().files;
+
+        check_batch_size(files.len())?;
+
+        let mut results = Vec::with_capacity(files.len());
+
+        // files are moved in order, a file can take the path another file was moved from
+        for file in files {
+            let move_res = self.move_file(caller, file).await;
+            results.push(batch_result(move_res.map(|f| Some(f.to_proto()))));
+        }
+
+        Ok(Response::new(BatchResponse { results }))
+    }
+
+    async fn list(
+        &self,
+        request: Request<ListFilesRequest>,
+    ) -> Result<Response<ListFilesResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let query = ListQuery::new(user_id, request.get_ref())?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        // one more file than requested tells whether there is another page
+        let options = FindOptions::builder()
+            .sort(query.sort.clone())
+            .limit(query.page_size as i64 + 1)
+            .build();
+
+        let mut db_files = db_files
+            .find(query.filter.clone(), options)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut next_page_token = String::new();
+        if db_files.len() > query.page_size {
+            db_files.truncate(query.page_size);
+            next_page_token = query.next_page_token(db_files.last().unwrap());
+        }
+
+        Ok(Response::new(ListFilesResponse {
+            files: db_files.iter().map(|f| f.to_proto()).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchFilesRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let query = ListQuery::search(user_id, request.get_ref())?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let options = FindOptions::builder()
+            .sort(query.sort.clone())
+            .limit(query.page_size as i64 + 1)
+            .build();
+
+        let mut cursor = db_files
+            .find(query.filter.clone(), options)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let stream = async_stream::try_stream! {
+            let mut files = Vec::new();
+            let mut next_page_token = String::new();
+            let mut count = 0;
+            let mut last = None;
+
+            while let Some(db_file) = cursor
+                .try_next()
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+            {
+                // the file after the page only tells that there is another page
+                if count == query.page_size {
+                    next_page_token = query.next_page_token(last.as_ref().unwrap());
+                    break;
+                }
+
+                count += 1;
+                files.push(db_file.to_proto());
+                last = Some(db_file);
+
+                if files.len() == SEARCH_BATCH_SIZE {
+                    yield SearchFilesResponse {
+                        files: std::mem::take(&mut files),
+                        next_page_token: String::new(),
+                    };
+                }
+            }
+
+            yield SearchFilesResponse { files, next_page_token };
+        };
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn search_content(
+        &self,
+        request: Request<SearchContentRequest>,
+    ) -> Result<Response<SearchContentResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let content_index = self
+            .content_index
+            .as_ref()
+            .ok_or(Status::unimplemented("content search is not enabled"))?;
+
+        let SearchContentRequest { query, limit } = request.into_inner();
+        let matches = content_index.search(user_id, query, limit).await?;
+
+        let db = self.mongo.database("cloud");
+        let db_files = db.collection::<DbFile>("files");
+
+        let file_ids = matches.iter().map(|m| m.file_id).collect::<Vec<_>>();
+        let mut db_files = db_files
+            .find(
+                doc! { "_id": { "$in": file_ids }, "owner_id": user_id },
+                None,
+            )
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_ok(|f| (f.id, f))
+            .try_collect::<HashMap<_, _>>()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        // files deleted since they were indexed are left out
+        let matches = matches
+            .into_iter()
+            .filter_map(|m| {
+                let db_file = db_files.remove(&m.file_id)?;
+
+                Some(proto::ContentMatch {
+                    file: Some(db_file.to_proto()),
+                    score: m.score,
+                    snippet: m.snippet,
+                    highlights: m
+                        .highlights
+                        .into_iter()
+                        .map(|r| proto::TextRange {
+                            start: r.start as u32,
+                            end: r.end as u32,
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+
+        Ok(Response::new(SearchContentResponse { matches }))
+    }
+
+    async fn add_tags(
+        &self,
+        request: Request<FileTagsRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        let tags = normalize_tags(&request.tags)?;
+        let limit = doc! {
+            "$lte": [
+                { "$size": { "$setUnion": [{ "$ifNull": ["$tags", []] }, &tags] } },
+                MAX_TAGS as i32,
+            ]
+        };
+        let message = format!("a file has at most {} tags", MAX_TAGS);
+
+        let db_file = self
+            .update_file_metadata(
+                user_id,
+                &request.id,
+                doc! { "$addToSet": { "tags": { "$each": &tags } } },
+                Some((limit, &message)),
+            )
+            .await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn remove_tags(
+        &self,
+        request: Request<FileTagsRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        let tags = normalize_tags(&request.tags)?;
+
+        let db_file = self
+            .update_file_metadata(
+                user_id,
+                &request.id,
+                doc! { "$pull": { "tags": { "$in": &tags } } },
+                None,
+            )
+            .await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn set_favorite(
+        &self,
+        request: Request<SetFavoriteRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        let db_file = self
+            .update_file_metadata(
+                user_id,
+                &request.id,
+                doc! { "$set": { "favorite": request.favorite } },
+                None,
+            )
+            .await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn set_metadata(
+        &self,
+        request: Request<SetMetadataRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        if request.metadata.is_empty() {
+            return Err(Status::invalid_argument("no metadata specified"));
+        }
+
+        let mut set = Document::new();
+        for (key, value) in &request.metadata {
+            validate_metadata_key(key)?;
+
+            if value.chars().count() > MAX_METADATA_VALUE_LENGTH {
+                return Err(Status::invalid_argument(format!(
+                    "metadata values have at most {} characters",
+                    MAX_METADATA_VALUE_LENGTH
+                )));
+            }
+
+            set.insert(format!("metadata.{}", key), value);
+        }
+
+        let keys = request.metadata.keys().collect::<Vec<_>>();
+        let limit = doc! {
+            "$lte": [
+                { "$size": { "$setUnion": [
+                    {
+                        "$map": {
+                            "input": { "$objectToArray": { "$ifNull": ["$metadata", {}] } },
+                            "in": "$$this.k",
+                        }
+                    },
+                    keys,
+                ] } },
+                MAX_METADATA_ENTRIES as i32,
+            ]
+        };
+        let message = format!(
+            "a file has at most {} metadata entries",
+            MAX_METADATA_ENTRIES
+        );
+
+        let db_file = self
+            .update_file_metadata(
+                user_id,
+                &request.id,
+                doc! { "$set": set },
+                Some((limit, &message)),
+            )
+            .await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn remove_metadata(
+        &self,
+        request: Request<RemoveMetadataRequest>,
+    ) -> Result<Response<proto::File>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        if request.keys.is_empty() {
+            return Err(Status::invalid_argument("no metadata keys specified"));
+        }
+
+        let mut unset = Document::new();
+        for key in &request.keys {
+            validate_metadata_key(key)?;
+            unset.insert(format!("metadata.{}", key), "");
+        }
+
+        let db_file = self
+            .update_file_metadata(user_id, &request.id, doc! { "$unset": unset }, None)
+            .await?;
+
+        Ok(Response::new(db_file.to_proto()))
+    }
+
+    async fn get_thumbnail(
+        &self,
+        request: Request<GetThumbnailRequest>,
+    ) -> Result<Response<proto::Thumbnail>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let request = request.get_ref();
+
+        let file_id =
+            ObjectId::parse_str(&request.id).map_err(|_| Status::invalid_argument("invalid id"))?;
+
+        let db = self.mongo.database("cloud");
+        let db_file = db
+            .collection::<DbFile>("files")
+            .find_one(doc! { "_id": file_id, "owner_id": user_id }, None)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .ok_or(Status::not_found("file not found"))?;
+
+        let thumbnail = thumbnails::open(&db, self.master_keys.clone(), &db_file, request.size)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let Some((db_thumbnail, data)) = thumbnail else {
+            let queued = self
+                .thumbnails
+                .generate_missing(&db, &db_file)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+
+            return match queued {
+                true => Err(Status::not_found("thumbnail is not generated yet")),
+                false => Err(Status::not_found("file has no thumbnail")),
+            };
+        };
+
+        Ok(Response::new(proto::Thumbnail {
+            data,
+            mime_type: thumbnails::MIME_TYPE.to_owned(),
+            width: db_thumbnail.width,
+            height: db_thumbnail.height,
+        }))
+    }
+
+    async fn get_duplicates(
+        &self,
+        request: Request<GetDuplicatesRequest>,
+    ) -> Result<Response<GetDuplicatesResponse>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+        let GetDuplicatesRequest { min_size, limit } = request.into_inner();
+
+        let db = self.mongo.database("cloud");
+        let groups = duplicates::find(&db, user_id, min_size, limit).await?;
+
+        Ok(Response::new(GetDuplicatesResponse { groups }))
+    }
+
+    async fn list_activity(
+        &self,
+        request: Request<ListActivityRequest>,
+    ) -> Result<Response<ListActivityResponse>, Status> {
+        let caller = auth_token::authenticated_user(&request)?;
+        let query = ActivityQuery::new(caller.id, request.get_ref())?;
+
+        let db = self.mongo.database("cloud");
+        let db_activities = db.collection::<DbActivity>("activities");
+
+        // one more activity than requested tells whether there is another page
+        let options = FindOptions::builder()
+            .sort(query.sort.clone())
+            .limit(query.page_size as i64 + 1)
+            .build();
+
+        let mut db_activities = db_activities
+            .find(query.filter.clone(), options)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        let mut next_page_token = String::new();
+        if db_activities.len() > query.page_size {
+            db_activities.truncate(query.page_size);
+            next_page_token = query.next_page_token(db_activities.last().unwrap());
+        }
+
+        Ok(Response::new(ListActivityResponse {
+            activities: db_activities
+                .iter()
+                .map(|a| a.to_proto(caller.session))
+                .collect(),
+            next_page_token,
+        }))
+    }
+}
+
+/// marks the chunks of a file as used and returns their stored size, fails if a chunk has
+/// not been uploaded or the chunks do not add up to `size`
+async fn use_chunks(
+    db: &Database,
+    owner_id: ObjectId,
+    hashes: &[String],
+    size: u64,
+    session: &mut ClientSession,
+) -> Result<u64, Status> {
+    let db_chunks = db.collection::<DbChunk>("chunks");
+
+    let mut cursor = db_chunks
+        .find_with_session(
+            doc! { "owner_id": owner_id, "hash": { "$in": hashes } },
+            None,
+            session,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+    let mut chunks = HashMap::new();
+
+    while let Some(db_chunk) = cursor.next(session).await {
+        let db_chunk = db_chunk.map_err(|e| Status::internal(e.to_string()))?;
+        chunks.insert(db_chunk.hash.to_owned(), db_chunk);
+    }
+
+    let mut chunks_size = 0;
+    let mut stored_size = 0;
+
+    for hash in hashes {
+        let db_chunk = chunks
+            .get(hash)
+            .ok_or_else(|| Status::not_found(format!("chunk {} has not been uploaded", hash)))?;
+
+        chunks_size += db_chunk.size;
+        stored_size += db_chunk.stored_size;
+    }
+
+    if chunks_size != size {
+        return Err(Status::invalid_argument(format!(
+            "size(chunks: {}, client: {}) do not match",
+            chunks_size, size
+        )));
+    }
+
+    // keeps the garbage collection from removing the chunks before the commit
+    db_chunks
+        .update_many_with_session(
+            doc! { "owner_id": owner_id, "hash": { "$in": hashes } },
+            doc! { "$set": { "last_used_at": bson::DateTime::now() } },
+            None,
+            session,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(stored_size)
+}
+
+/// the size charged for the chunks of an owner that are not part of any file, e.g. uploaded
+/// for a commit that has not happened yet
+async fn pending_chunks_size(
+    db: &Database,
+    owner_id: ObjectId,
+    quota_charge: QuotaCharge,
+) -> Result<u64, Status> {
+    let pending = db
+        .collection::<DbChunk>("chunks")
+        .aggregate(
+            [
+                doc! { "$match": { "owner_id": owner_id } },
+                // like the garbage collection, files of other owners with the same chunk
+                // count as using it
+                doc! { "$lookup": {
+                    "from": "files",
+                    "localField": "hash",
+                    "foreignField": "chunks",
+                    "as": "files",
+                } },
+                doc! { "$match": { "files": { "$size": 0 } } },
+                doc! { "$group": {
+                    "_id": null,
+                    "size": { "$sum": DbChunk::charged_size_expression(quota_charge) },
+                } },
+            ],
+            None,
+        )
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .try_next()
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(pending
+        .and_then(|p| p.get("size").cloned())
+        .and_then(|s| bson::from_bson::<i64>(s).ok())
+        .map_or(0, |s| s.max(0) as u64))
+}
+
+fn validate_path(path: &str) -> Result<(), Status> {
+    let path = Path::new(path);
+
+    if !path.is_absolute() {
+        return Err(Status::invalid_argument("path is not absolute"));
+    }
+
+    match path.file_name() {
+        Some(file_name) => {
+            if file_name == ".sync.db" {
+                return Err(Status::invalid_argument("file name can not be .sync.db"));
+            }
+
+            // temp files of the desktop client
+            for prefix in [".~download~", ".~outboard~"] {
+                if file_name.to_string_lossy().starts_with(prefix) {
+                    return Err(Status::invalid_argument(format!(
+                        "file name can not start with {}",
+                        prefix
+                    )));
+                }
+            }
+        }
+        None => {
+            return Err(Status::invalid_argument("no file name specified"));
+        }
+    }
+
+    Ok(())
+}
+
+/// trims the tags of a request and removes duplicates
+fn normalize_tags(tags: &[String]) -> Result<Vec<String>, Status> {
+    if tags.is_empty() {
+        return Err(Status::invalid_argument("no tags specified"));
+    }
+
+    let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+
+    for tag in tags {
+        let tag = tag.trim();
+
+        if tag.is_empty() {
+            return Err(Status::invalid_argument("tags can not be empty"));
+        }
+
+        if tag.chars().count() > MAX_KEY_LENGTH {
+            return Err(Status::invalid_argument(format!(
+                "tags have at most {} characters",
+                MAX_KEY_LENGTH
+            )));
+        }
+
+        if !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_owned());
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        return Err(Status::invalid_argument(format!(
+            "a file has at most {} tags",
+            MAX_TAGS
+        )));
+    }
+
+    Ok(normalized)
+}
+
+/// metadata is stored as a document, its keys can not be field paths or operators
+fn validate_metadata_key(key: &str) -> Result<(), Status> {
+    if key.is_empty() {
+        return Err(Status::invalid_argument("metadata keys can not be empty"));
+    }
+
+    if key.chars().count() > MAX_KEY_LENGTH {
+        return Err(Status::invalid_argument(format!(
+            "metadata keys have at most {} characters",
+            MAX_KEY_LENGTH
+        )));
+    }
+
+    if key.contains('.') || key.starts_with('$') {
+        return Err(Status::invalid_argument(
+            "metadata keys can not contain a dot or start with $",
+        ));
+    }
+
+    Ok(())
+}
+
+/// fails unless the current file has the expected hash, an empty hash expects no file
+///
+/// the current file is sent in the status details so the client can resolve the conflict
+fn check_precondition(expected_hash: Option<&str>, db_file: Option<&DbFile>) -> Result<(), Status> {
+    let expected_hash = match expected_hash {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+
+    if db_file.map_or("", |f| f.hash.as_str()) == expected_hash {
+        return Ok(());
+    }
+
+    let details = db_file
+        .map(|f| f.to_proto().encode_to_vec())
+        .unwrap_or_default();
+
+    Err(Status::with_details(
+        Code::FailedPrecondition,
+        "file has been changed",
+        details.into(),
+    ))
+}
+
+/// deletes blobs that are no longer referenced, a blob that can not be deleted is left to
+/// the garbage collection
+async fn delete_blobs(
+    bucket: &GridFsBucket,
+    ids: impl IntoIterator<Item = ObjectId>,
+    reason: &str,
+) {
+    for id in ids {
+        if let Err(e) = bucket.delete(id.into()).await {
+            tracing::error!("failed to delete {} blob {}: {:?}", reason, id, e);
+        }
+    }
+}
+
+/// rejects batches larger than [`MAX_BATCH_SIZE`]
+fn check_batch_size(len: usize) -> Result<(), Status> {
+    if len > MAX_BATCH_SIZE {
+        return Err(Status::invalid_argument(format!(
+            "batches take at most {} items",
+            MAX_BATCH_SIZE
+        )));
+    }
+
+    Ok(())
+}
+
+/// the result of one batch item, failed items carry the status the single item rpc would
+/// have returned
+fn batch_result(res: Result<Option<proto::File>, Status>) -> BatchResult {
+    match res {
+        Ok(file) => BatchResult {
+            file,
+            code: Code::Ok as i32,
+            message: String::new(),
+            details: Vec::new(),
+        },
+        Err(status) => BatchResult {
+            file: None,
+            code: status.code() as i32,
+            message: status.message().to_owned(),
+            details: status.details().to_vec(),
+        },
+    }
+}
+
+/// fails if a session other than the caller's holds a lock on the path
+async fn check_lock(
+    db: &mongodb::Database,
+    caller: &AuthenticatedUser,
+    path: &str,
+    session: Option<&mut ClientSession>,
+) -> Result<(), Status> {
+    let db_locks = db.collection::<DbFileLock>("file_locks");
+    let filter = doc! {
+        "owner_id": caller.id,
+        "path": path,
+        "expires_at": { "$gt": bson::DateTime::now() },
+    };
+
+    let db_lock = match session {
+        Some(session) => db_locks.find_one_with_session(filter, None, session).await,
+        None => db_locks.find_one(filter, None).await,
+    }
+    .map_err(|e| Status::internal(e.to_string()))?;
+
+    match db_lock {
+        Some(db_lock) if db_lock.session != caller.session => Err(Status::permission_denied(
+            format!("file is locked by {}", db_lock.holder_name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloud_proto::{prost::Message, proto};
+    use mongodb::bson::{self, oid::ObjectId};
+
+    use crate::{models::DbFile, services::file};
+
+    #[test]
+    fn check_precondition() {
+        let db_file = DbFile {
+            id: ObjectId::new(),
+            owner_id: ObjectId::new(),
+            bucket_id: Some(ObjectId::new()),
+            chunks: None,
+            outboard_id: None,
+            path: "/a.txt".to_owned(),
+            hash: "abc".to_owned(),
+            size: 3,
+            stored_size: None,
+            created_at: bson::DateTime::now(),
+            modified_at: bson::DateTime::now(),
+            mtime: None,
+            mode: None,
+            mime_type: "text/plain".to_owned(),
+            tags: Vec::new(),
+            favorite: false,
+            metadata: Default::default(),
+            media: None,
+        };
+
+        assert!(file::check_precondition(None, Some(&db_file)).is_ok());
+        assert!(file::check_precondition(Some("abc"), Some(&db_file)).is_ok());
+        assert!(file::check_precondition(Some(""), None).is_ok());
+
+        let status = file::check_precondition(Some("abd"), Some(&db_file)).unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+        let current = proto::File::decode(status.details()).unwrap();
+        assert_eq!(db_file.id.to_string(), current.id);
+
+        let status = file::check_precondition(Some(""), Some(&db_file)).unwrap_err();
+        assert_eq!(tonic::Code::FailedPrecondition, status.code());
+
+        let status = file::check_precondition(Some("abc"), None).unwrap_err();
+        assert!(status.details().is_empty());
+    }
+
+    #[test]
+    fn normalize_tags() {
+        let tags = vec![
+            " invoice ".to_owned(),
+            "2024".to_owned(),
+            "invoice".to_owned(),
+        ];
+        assert_eq!(
+            vec!["invoice".to_owned(), "2024".to_owned()],
+            file::normalize_tags(&tags).unwrap()
+        );
+
+        assert!(file::normalize_tags(&[]).is_err());
+        assert!(file::normalize_tags(&[" ".to_owned()]).is_err());
+        assert!(file::normalize_tags(&["a".repeat(101)]).is_err());
+
+        assert!(file::validate_metadata_key("camera").is_ok());
+        assert!(file::validate_metadata_key("a.b").is_err());
+        assert!(file::validate_metadata_key("$set").is_err());
+    }
+
+    #[test]
+    fn batch_result_keeps_status() {
+        let file = proto::File {
+            id: ObjectId::new().to_string(),
+            ..Default::default()
+        };
+
+        let result = file::batch_result(Ok(Some(file.clone())));
+        assert_eq!(tonic::Code::Ok as i32, result.code);
+        assert_eq!(Some(file.clone()), result.file);
+
+        let status = tonic::Status::with_details(
+            tonic::Code::FailedPrecondition,
+            "file has changed",
+            file.encode_to_vec().into(),
+        );
+        let result = file::batch_result(Err(status));
+        assert_eq!(tonic::Code::FailedPrecondition as i32, result.code);
+        assert_eq!("file has changed", result.message);
+        assert_eq!(file, proto::File::decode(&result.details[..]).unwrap());
+        assert!(result.file.is_none());
+    }
 }
@@ -2,23 +2,24 @@ use cloud_proto::proto::{self, user_service_server::UserService};
 use mongodb::bson::doc;
 use tonic::{Request, Response, Status};
 
-use crate::{auth_token, models::DbUser};
+use crate::{auth_token, config::Configuration, models::DbUser, usage};
 
 #[derive(Debug)]
 pub struct MyUserService {
+    config: Configuration,
     mongo: mongodb::Client,
 }
 
 impl MyUserService {
-    pub fn new(mongo: mongodb::Client) -> Self {
-        Self { mongo }
+    pub fn new(config: Configuration, mongo: mongodb::Client) -> Self {
+        Self { config, mongo }
     }
 }
 
 #[tonic::async_trait]
 impl UserService for MyUserService {
     async fn get_self(&self, request: Request<()>) -> Result<Response<proto::User>, Status> {
-        let user_id = auth_token::get_user_id_from_request(&request)?;
+        let user_id = auth_token::authenticated_user(&request)?.id;
 
         let db = self.mongo.database("cloud");
         let db_users = db.collection::<DbUser>("users");
@@ -33,4 +34,16 @@ impl UserService for MyUserService {
             None => return Err(Status::not_found("could not find user")),
         }
     }
+
+    async fn get_storage_usage(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<proto::StorageUsage>, Status> {
+        let user_id = auth_token::authenticated_user(&request)?.id;
+
+        let db = self.mongo.database("cloud");
+        let storage_usage = usage::breakdown(&db, user_id, self.config.quota_charge).await?;
+
+        Ok(Response::new(storage_usage))
+    }
 }
@@ -0,0 +1,427 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use futures_util::AsyncWriteExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId},
+    options::GridFsUploadOptions,
+    GridFsBucket, GridFsUploadStream,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt as _},
+};
+
+use crate::{
+    compression::{self, Compressor},
+    crypto::{self, MasterKeys, SegmentEncryptor},
+};
+
+/// An upload that is written to the staging directory until it has been verified.
+///
+/// Nothing reaches the bucket before [`StagedUpload::store`], the staging file is removed
+/// when the upload is dropped.
+#[derive(Debug)]
+pub struct StagedUpload {
+    path: PathBuf,
+    file: File,
+    hasher: blake3::Hasher,
+    size: u64,
+}
+
+impl StagedUpload {
+    pub async fn create(staging_dir: &Path) -> Result<Self, std::io::Error> {
+        let path = staging_dir.join(ObjectId::new().to_hex());
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+
+        Ok(Self {
+            path,
+            file,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        })
+    }
+
+    /// takes over a staging file that was written out of order, its bytes are hashed by
+    /// reading it back
+    pub async fn open(path: PathBuf) -> Result<Self, std::io::Error> {
+        let file = File::options().read(true).write(true).open(&path).await?;
+
+        let mut staged = Self {
+            path,
+            file,
+            hasher: blake3::Hasher::new(),
+            size: 0,
+        };
+        let mut buf = vec![0; crypto::SEGMENT_SIZE];
+
+        loop {
+            let len = staged.file.read(&mut buf).await?;
+
+            if len == 0 {
+                break;
+            }
+
+            staged.hasher.update(&buf[..len]);
+            staged.size += len as u64;
+        }
+
+        Ok(staged)
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> Result<(), std::io::Error> {
+        self.file.write_all(bytes).await?;
+        self.hasher.update(bytes);
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn hash(&self) -> String {
+        self.hasher.finalize().to_string()
+    }
+
+    /// copies the staged bytes into a new blob, the blob is compressed if a compression
+    /// level is set and encrypted if master keys are set
+    ///
+    /// the bytes are hashed again while they are copied, so a staging file that changed on
+    /// disk is never stored. The bao outboard tree of the bytes is stored in a second blob.
+    pub async fn store(
+        mut self,
+        bucket: &GridFsBucket,
+        filename: &str,
+        master_keys: Option<&MasterKeys>,
+        compression_level: Option<i32>,
+    ) -> Result<StoredBlob, anyhow::Error> {
+        self.file.flush().await?;
+        self.file.rewind().await?;
+
+        let expected_hash = self.hasher.finalize();
+        let outboard_path = self.path.with_extension("obao");
+        let path = &self.path;
+        let file = &mut self.file;
+        let size = self.size;
+
+        let store_res = async {
+            write_outboard(path, &outboard_path, expected_hash).await?;
+
+            let mut blob = write_blob(
+                bucket,
+                filename,
+                file,
+                size,
+                Some(expected_hash),
+                master_keys,
+                compression_level,
+            )
+            .await?;
+
+            let mut outboard = File::open(&outboard_path).await?;
+            let outboard_size = outboard.metadata().await?.len();
+
+            // the tree consists of hashes, which do not compress
+            let outboard_res = write_blob(
+                bucket,
+                &format!("{}.obao", filename),
+                &mut outboard,
+                outboard_size,
+                None,
+                master_keys,
+                None,
+            )
+            .await;
+
+            match outboard_res {
+                Ok(outboard_blob) => blob.outboard_id = Some(outboard_blob.id),
+                Err(e) => {
+                    bucket.delete(blob.id.into()).await.ok();
+                    return Err(e);
+                }
+            }
+
+            Ok(blob)
+        }
+        .await;
+
+        if let Err(e) = tokio::fs::remove_file(&outboard_path).await {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("failed to remove outboard {:?}: {}", outboard_path, e);
+            }
+        }
+
+        store_res
+    }
+}
+
+/// builds the bao outboard tree of the staged file at `path`, which lets a download verify
+/// every range of the content as it arrives
+async fn write_outboard(
+    path: &Path,
+    outboard_path: &Path,
+    expected_hash: blake3::Hash,
+) -> Result<(), anyhow::Error> {
+    let path = path.to_owned();
+    let outboard_path = outboard_path.to_owned();
+
+    let hash = tokio::task::spawn_blocking(move || -> Result<blake3::Hash, std::io::Error> {
+        let mut content = std::fs::File::open(path)?;
+        let outboard = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(outboard_path)?;
+
+        let mut encoder = bao::encode::Encoder::new_outboard(outboard);
+        std::io::copy(&mut content, &mut encoder)?;
+        encoder.finalize()
+    })
+    .await??;
+
+    // the root of the tree is the blake3 hash of the content
+    if hash != expected_hash {
+        return Err(anyhow!("staged upload changed before it was stored"));
+    }
+
+    Ok(())
+}
+
+/// stores bytes that are already in memory and have been verified by the caller as a new
+/// blob
+pub async fn store_bytes(
+    bucket: &GridFsBucket,
+    filename: &str,
+    mut bytes: &[u8],
+    master_keys: Option<&MasterKeys>,
+    compression_level: Option<i32>,
+) -> Result<StoredBlob, anyhow::Error> {
+    let size = bytes.len() as u64;
+
+    write_blob(
+        bucket,
+        filename,
+        &mut bytes,
+        size,
+        None,
+        master_keys,
+        compression_level,
+    )
+    .await
+}
+
+/// copies `size` bytes of `reader` into a new blob and aborts it if the bytes do not match
+/// `expected_hash`
+async fn write_blob<R>(
+    bucket: &GridFsBucket,
+    filename: &str,
+    reader: &mut R,
+    size: u64,
+    expected_hash: Option<blake3::Hash>,
+    master_keys: Option<&MasterKeys>,
+    compression_level: Option<i32>,
+) -> Result<StoredBlob, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut metadata = doc! {};
+
+    let mut compressor = match compression_level {
+        Some(level) => {
+            metadata.insert("compression", compression::ZSTD);
+            Some(Compressor::new(level)?)
+        }
+        None => None,
+    };
+
+    let encryptor = match master_keys {
+        Some(master_keys) => {
+            let (encryptor, encryption) = master_keys.encryptor()?;
+            metadata.insert("encryption", bson::to_bson(&encryption)?);
+            Some(encryptor)
+        }
+        None => None,
+    };
+
+    let mut bucket_stream = bucket.open_upload_stream(
+        filename,
+        GridFsUploadOptions::builder()
+            .metadata((!metadata.is_empty()).then_some(metadata))
+            .build(),
+    );
+    let mut writer = SegmentWriter {
+        encryptor,
+        pending: Vec::new(),
+        size: 0,
+    };
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0; crypto::SEGMENT_SIZE];
+    let mut remaining = size;
+
+    let copy_res: Result<(), anyhow::Error> = async {
+        while remaining > 0 {
+            let len = remaining.min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..len]).await?;
+            remaining -= len as u64;
+
+            hasher.update(&buf[..len]);
+
+            match &mut compressor {
+                Some(compressor) => {
+                    let compressed = compressor.compress(&buf[..len])?;
+                    writer.write(&mut bucket_stream, &compressed).await?;
+                }
+                None => writer.write(&mut bucket_stream, &buf[..len]).await?,
+            }
+        }
+
+        if let Some(compressor) = compressor {
+            writer
+                .write(&mut bucket_stream, &compressor.finish()?)
+                .await?;
+        }
+
+        writer.finish(&mut bucket_stream).await?;
+
+        if matches!(expected_hash, Some(h) if h != hasher.finalize()) {
+            return Err(anyhow!("staged upload changed before it was stored"));
+        }
+
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = copy_res {
+        bucket_stream.abort().await.ok();
+        return Err(e);
+    }
+
+    bucket_stream.close().await?;
+
+    Ok(StoredBlob {
+        id: bucket_stream.id().as_object_id().unwrap(),
+        size: writer.size,
+        outboard_id: None,
+    })
+}
+
+/// A blob written to the bucket by [`StagedUpload::store`].
+#[derive(Debug)]
+pub struct StoredBlob {
+    pub id: ObjectId,
+    /// bytes written to the bucket, after compression and encryption
+    pub size: u64,
+    /// the blob holding the bao outboard tree of the content, only kept for staged uploads
+    pub outboard_id: Option<ObjectId>,
+}
+
+/// Cuts the stored bytes into segments, so every encrypted segment but the last one is
+/// full.
+struct SegmentWriter {
+    encryptor: Option<SegmentEncryptor>,
+    pending: Vec<u8>,
+    size: u64,
+}
+
+impl SegmentWriter {
+    async fn write(
+        &mut self,
+        bucket_stream: &mut GridFsUploadStream,
+        bytes: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        self.pending.extend_from_slice(bytes);
+
+        // a segment is only known not to be the last one once more bytes follow it
+        while self.pending.len() > crypto::SEGMENT_SIZE {
+            let rest = self.pending.split_off(crypto::SEGMENT_SIZE);
+            let segment = std::mem::replace(&mut self.pending, rest);
+            self.write_segment(bucket_stream, &segment, false).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish(
+        &mut self,
+        bucket_stream: &mut GridFsUploadStream,
+    ) -> Result<(), anyhow::Error> {
+        let segment = std::mem::take(&mut self.pending);
+        self.write_segment(bucket_stream, &segment, true).await
+    }
+
+    async fn write_segment(
+        &mut self,
+        bucket_stream: &mut GridFsUploadStream,
+        segment: &[u8],
+        last: bool,
+    ) -> Result<(), anyhow::Error> {
+        match &mut self.encryptor {
+            Some(encryptor) => {
+                let sealed = encryptor.encrypt(segment, last)?;
+                bucket_stream.write_all(&sealed).await?;
+                self.size += sealed.len() as u64;
+            }
+            None => {
+                bucket_stream.write_all(segment).await?;
+                self.size += segment.len() as u64;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StagedUpload {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("failed to remove staged upload {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// creates the staging directory and removes uploads left behind by a previous run
+pub async fn prepare(staging_dir: &Path) -> Result<(), std::io::Error> {
+    tokio::fs::create_dir_all(staging_dir).await?;
+
+    let mut entries = tokio::fs::read_dir(staging_dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::staging::StagedUpload;
+
+    #[tokio::test]
+    async fn staged_upload_is_removed_on_drop() {
+        let dir = std::env::temp_dir().join(format!("cloud-staging-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let mut staged = StagedUpload::create(&dir).await.unwrap();
+        staged.write(b"hello ").await.unwrap();
+        staged.write(b"world").await.unwrap();
+
+        assert_eq!(11, staged.size());
+        assert_eq!(blake3::hash(b"hello world").to_string(), staged.hash());
+
+        let path = staged.path.clone();
+        assert!(path.exists());
+
+        drop(staged);
+        assert!(!path.exists());
+
+        tokio::fs::remove_dir(&dir).await.unwrap();
+    }
+}
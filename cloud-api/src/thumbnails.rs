@@ -0,0 +1,365 @@
+use std::{
+    collections::HashSet,
+    io::Cursor,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use futures_util::{StreamExt, TryStreamExt};
+use image::{codecs::jpeg::JpegEncoder, io::Reader, DynamicImage};
+use mongodb::{
+    bson::{doc, oid::ObjectId},
+    options::{FindOptions, UpdateOptions},
+    Database,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    blob,
+    crypto::MasterKeys,
+    models::{DbFile, DbThumbnail, DbThumbnailFailure},
+    staging,
+};
+
+/// edge lengths of the generated thumbnails in pixels
+pub const SIZES: [u32; 2] = [128, 512];
+pub const MIME_TYPE: &str = "image/jpeg";
+/// largest file a thumbnail is generated for
+const MAX_SOURCE_SIZE: u64 = 64 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+
+/// Generates the thumbnails of committed files in the background.
+///
+/// The thumbnails of a file are replaced whenever it is committed or deleted, a thumbnail
+/// whose file is gone leaves an unreferenced blob that the blob garbage collection removes.
+#[derive(Debug)]
+pub struct Thumbnails {
+    queue: mpsc::UnboundedSender<(ObjectId, ObjectId)>,
+    /// files in the queue, so a file is only queued once
+    pending: Arc<Mutex<HashSet<ObjectId>>>,
+}
+
+impl Thumbnails {
+    /// starts the worker that generates the thumbnails of the files passed to
+    /// [`Thumbnails::update`]
+    pub fn start(mongo: mongodb::Client, master_keys: Option<Arc<MasterKeys>>) -> Self {
+        let (queue, mut rx) = mpsc::unbounded_channel();
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let worker_pending = pending.clone();
+
+        tokio::spawn(async move {
+            let db = mongo.database("cloud");
+
+            while let Some((owner_id, file_id)) = rx.recv().await {
+                // a commit while the thumbnails are generated queues the file again
+                worker_pending.lock().unwrap().remove(&file_id);
+
+                let generate_res = generate(&db, master_keys.clone(), owner_id, file_id).await;
+
+                if let Err(e) = generate_res {
+                    tracing::warn!("failed to generate thumbnails of {}: {:?}", file_id, e);
+                }
+            }
+        });
+
+        Self { queue, pending }
+    }
+
+    /// generates the thumbnails of a file again after it was committed or deleted
+    pub fn update(&self, owner_id: ObjectId, file_id: ObjectId) {
+        if self.pending.lock().unwrap().insert(file_id) {
+            self.queue.send((owner_id, file_id)).ok();
+        }
+    }
+
+    /// queues a file without thumbnails, e.g. one uploaded before thumbnails were generated,
+    /// returns whether it can have any
+    pub async fn generate_missing(
+        &self,
+        db: &Database,
+        db_file: &DbFile,
+    ) -> Result<bool, anyhow::Error> {
+        if Source::of(db_file).is_none() {
+            return Ok(false);
+        }
+
+        let failed = db
+            .collection::<DbThumbnailFailure>("thumbnail_failures")
+            .count_documents(
+                doc! { "owner_id": db_file.owner_id, "hash": &db_file.hash },
+                None,
+            )
+            .await?;
+
+        if failed > 0 {
+            return Ok(false);
+        }
+
+        self.update(db_file.owner_id, db_file.id);
+        Ok(true)
+    }
+}
+
+/// the smallest thumbnail of the current content of a file that is at least `size` pixels
+/// large, or its largest thumbnail, together with its content
+pub async fn open(
+    db: &Database,
+    master_keys: Option<Arc<MasterKeys>>,
+    db_file: &DbFile,
+    size: u32,
+) -> Result<Option<(DbThumbnail, Vec<u8>)>, anyhow::Error> {
+    let options = FindOptions::builder().sort(doc! { "size": 1 }).build();
+    let db_thumbnails = db
+        .collection::<DbThumbnail>("thumbnails")
+        .find(
+            doc! { "file_id": db_file.id, "hash": &db_file.hash },
+            options,
+        )
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let db_thumbnail = match db_thumbnails.iter().position(|t| t.size >= size) {
+        Some(i) => db_thumbnails.into_iter().nth(i),
+        None => db_thumbnails.into_iter().last(),
+    };
+
+    let Some(db_thumbnail) = db_thumbnail else {
+        return Ok(None);
+    };
+
+    let bucket = db.gridfs_bucket(None);
+    let mut blob_stream =
+        blob::open(&bucket, master_keys.as_deref(), db_thumbnail.bucket_id).await?;
+    let mut content = Vec::new();
+
+    while let Some(bytes) = blob_stream.next().await {
+        content.extend(bytes?);
+    }
+
+    Ok(Some((db_thumbnail, content)))
+}
+
+async fn generate(
+    db: &Database,
+    master_keys: Option<Arc<MasterKeys>>,
+    owner_id: ObjectId,
+    file_id: ObjectId,
+) -> Result<(), anyhow::Error> {
+    let db_thumbnails = db.collection::<DbThumbnail>("thumbnails");
+
+    // the blobs of the old thumbnails are left to the garbage collection
+    db_thumbnails
+        .delete_many(doc! { "file_id": file_id }, None)
+        .await?;
+
+    let db_file = db
+        .collection::<DbFile>("files")
+        .find_one(doc! { "_id": file_id, "owner_id": owner_id }, None)
+        .await?;
+
+    let (db_file, source) = match db_file {
+        Some(db_file) => match Source::of(&db_file) {
+            Some(source) => (db_file, source),
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    let mut blob_stream = blob::open_file(db, master_keys.clone(), &db_file).await?;
+    let mut content = Vec::with_capacity(db_file.size as usize);
+
+    while let Some(bytes) = blob_stream.next().await {
+        content.extend(bytes?);
+    }
+
+    // decoding a malformed file may panic
+    let render_res = tokio::task::spawn_blocking(move || render(source, &content)).await;
+
+    let rendered = match render_res {
+        Ok(Ok(rendered)) => rendered,
+        Ok(Err(e)) => {
+            tracing::debug!("could not generate thumbnails of {}: {:?}", file_id, e);
+            return record_failure(db, owner_id, &db_file.hash).await;
+        }
+        Err(e) => {
+            tracing::debug!("thumbnail generation of {} panicked: {:?}", file_id, e);
+            return record_failure(db, owner_id, &db_file.hash).await;
+        }
+    };
+
+    let bucket = db.gridfs_bucket(None);
+
+    for rendered in rendered {
+        let stored_blob = staging::store_bytes(
+            &bucket,
+            "thumbnail",
+            &rendered.jpeg,
+            master_keys.as_deref(),
+            None,
+        )
+        .await?;
+
+        let db_thumbnail = DbThumbnail {
+            id: ObjectId::new(),
+            owner_id,
+            file_id,
+            hash: db_file.hash.to_owned(),
+            size: rendered.size,
+            width: rendered.width,
+            height: rendered.height,
+            bucket_id: stored_blob.id,
+        };
+
+        db_thumbnails.insert_one(&db_thumbnail, None).await?;
+    }
+
+    Ok(())
+}
+
+/// keeps the files with the content from being queued again by
+/// [`Thumbnails::generate_missing`]
+async fn record_failure(
+    db: &Database,
+    owner_id: ObjectId,
+    hash: &str,
+) -> Result<(), anyhow::Error> {
+    let options = UpdateOptions::builder().upsert(true).build();
+
+    db.collection::<DbThumbnailFailure>("thumbnail_failures")
+        .update_one(
+            doc! { "owner_id": owner_id, "hash": hash },
+            doc! { "$setOnInsert": { "_id": ObjectId::new() } },
+            options,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// A thumbnail encoded by [`render`].
+#[derive(Debug)]
+struct Rendered {
+    size: u32,
+    width: u32,
+    height: u32,
+    jpeg: Vec<u8>,
+}
+
+/// The kinds of files thumbnails are generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    Image,
+    Pdf,
+}
+
+impl Source {
+    fn of(db_file: &DbFile) -> Option<Self> {
+        if db_file.size > MAX_SOURCE_SIZE {
+            return None;
+        }
+
+        let extension = Path::new(&db_file.path)
+            .extension()?
+            .to_string_lossy()
+            .to_lowercase();
+
+        match extension.as_str() {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" => Some(Source::Image),
+            "pdf" => Some(Source::Pdf),
+            _ => None,
+        }
+    }
+
+    /// decodes the picture shown in the thumbnails
+    fn decode(self, content: &[u8]) -> Result<DynamicImage, anyhow::Error> {
+        match self {
+            Source::Image => decode_image(content),
+            Source::Pdf => decode_image(&first_page_image(content)?),
+        }
+    }
+}
+
+fn decode_image(bytes: &[u8]) -> Result<DynamicImage, anyhow::Error> {
+    // the default limits keep a huge image from allocating more than 512 MiB
+    Ok(Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+/// the largest jpeg image on the first page of a pdf, pages are not rendered, so only
+/// scanned documents have a preview
+fn first_page_image(content: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let document = pdf_extract::Document::load_mem(content)?;
+    let page_id = *document
+        .get_pages()
+        .values()
+        .next()
+        .ok_or_else(|| anyhow!("pdf has no pages"))?;
+
+    let image = document
+        .get_page_images(page_id)?
+        .into_iter()
+        .filter(|i| matches!(&i.filters, Some(f) if f == &["DCTDecode"]))
+        .max_by_key(|i| i.width * i.height)
+        .ok_or_else(|| anyhow!("first page has no jpeg image"))?;
+
+    Ok(image.content.to_vec())
+}
+
+/// the jpeg thumbnails of a file for each of [`SIZES`], pictures are scaled down to fit a
+/// size but never scaled up
+fn render(source: Source, content: &[u8]) -> Result<Vec<Rendered>, anyhow::Error> {
+    let picture = source.decode(content)?;
+    let mut rendered = Vec::with_capacity(SIZES.len());
+
+    for size in SIZES {
+        let thumbnail = match picture.width() > size || picture.height() > size {
+            true => picture.thumbnail(size, size),
+            false => picture.clone(),
+        };
+
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+            .encode_image(&thumbnail.to_rgb8())?;
+
+        rendered.push(Rendered {
+            size,
+            width: thumbnail.width(),
+            height: thumbnail.height(),
+            jpeg,
+        });
+    }
+
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat};
+
+    use crate::thumbnails::{self, Source};
+
+    #[test]
+    fn render_scales_down_to_each_size() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(1000, 250)
+            .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+
+        let rendered = thumbnails::render(Source::Image, &png).unwrap();
+        let sizes = rendered
+            .iter()
+            .map(|r| (r.size, r.width, r.height))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(128, 128, 32), (512, 512, 128)], sizes);
+
+        let jpeg = image::load_from_memory(&rendered[0].jpeg).unwrap();
+        assert_eq!((128, 32), (jpeg.width(), jpeg.height()));
+
+        assert!(thumbnails::render(Source::Image, b"not an image").is_err());
+    }
+}
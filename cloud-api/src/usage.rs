@@ -0,0 +1,204 @@
+use cloud_proto::proto;
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{self, doc, oid::ObjectId, Bson},
+    Database,
+};
+use serde::Deserialize;
+use tonic::Status;
+
+use crate::{config::QuotaCharge, models::DbFile};
+
+/// entries of the breakdowns by folder, type and extension
+const MAX_ENTRIES: i64 = 100;
+/// the keys of the age breakdown and the age in days up to which a file is counted in
+/// them, youngest first
+const AGE_BUCKETS: [(&str, Option<i64>); 5] = [
+    ("day", Some(1)),
+    ("week", Some(7)),
+    ("month", Some(30)),
+    ("year", Some(365)),
+    ("older", None),
+];
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+/// the extension of the file name in a path, names starting with a dot have none
+const EXTENSION_REGEX: &str = r"[^/]\.([^./]+)$";
+
+#[derive(Debug, Deserialize)]
+struct UsageEntry {
+    #[serde(rename = "_id")]
+    key: Option<String>,
+    size: u64,
+    file_count: u64,
+}
+
+/// The breakdowns computed by the `$facet` stage in [`breakdown`].
+#[derive(Debug, Deserialize)]
+struct Breakdowns {
+    total: Vec<UsageEntry>,
+    by_folder: Vec<UsageEntry>,
+    by_type: Vec<UsageEntry>,
+    by_extension: Vec<UsageEntry>,
+    by_age: Vec<UsageEntry>,
+}
+
+/// the storage charged for the files of an owner by folder, type, extension and age, all
+/// computed in a single aggregation
+pub async fn breakdown(
+    db: &Database,
+    owner_id: ObjectId,
+    quota_charge: QuotaCharge,
+) -> Result<proto::StorageUsage, Status> {
+    let now = bson::DateTime::now().timestamp_millis();
+    let age_branches = AGE_BUCKETS
+        .iter()
+        .filter_map(|(key, days)| {
+            let since = bson::DateTime::from_millis(now - (*days)? * DAY_MILLIS);
+            Some(doc! { "case": { "$gte": ["$modified_at", since] }, "then": key })
+        })
+        .collect::<Vec<_>>();
+
+    let group = |key: Bson| {
+        doc! { "$group": {
+            "_id": key,
+            "size": { "$sum": "$size" },
+            "file_count": { "$sum": 1 },
+        } }
+    };
+    let largest = |key: &str| {
+        vec![
+            group(key.into()),
+            doc! { "$sort": { "size": -1, "_id": 1 } },
+            doc! { "$limit": MAX_ENTRIES },
+        ]
+    };
+
+    let pipeline = [
+        doc! { "$match": { "owner_id": owner_id } },
+        doc! { "$project": {
+            "size": DbFile::charged_size_expression(quota_charge),
+            "folder": { "$let": {
+                "vars": { "parts": { "$split": ["$path", "/"] } },
+                // the first part is the empty string before the leading slash
+                "in": { "$cond": [
+                    { "$gt": [{ "$size": "$$parts" }, 2] },
+                    { "$concat": ["/", { "$arrayElemAt": ["$$parts", 1] }] },
+                    "/",
+                ] },
+            } },
+            "type": { "$arrayElemAt": [{ "$split": ["$mime_type", "/"] }, 0] },
+            "extension": { "$let": {
+                "vars": { "found": { "$regexFind": { "input": "$path", "regex": EXTENSION_REGEX } } },
+                "in": { "$toLower": { "$ifNull": [{ "$arrayElemAt": ["$$found.captures", 0] }, ""] } },
+            } },
+            "age": { "$switch": { "branches": age_branches, "default": "older" } },
+        } },
+        doc! { "$facet": {
+            "total": [group(Bson::Null)],
+            "by_folder": largest("$folder"),
+            "by_type": largest("$type"),
+            "by_extension": largest("$extension"),
+            "by_age": [group("$age".into())],
+        } },
+    ];
+
+    let mut cursor = db
+        .collection::<DbFile>("files")
+        .aggregate(pipeline, None)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    let breakdowns = cursor
+        .try_next()
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .ok_or_else(|| Status::internal("usage aggregation returned no breakdowns"))?;
+
+    let breakdowns = bson::from_document::<Breakdowns>(breakdowns)
+        .map_err(|e| Status::internal(e.to_string()))?;
+
+    Ok(to_proto(breakdowns))
+}
+
+/// every age bucket has an entry, empty buckets included
+fn to_proto(breakdowns: Breakdowns) -> proto::StorageUsage {
+    let total = breakdowns.total.into_iter().next();
+    let entries = |entries: Vec<UsageEntry>| entries.into_iter().map(|e| e.to_proto()).collect();
+
+    let by_age = AGE_BUCKETS
+        .iter()
+        .map(|(key, _)| {
+            let entry = breakdowns
+                .by_age
+                .iter()
+                .find(|e| e.key.as_deref() == Some(key));
+
+            proto::UsageEntry {
+                key: key.to_string(),
+                size: entry.map_or(0, |e| e.size),
+                file_count: entry.map_or(0, |e| e.file_count),
+            }
+        })
+        .collect();
+
+    proto::StorageUsage {
+        size: total.as_ref().map_or(0, |t| t.size),
+        file_count: total.as_ref().map_or(0, |t| t.file_count),
+        by_folder: entries(breakdowns.by_folder),
+        by_type: entries(breakdowns.by_type),
+        by_extension: entries(breakdowns.by_extension),
+        by_age,
+    }
+}
+
+impl UsageEntry {
+    fn to_proto(&self) -> proto::UsageEntry {
+        proto::UsageEntry {
+            key: self.key.to_owned().unwrap_or_default(),
+            size: self.size,
+            file_count: self.file_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{self, doc};
+
+    use crate::usage::{self, Breakdowns};
+
+    #[test]
+    fn age_breakdown_has_every_bucket() {
+        let breakdowns = bson::from_document::<Breakdowns>(doc! {
+            "total": [{ "_id": null, "size": 30_i64, "file_count": 3 }],
+            "by_folder": [{ "_id": "/", "size": 30_i64, "file_count": 3 }],
+            "by_type": [],
+            "by_extension": [],
+            "by_age": [
+                { "_id": "older", "size": 20_i64, "file_count": 1 },
+                { "_id": "day", "size": 10_i64, "file_count": 2 },
+            ],
+        })
+        .unwrap();
+
+        let usage = usage::to_proto(breakdowns);
+        assert_eq!((30, 3), (usage.size, usage.file_count));
+        assert_eq!("/", usage.by_folder[0].key);
+
+        let by_age = usage
+            .by_age
+            .iter()
+            .map(|e| (e.key.as_str(), e.size))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("day", 10),
+                ("week", 0),
+                ("month", 0),
+                ("year", 0),
+                ("older", 20)
+            ],
+            by_age
+        );
+    }
+}
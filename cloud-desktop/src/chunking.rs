@@ -0,0 +1,125 @@
+use std::{io::SeekFrom, path::Path};
+
+use fastcdc::v2020::StreamCDC;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    pub hash: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct ChunkedFile {
+    /// hash of the whole file
+    pub hash: String,
+    pub size: u64,
+    pub chunks: Vec<FileChunk>,
+}
+
+/// splits a file into content-defined chunks, so an edit only changes the chunks around it
+/// instead of shifting all chunks that follow
+pub async fn chunk_file<P>(path: P) -> Result<ChunkedFile, anyhow::Error>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref().to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(path)?;
+        let mut hasher = blake3::Hasher::new();
+        let mut size = 0;
+        let mut chunks = Vec::new();
+
+        for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = chunk?;
+            hasher.update(&chunk.data);
+            size += chunk.length as u64;
+
+            chunks.push(FileChunk {
+                hash: blake3::hash(&chunk.data).to_string(),
+                offset: chunk.offset,
+                size: chunk.length as u64,
+            });
+        }
+
+        Ok(ChunkedFile {
+            hash: hasher.finalize().to_string(),
+            size,
+            chunks,
+        })
+    })
+    .await?
+}
+
+/// reads a chunk back from the file it was cut from
+pub async fn read_chunk(file: &mut fs::File, chunk: &FileChunk) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = vec![0; chunk.size as usize];
+
+    file.seek(SeekFrom::Start(chunk.offset)).await?;
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunking;
+
+    #[tokio::test]
+    async fn chunk_file() {
+        let dir = std::env::temp_dir().join(format!("cloud-chunking-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        // pseudo random content, so the chunk boundaries depend on the content
+        let mut state = 1u64;
+        let content = (0..2 * 1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        let mut edited = content.clone();
+        edited.splice(1024 * 1024..1024 * 1024, b"inserted".iter().cloned());
+
+        tokio::fs::write(dir.join("content"), &content)
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("edited"), &edited).await.unwrap();
+
+        let chunked = chunking::chunk_file(dir.join("content")).await.unwrap();
+        let chunked_edited = chunking::chunk_file(dir.join("edited")).await.unwrap();
+
+        assert_eq!(blake3::hash(&content).to_string(), chunked.hash);
+        assert_eq!(content.len() as u64, chunked.size);
+        assert_eq!(
+            content.len() as u64,
+            chunked.chunks.iter().map(|c| c.size).sum::<u64>()
+        );
+
+        // only the chunk around the insertion changes
+        let changed = chunked_edited
+            .chunks
+            .iter()
+            .filter(|c| !chunked.chunks.iter().any(|o| o.hash == c.hash))
+            .count();
+        assert!(changed <= 2);
+
+        let mut file = tokio::fs::File::open(dir.join("content")).await.unwrap();
+        let chunk = &chunked.chunks[1];
+        let data = chunking::read_chunk(&mut file, chunk).await.unwrap();
+        assert_eq!(chunk.hash, blake3::hash(&data).to_string());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -1,15 +1,29 @@
+use std::{sync::Arc, time::SystemTime};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cloud_proto::proto;
 use dioxus::prelude::*;
+use tokio::sync::Mutex;
 
 use crate::{
+    global_state,
     path_helper::FilePath,
     routes::files::{FilePromptKeep, HandleFileCommand},
+    services::{api_service::FileApiService, database_service::DatabaseService},
 };
 
+/// edge length of the thumbnails in the list in pixels
+const THUMBNAIL_SIZE: u32 = 64;
+
 #[derive(Debug, Clone, PartialEq, Props)]
 pub struct FileElementProps {
     pub status: FileStatus,
     pub path: FilePath,
     pub size: u64,
+    #[props(!optional)]
+    pub lock: Option<proto::FileLock>,
+    pub favorite: bool,
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -40,6 +54,44 @@ pub fn FileElement(cx: Scope<FileElementProps>) -> Element {
         .to_owned();
     let size = byte_unit::Byte::from_bytes(cx.props.size.into()).get_appropriate_unit(true);
     let prompt_action = use_coroutine_handle::<HandleFileCommand>(cx).unwrap();
+    let lock = cx.props.lock.as_ref().map(lock_text);
+    // a lock held by another session can only be waited out
+    let lock_command = match &cx.props.lock {
+        None => Some((
+            HandleFileCommand::Lock(cx.props.path.clone()),
+            "fa-solid fa-lock-open",
+            "Lock",
+        )),
+        Some(lock) if lock.held_by_caller => Some((
+            HandleFileCommand::Unlock(cx.props.path.clone()),
+            "fa-solid fa-lock",
+            "Unlock",
+        )),
+        Some(_) => None,
+    };
+    let db_service = fermi::use_atom_state(cx, global_state::DATABASE_SERVICE);
+    let file_service = fermi::use_atom_state(cx, global_state::FILE_API_SERVICE);
+    // loaded again once the file has been synced
+    let thumbnail = use_future(
+        cx,
+        (&cx.props.path.to_rel_str(), &cx.props.status),
+        |(path, status)| {
+            let db_service = db_service.get().clone();
+            let file_service = file_service.get().clone();
+            async move {
+                match status {
+                    FileStatus::Success => load_thumbnail(db_service?, file_service?, &path).await,
+                    _ => None,
+                }
+            }
+        },
+    );
+    let thumbnail = thumbnail.value().cloned().flatten();
+    let favorite = cx.props.favorite;
+    let star_icon = match favorite {
+        true => "fa-solid fa-star text-yellow-400",
+        false => "fa-regular fa-star",
+    };
 
     cx.render(rsx! {
         li {
@@ -48,7 +100,18 @@ pub fn FileElement(cx: Scope<FileElementProps>) -> Element {
                 class: "flex items-center space-x-4",
                 div {
                     class: "flex-shrink-0 text-gray-500 text-2xl",
-                    i { class: "{icon}" }
+                    if let Some(thumbnail) = &thumbnail {
+                        rsx! {
+                            img {
+                                class: "w-8 h-8 object-cover rounded",
+                                src: "{thumbnail}",
+                            }
+                        }
+                    } else {
+                        rsx! {
+                            i { class: "{icon}" }
+                        }
+                    }
                 }
                 div {
                     class: "flex-1 min-w-0",
@@ -60,6 +123,61 @@ pub fn FileElement(cx: Scope<FileElementProps>) -> Element {
                         class: "text-sm text-gray-500 truncate dark:text-gray-400",
                         "{dir}"
                     }
+                    if let Some(lock) = &lock {
+                        rsx! {
+                            p {
+                                class: "text-sm text-yellow-600 truncate dark:text-yellow-400",
+                                i { class: "fa-solid fa-lock mr-1" }
+                                "{lock}"
+                            }
+                        }
+                    }
+                    div {
+                        class: "flex flex-wrap items-center gap-1 pt-1",
+                        cx.props.tags.iter().map(|tag| {
+                            let tag = tag.clone();
+                            rsx! {
+                                span {
+                                    key: "{tag}",
+                                    class: "inline-flex items-center text-xs font-medium px-2 py-0.5 rounded bg-blue-100 text-blue-800 dark:bg-blue-900 dark:text-blue-300",
+                                    "{tag}"
+                                    button {
+                                        onclick: move |_| prompt_action.send(HandleFileCommand::RemoveTag(cx.props.path.clone(), tag.clone())),
+                                        class: "ml-1 text-blue-400 hover:text-blue-900",
+                                        i { class: "fa-solid fa-xmark" }
+                                    }
+                                }
+                            }
+                        })
+                        form {
+                            onsubmit: move |e| {
+                                let tag = e.values.get("tag").map(|t| t.trim().to_owned()).unwrap_or_default();
+                                if !tag.is_empty() {
+                                    prompt_action.send(HandleFileCommand::AddTag(cx.props.path.clone(), tag));
+                                }
+                            },
+                            input {
+                                class: "bg-gray-50 border border-gray-300 text-gray-900 text-xs rounded w-24 px-1 py-0.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                name: "tag",
+                                placeholder: "Add tag",
+                            }
+                        }
+                    }
+                }
+                if let Some((command, lock_icon, lock_title)) = lock_command {
+                    rsx! {
+                        button {
+                            onclick: move |_| prompt_action.send(command.clone()),
+                            class: "text-gray-400 hover:text-yellow-600",
+                            title: "{lock_title}",
+                            i { class: "{lock_icon}" }
+                        }
+                    }
+                }
+                button {
+                    onclick: move |_| prompt_action.send(HandleFileCommand::SetFavorite(cx.props.path.clone(), !favorite)),
+                    class: "text-gray-400 hover:text-yellow-400",
+                    i { class: "{star_icon}" }
                 }
                 div {
                     class: "inline-flex items-center text-base font-semibold text-gray-900 dark:text-white",
@@ -92,6 +210,54 @@ pub fn FileElement(cx: Scope<FileElementProps>) -> Element {
     })
 }
 
+/// the thumbnail of a synced file as a data uri, `None` if it has none, the id of the server
+/// file is taken from the local database
+async fn load_thumbnail(
+    db_service: Arc<DatabaseService>,
+    file_service: Arc<Mutex<FileApiService>>,
+    path: &str,
+) -> Option<String> {
+    let sql_file = match db_service.find_file_by_path(path).await {
+        Ok(sql_file) => sql_file?,
+        Err(e) => {
+            tracing::debug!("failed to find synced file {} {:?}", path, e);
+            return None;
+        }
+    };
+
+    let thumbnail_res = file_service
+        .lock()
+        .await
+        .get_thumbnail(sql_file.id, THUMBNAIL_SIZE)
+        .await;
+
+    match thumbnail_res {
+        Ok(thumbnail) => {
+            thumbnail.map(|t| format!("data:{};base64,{}", t.mime_type, STANDARD.encode(t.data)))
+        }
+        Err(e) => {
+            tracing::debug!("failed to load thumbnail of {} {:?}", path, e);
+            None
+        }
+    }
+}
+
+fn lock_text(lock: &proto::FileLock) -> String {
+    let holder = match lock.held_by_caller {
+        true => "you",
+        false => lock.holder_name.as_str(),
+    };
+
+    let minutes = lock
+        .expires_at
+        .clone()
+        .and_then(|t| SystemTime::try_from(t).ok())
+        .and_then(|t| t.duration_since(SystemTime::now()).ok())
+        .map_or(0, |d| d.as_secs() / 60 + 1);
+
+    format!("Locked by {} for {} min", holder, minutes)
+}
+
 impl FileStatus {
     pub fn to_icon(&self) -> String {
         match *self {
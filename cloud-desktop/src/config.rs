@@ -4,12 +4,22 @@ use serde::{Deserialize, Serialize};
 use tokio::fs;
 
 const CONFIG_FILE_NAME: &str = "config.json";
+/// files at least this large are uploaded in parts over several streams
+pub const DEFAULT_MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Configuration {
     pub url: Option<String>,
     pub credentials: Option<Credentials>,
     pub sync_dir: Option<String>,
+    #[serde(default)]
+    pub e2e: Option<E2eConfiguration>,
+    #[serde(default = "default_multipart_threshold")]
+    pub multipart_threshold: u64,
+}
+
+fn default_multipart_threshold() -> u64 {
+    DEFAULT_MULTIPART_THRESHOLD
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +28,15 @@ pub struct Credentials {
     pub password: String,
 }
 
+/// Files are encrypted with a key derived from a passphrase before they are uploaded.
+///
+/// The passphrase is not saved, it is entered on every start and only the keys derived from
+/// it are kept in memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct E2eConfiguration {
+    pub encrypt_names: bool,
+}
+
 pub async fn read_conf() -> Result<Configuration, anyhow::Error> {
     let conf_path = Path::new(CONFIG_FILE_NAME);
 
@@ -31,6 +50,8 @@ pub async fn read_conf() -> Result<Configuration, anyhow::Error> {
         url: None,
         credentials: None,
         sync_dir: None,
+        e2e: None,
+        multipart_threshold: DEFAULT_MULTIPART_THRESHOLD,
     };
     write_conf(&conf).await?;
     Ok(conf)
@@ -2,13 +2,16 @@
 use dioxus::prelude::*;
 use dioxus_router::Router;
 
+pub mod chunking;
 pub mod components;
 pub mod config;
 pub mod context;
 pub mod global_state;
+pub mod outboard;
 pub mod path_helper;
 pub mod routes;
 pub mod services;
+pub mod sso;
 
 fn main() {
     tracing_subscriber::fmt::init();
@@ -0,0 +1,142 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// bytes of a download that are verified at once, a multiple of the bao chunk size
+pub const RANGE_SIZE: usize = 1024 * 1024;
+
+/// The bao outboard tree of a server file, which verifies ranges of the content against the
+/// file hash before the whole file has been downloaded.
+///
+/// The tree is kept in a temp file next to the download and removed when it is dropped.
+#[derive(Debug)]
+pub struct Outboard {
+    path: PathBuf,
+    file: std::fs::File,
+    hash: blake3::Hash,
+}
+
+impl Outboard {
+    /// opens the tree written to `path` for the content with `hash`
+    pub fn open<P>(path: P, hash: &str) -> Result<Self, anyhow::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file: std::fs::File::open(&path)?,
+            hash: blake3::Hash::from_hex(hash)?,
+        })
+    }
+
+    /// returns whether `data` is the content at `offset`, `offset` has to be a multiple of
+    /// [`RANGE_SIZE`] and `data` must only be shorter than that at the end of the content
+    pub fn verify(&mut self, offset: u64, data: &[u8]) -> Result<bool, anyhow::Error> {
+        self.file.rewind()?;
+
+        let content = RangeReader {
+            offset,
+            data,
+            position: 0,
+        };
+        let mut slice = Vec::new();
+
+        // content outside of the range is never read, a short range fails the extraction
+        let extracted = bao::encode::SliceExtractor::new_outboard(
+            content,
+            &mut self.file,
+            offset,
+            data.len() as u64,
+        )
+        .read_to_end(&mut slice);
+
+        if extracted.is_err() {
+            return Ok(false);
+        }
+
+        let mut decoded = Vec::with_capacity(data.len());
+        let verified =
+            bao::decode::SliceDecoder::new(&slice[..], &self.hash, offset, data.len() as u64)
+                .read_to_end(&mut decoded);
+
+        Ok(verified.is_ok() && decoded == data)
+    }
+}
+
+impl Drop for Outboard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("failed to remove outboard {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Reads a range of the content as if it was the whole content, positions outside of the
+/// range can be seeked to but not read.
+struct RangeReader<'a> {
+    offset: u64,
+    data: &'a [u8],
+    position: u64,
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = self
+            .position
+            .checked_sub(self.offset)
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        let rest = self.data.get(start as usize..).unwrap_or_default();
+
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for RangeReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Start(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(std::io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::outboard::{self, Outboard};
+
+    #[test]
+    fn verify_detects_corrupted_ranges() {
+        let content = (0..outboard::RANGE_SIZE * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let (tree, hash) = bao::encode::outboard(&content);
+
+        let path = std::env::temp_dir().join(format!("cloud-outboard-{}", std::process::id()));
+        std::fs::write(&path, tree).unwrap();
+
+        let mut outboard = Outboard::open(&path, &hash.to_hex()).unwrap();
+
+        for (i, range) in content.chunks(outboard::RANGE_SIZE).enumerate() {
+            let offset = (i * outboard::RANGE_SIZE) as u64;
+            assert!(outboard.verify(offset, range).unwrap());
+
+            let mut corrupted = range.to_vec();
+            corrupted[range.len() / 2] ^= 1;
+            assert!(!outboard.verify(offset, &corrupted).unwrap());
+        }
+
+        // a range that ends early is not the content
+        assert!(!outboard.verify(0, &content[..1000]).unwrap());
+
+        drop(outboard);
+        assert!(!path.exists());
+    }
+}
@@ -1,5 +1,11 @@
-use std::path::{Path, PathBuf};
-
+#[cfg(unix)]
+use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use cloud_proto::{prost_types::Timestamp, proto};
 use futures::StreamExt;
 use tokio::fs;
 use tokio_util::io::ReaderStream;
@@ -102,9 +108,53 @@ pub async fn read_file_meta(path: &PathBuf) -> Result<(String, u64), anyhow::Err
     Ok((hash, size as u64))
 }
 
+/// the modification time and permission bits of a local file, they are uploaded with the
+/// file and restored when it is downloaded
+pub async fn read_file_attributes(
+    path: &Path,
+) -> Result<(Option<Timestamp>, Option<u32>), anyhow::Error> {
+    let metadata = fs::metadata(path).await?;
+    let mtime = metadata.modified().ok().map(Timestamp::from);
+
+    #[cfg(unix)]
+    let mode = Some(metadata.permissions().mode() & 0o777);
+    #[cfg(not(unix))]
+    let mode = None;
+
+    Ok((mtime, mode))
+}
+
+/// restores the attributes the uploading client reported, permissions are only restored on
+/// unix and never include the setuid, setgid or sticky bits
+pub async fn write_file_attributes(
+    path: &Path,
+    api_file: &proto::File,
+) -> Result<(), anyhow::Error> {
+    // the mtime is set first, the permissions may not allow to open the file for writing
+    if let Some(mtime) = api_file.mtime.clone() {
+        let mtime = SystemTime::try_from(mtime)?;
+        let fs_file = fs::File::options().write(true).open(path).await?;
+        let fs_file = fs_file.into_std().await;
+
+        tokio::task::spawn_blocking(move || fs_file.set_modified(mtime)).await??;
+    }
+
+    #[cfg(unix)]
+    if let Some(mode) = api_file.mode {
+        fs::set_permissions(path, Permissions::from_mode(mode & 0o777)).await?;
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::path::Path;
+    use std::{
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use cloud_proto::proto;
 
     use crate::path_helper;
 
@@ -125,6 +175,30 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn file_attributes_round_trip() {
+        let path = std::env::temp_dir().join(format!("cloud-attributes-{}", std::process::id()));
+        tokio::fs::write(&path, b"test").await.unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let api_file = proto::File {
+            mtime: Some(mtime.into()),
+            mode: Some(0o640),
+            ..Default::default()
+        };
+
+        path_helper::write_file_attributes(&path, &api_file)
+            .await
+            .unwrap();
+        let (read_mtime, read_mode) = path_helper::read_file_attributes(&path).await.unwrap();
+
+        assert_eq!(api_file.mtime, read_mtime);
+        #[cfg(unix)]
+        assert_eq!(Some(0o640), read_mode);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
     #[test]
     fn rel_to_abs() {
         let sync_dir = Path::new("/home/test/cloud").to_path_buf();
@@ -0,0 +1,184 @@
+use std::{sync::Arc, time::SystemTime};
+
+use cloud_proto::proto;
+use dioxus::prelude::*;
+use dioxus_router::Link;
+use tokio::sync::Mutex;
+
+use crate::{global_state, services::api_service::FileApiService};
+
+/// Lists the recent uploads, replacements, deletions and moves of the user on all devices.
+pub fn Activity(cx: Scope) -> Element {
+    let file_service = fermi::use_atom_state(cx, global_state::FILE_API_SERVICE);
+    let activities = use_state(cx, || None::<Vec<proto::Activity>>);
+    // empty once the last page has been loaded
+    let next_page_token = use_state(cx, String::new);
+    let message = use_state(cx, String::new);
+
+    use_future(cx, (), |_| {
+        let file_service = file_service.get().as_ref().unwrap().clone();
+        load_page(
+            file_service,
+            activities.clone(),
+            next_page_token.clone(),
+            message.clone(),
+        )
+    });
+
+    let has_more = !next_page_token.is_empty();
+
+    cx.render(rsx! {
+        div {
+            class: "w-full h-full p-4 bg-white sm:p-8 dark:bg-gray-800",
+            div {
+                class: "flex items-center justify-between mb-4",
+                h5 {
+                    class: "text-xl font-medium text-gray-900 dark:text-white",
+                    "Recent activity"
+                }
+                Link {
+                    to: "/files",
+                    class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                    "Back to files"
+                }
+            }
+            p {
+                class: "text-red-800 text-sm",
+                "{message}"
+            }
+            if activities.is_none() {
+                rsx! {
+                    p {
+                        class: "text-sm text-gray-500 dark:text-gray-400",
+                        "Loading..."
+                    }
+                }
+            }
+            if activities.as_ref().is_some_and(|a| a.is_empty()) {
+                rsx! {
+                    p {
+                        class: "text-sm text-gray-500 dark:text-gray-400",
+                        "No activity yet"
+                    }
+                }
+            }
+            ul {
+                role: "list",
+                class: "divide-y divide-gray-200 dark:divide-gray-700",
+                activities.get().iter().flatten().map(|activity| {
+                    let id = &activity.id;
+                    let (icon, action) = kind_text(activity.kind);
+                    let path = &activity.path;
+                    let when = time_text(activity);
+                    let moved_from = activity.old_path.as_ref().map(|old_path| rsx! {
+                        p {
+                            class: "text-sm text-gray-500 truncate dark:text-gray-400",
+                            "Moved from {old_path}"
+                        }
+                    });
+
+                    rsx! {
+                        li {
+                            key: "{id}",
+                            class: "py-3 sm:py-4",
+                            div {
+                                class: "flex items-center space-x-4",
+                                i { class: "{icon} text-gray-500 dark:text-gray-400" }
+                                div {
+                                    class: "flex-1 min-w-0",
+                                    p {
+                                        class: "text-sm font-medium text-gray-900 truncate dark:text-white",
+                                        "{path}"
+                                    }
+                                    moved_from
+                                }
+                                div {
+                                    class: "text-sm text-gray-500 dark:text-gray-400",
+                                    "{action} {when}"
+                                }
+                            }
+                        }
+                    }
+                })
+            }
+            if has_more {
+                rsx! {
+                    button {
+                        onclick: move |_| {
+                            let file_service = file_service.get().as_ref().unwrap().clone();
+                            cx.spawn(load_page(
+                                file_service,
+                                activities.clone(),
+                                next_page_token.clone(),
+                                message.clone(),
+                            ));
+                        },
+                        class: "mt-4 text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                        "Load more"
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// appends the page following `next_page_token` to the loaded activities
+async fn load_page(
+    file_service: Arc<Mutex<FileApiService>>,
+    activities: UseState<Option<Vec<proto::Activity>>>,
+    next_page_token: UseState<String>,
+    message: UseState<String>,
+) {
+    let page_res = file_service
+        .lock()
+        .await
+        .list_activity(next_page_token.get().to_owned())
+        .await;
+
+    match page_res {
+        Ok((page, page_token)) => {
+            let mut loaded = activities.get().clone().unwrap_or_default();
+            loaded.extend(page);
+
+            activities.set(Some(loaded));
+            next_page_token.set(page_token);
+        }
+        Err(e) => {
+            tracing::error!("failed to list activity {:?}", e);
+            message.set(e.to_string());
+        }
+    }
+}
+
+/// the icon and the past tense of the kind of an activity
+fn kind_text(kind: i32) -> (&'static str, &'static str) {
+    match proto::ActivityKind::from_i32(kind) {
+        Some(proto::ActivityKind::Upload) => ("fa-solid fa-upload", "Uploaded"),
+        Some(proto::ActivityKind::Replace) => ("fa-solid fa-pen", "Replaced"),
+        Some(proto::ActivityKind::Delete) => ("fa-solid fa-trash", "Deleted"),
+        Some(proto::ActivityKind::Move) => ("fa-solid fa-arrow-right", "Moved"),
+        None => ("fa-solid fa-question", "Changed"),
+    }
+}
+
+/// how long ago the activity occurred and whether it was on this device
+fn time_text(activity: &proto::Activity) -> String {
+    let seconds = activity
+        .occurred_at
+        .clone()
+        .and_then(|t| SystemTime::try_from(t).ok())
+        .and_then(|t| SystemTime::now().duration_since(t).ok())
+        .map_or(0, |d| d.as_secs());
+
+    let ago = match seconds {
+        0..=59 => "just now".to_owned(),
+        60..=3599 => format!("{} min ago", seconds / 60),
+        3600..=86399 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    };
+
+    match activity.by_caller_session {
+        true => format!("{} on this device", ago),
+        false => ago,
+    }
+}
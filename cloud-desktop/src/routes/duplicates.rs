@@ -0,0 +1,235 @@
+use std::{collections::HashSet, sync::Arc};
+
+use cloud_proto::proto;
+use dioxus::prelude::*;
+use dioxus_router::Link;
+use tokio::sync::Mutex;
+
+use crate::{global_state, services::api_service::FileApiService};
+
+/// Lists the server files with the same content and deletes the selected copies, the
+/// deleted copies are removed from the sync dir on the next refresh.
+pub fn Duplicates(cx: Scope) -> Element {
+    let file_service = fermi::use_atom_state(cx, global_state::FILE_API_SERVICE);
+    let groups = use_state(cx, || None::<Vec<proto::DuplicateGroup>>);
+    // ids of the copies to delete
+    let selected = use_state(cx, HashSet::<String>::new);
+    let message = use_state(cx, String::new);
+
+    use_future(cx, (), |_| {
+        let file_service = file_service.get().as_ref().unwrap().clone();
+        load_duplicates(
+            file_service,
+            groups.clone(),
+            selected.clone(),
+            message.clone(),
+        )
+    });
+
+    let wasted = groups
+        .get()
+        .iter()
+        .flatten()
+        .map(|g| g.wasted_bytes)
+        .sum::<u64>();
+    let wasted = byte_unit::Byte::from_bytes(wasted.into()).get_appropriate_unit(true);
+    let freed = groups
+        .get()
+        .iter()
+        .flatten()
+        .flat_map(|g| g.files.iter())
+        .filter(|f| selected.contains(&f.id))
+        .map(|f| f.size)
+        .sum::<u64>();
+    let freed = byte_unit::Byte::from_bytes(freed.into()).get_appropriate_unit(true);
+    let selected_count = selected.len();
+    let nothing_selected = selected_count == 0;
+
+    cx.render(rsx! {
+        div {
+            class: "w-full h-full p-4 bg-white sm:p-8 dark:bg-gray-800",
+            div {
+                class: "flex items-center justify-between mb-4",
+                div {
+                    class: "flex-1 min-w-0",
+                    h5 {
+                        class: "text-xl font-medium text-gray-900 dark:text-white",
+                        "Duplicates"
+                    },
+                    p {
+                        class: "text-sm text-gray-500 truncate dark:text-gray-400",
+                        "{wasted} used by extra copies"
+                    }
+                }
+                Link {
+                    to: "/files",
+                    class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                    "Back to files"
+                }
+            }
+            div {
+                class: "flex items-center space-x-4 mb-4",
+                button {
+                    disabled: "{nothing_selected}",
+                    onclick: move |_| {
+                        let file_service = file_service.get().as_ref().unwrap().clone();
+                        cx.spawn(delete_selected(
+                            file_service,
+                            groups.clone(),
+                            selected.clone(),
+                            message.clone(),
+                        ));
+                    },
+                    class: "text-white bg-red-700 hover:bg-red-800 focus:ring-4 focus:outline-none focus:ring-red-300 font-medium rounded-lg text-sm px-5 py-2.5 text-center dark:bg-red-600 dark:hover:bg-red-700 dark:focus:ring-red-800",
+                    "Delete {selected_count} copies ({freed})"
+                }
+                p {
+                    class: "text-sm text-gray-500 dark:text-gray-400",
+                    "{message}"
+                }
+            }
+            if groups.is_none() {
+                rsx! {
+                    p {
+                        class: "text-sm text-gray-500 dark:text-gray-400",
+                        "Looking for duplicates..."
+                    }
+                }
+            }
+            if groups.as_ref().is_some_and(|g| g.is_empty()) {
+                rsx! {
+                    p {
+                        class: "text-sm text-gray-500 dark:text-gray-400",
+                        "No duplicates found"
+                    }
+                }
+            }
+            ul {
+                role: "list",
+                class: "divide-y divide-gray-200 dark:divide-gray-700",
+                groups.get().iter().flatten().map(|group| {
+                    let hash = &group.hash;
+                    let copies = group.files.len();
+                    let size = byte_unit::Byte::from_bytes(group.size.into())
+                        .get_appropriate_unit(true);
+
+                    rsx! {
+                        li {
+                            key: "{hash}",
+                            class: "py-3 sm:py-4",
+                            p {
+                                class: "text-sm font-semibold text-gray-900 dark:text-white",
+                                "{copies} copies of {size}"
+                            }
+                            group.files.iter().map(|file| {
+                                let id = &file.id;
+                                let path = &file.path;
+                                let checked = selected.contains(id);
+
+                                rsx! {
+                                    label {
+                                        key: "{id}",
+                                        class: "flex items-center space-x-2 pt-1 text-sm text-gray-500 dark:text-gray-400",
+                                        input {
+                                            r#type: "checkbox",
+                                            checked: "{checked}",
+                                            onclick: move |_| toggle(selected, group, id),
+                                        }
+                                        span { class: "truncate", "{path}" }
+                                    }
+                                }
+                            })
+                        }
+                    }
+                })
+            }
+        }
+    })
+}
+
+/// selects all copies but the oldest of each group
+async fn load_duplicates(
+    file_service: Arc<Mutex<FileApiService>>,
+    groups: UseState<Option<Vec<proto::DuplicateGroup>>>,
+    selected: UseState<HashSet<String>>,
+    message: UseState<String>,
+) {
+    let duplicates_res = file_service.lock().await.get_duplicates().await;
+
+    match duplicates_res {
+        Ok(duplicates) => {
+            let extra_copies = duplicates
+                .iter()
+                .flat_map(|g| g.files.iter().skip(1))
+                .map(|f| f.id.to_owned())
+                .collect();
+
+            selected.set(extra_copies);
+            groups.set(Some(duplicates));
+        }
+        Err(e) => {
+            tracing::error!("failed to get duplicates {:?}", e);
+            message.set(e.to_string());
+        }
+    }
+}
+
+/// one copy of each group is always kept
+fn toggle(selected: &UseState<HashSet<String>>, group: &proto::DuplicateGroup, id: &str) {
+    let mut ids = selected.get().clone();
+
+    if !ids.remove(id) {
+        let kept = group.files.iter().filter(|f| !ids.contains(&f.id)).count();
+
+        if kept > 1 {
+            ids.insert(id.to_owned());
+        }
+    }
+
+    selected.set(ids);
+}
+
+/// copies that changed since the duplicates were loaded are not deleted
+async fn delete_selected(
+    file_service: Arc<Mutex<FileApiService>>,
+    groups: UseState<Option<Vec<proto::DuplicateGroup>>>,
+    selected: UseState<HashSet<String>>,
+    message: UseState<String>,
+) {
+    let requests = groups
+        .get()
+        .iter()
+        .flatten()
+        .flat_map(|g| g.files.iter().map(move |f| (g, f)))
+        .filter(|(_, f)| selected.contains(&f.id))
+        .map(|(g, f)| proto::DeleteFileRequest {
+            id: f.id.to_owned(),
+            expected_hash: Some(g.hash.to_owned()),
+        })
+        .collect::<Vec<_>>();
+
+    let delete_res = file_service.lock().await.delete_files(requests).await;
+
+    match delete_res {
+        Ok(results) => {
+            let failed = results.iter().filter(|r| r.is_err()).count();
+
+            for e in results.into_iter().filter_map(Result::err) {
+                tracing::error!("failed to delete duplicate {:?}", e);
+            }
+
+            message.set(match failed {
+                0 => "Deleted, the copies are removed from this device on the next refresh"
+                    .to_owned(),
+                failed => format!("{} copies could not be deleted", failed),
+            });
+        }
+        Err(e) => {
+            tracing::error!("failed to delete duplicates {:?}", e);
+            message.set(e.to_string());
+            return;
+        }
+    }
+
+    load_duplicates(file_service, groups, selected, message).await;
+}
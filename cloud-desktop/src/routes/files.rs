@@ -1,9 +1,9 @@
 use std::{cmp::Ordering, collections::BTreeMap, path::Path, sync::Arc};
 
-use anyhow::anyhow;
 use cloud_proto::proto;
 use dioxus::prelude::*;
-use fermi::UseAtomRef;
+use dioxus_router::Link;
+use fermi::{UseAtomRef, UseAtomState};
 use futures::StreamExt;
 use tokio::{fs, sync::Mutex};
 use walkdir::WalkDir;
@@ -13,7 +13,7 @@ use crate::{
     global_state,
     path_helper::{self, FilePath},
     services::{
-        api_service::{FileApiService, UserApiService},
+        api_service::{FileApiService, FileConflict, UserApiService},
         database_service::{DatabaseService, DbFile},
     },
 };
@@ -24,6 +24,19 @@ pub enum HandleFileCommand {
     Skip(FilePath),
     KeepLocal(FilePromptKeep),
     KeepRemote(FilePromptKeep),
+    SetFavorite(FilePath, bool),
+    AddTag(FilePath, String),
+    RemoveTag(FilePath, String),
+    Lock(FilePath),
+    Unlock(FilePath),
+}
+
+/// A server file whose local file has been deleted, the server files are deleted together
+/// once all files have been synced.
+#[derive(Debug)]
+struct PendingDelete {
+    file_path: FilePath,
+    sql_file: DbFile,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -39,6 +52,8 @@ pub fn Files(cx: Scope) -> Element {
         .as_ref()
         .unwrap();
     let storage_space = use_state(cx, || "".to_string());
+    let search_results = use_state(cx, || None::<Vec<proto::File>>);
+    let search_error = use_state(cx, String::new);
     let db_service = fermi::use_atom_state(cx, global_state::DATABASE_SERVICE);
     let user_service = fermi::use_atom_state(cx, global_state::USER_API_SERVICE);
     let file_service = fermi::use_atom_state(cx, global_state::FILE_API_SERVICE);
@@ -65,6 +80,25 @@ pub fn Files(cx: Scope) -> Element {
         (_, _) => Ordering::Equal,
     });
 
+    // search results are shown instead of the synced files until the search is cleared
+    let search_files = search_results.get().as_ref().map(|results| {
+        results
+            .iter()
+            .map(|f| FileElementProps {
+                status: FileStatus::Success,
+                path: FilePath::from_rel(sync_dir, &f.path),
+                size: f.size,
+                lock: None,
+                favorite: f.favorite,
+                tags: f.tags.clone(),
+            })
+            .collect::<Vec<_>>()
+    });
+    let files = match &search_files {
+        Some(search_files) => search_files.iter().collect(),
+        None => files,
+    };
+
     cx.render(rsx! {
         div {
             class: "w-full h-full p-4 bg-white sm:p-8 dark:bg-gray-800",
@@ -81,15 +115,61 @@ pub fn Files(cx: Scope) -> Element {
                         "{storage_space}"
                     }
                 }
+                div {
+                    class: "flex items-center space-x-4",
+                    Link {
+                        to: "/activity",
+                        class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                        "Activity"
+                    }
+                    Link {
+                        to: "/usage",
+                        class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                        "Usage"
+                    }
+                    Link {
+                        to: "/duplicates",
+                        class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                        "Duplicates"
+                    }
+                    button {
+                        onclick: |_| {
+                            coroutine_handle.send(
+                                HandleFileCommand::Refresh
+                            )
+                        },
+                        class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                        "Refresh"
+                    }
+                }
+            }
+            form {
+                onsubmit: move |e| { on_submit_search(cx, file_service, search_results, search_error, e) },
+                class: "flex items-center space-x-2 mb-4",
+                input {
+                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-blue-500 focus:border-blue-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                    name: "query",
+                    placeholder: "Search files on the server",
+                }
                 button {
-                    onclick: |_| {
-                        coroutine_handle.send(
-                            HandleFileCommand::Refresh
-                        )
-                    },
-                    class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
-                    "Refresh"
+                    r#type: "submit",
+                    class: "text-white bg-blue-700 hover:bg-blue-800 focus:ring-4 focus:outline-none focus:ring-blue-300 font-medium rounded-lg text-sm px-5 py-2.5 text-center dark:bg-blue-600 dark:hover:bg-blue-700 dark:focus:ring-blue-800",
+                    "Search"
                 }
+                if search_results.is_some() {
+                    rsx! {
+                        button {
+                            r#type: "button",
+                            onclick: |_| search_results.set(None),
+                            class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                            "Clear"
+                        }
+                    }
+                }
+            }
+            div {
+                class: "text-red-800 text-sm pb-3",
+                "{search_error}"
             }
             div {
                 class: "flow-root",
@@ -102,6 +182,9 @@ pub fn Files(cx: Scope) -> Element {
                                 status: v.status.clone(),
                                 path: v.path.clone(),
                                 size: v.size,
+                                lock: v.lock.clone(),
+                                favorite: v.favorite,
+                                tags: v.tags.clone(),
                             }
                         }
                     )
@@ -111,6 +194,38 @@ pub fn Files(cx: Scope) -> Element {
     })
 }
 
+fn on_submit_search(
+    cx: Scope,
+    file_service: &UseAtomState<Option<Arc<Mutex<FileApiService>>>>,
+    search_results: &UseState<Option<Vec<proto::File>>>,
+    search_error: &UseState<String>,
+    event: Event<FormData>,
+) {
+    let query = event.values.get("query").cloned().unwrap_or_default();
+    search_error.set(String::new());
+
+    if query.is_empty() {
+        search_results.set(None);
+        return;
+    }
+
+    let file_service = file_service.get().as_ref().unwrap().clone();
+    let search_results = search_results.clone();
+    let search_error = search_error.clone();
+
+    cx.spawn(async move {
+        let search_res = file_service.lock().await.search_files(&query).await;
+
+        match search_res {
+            Ok(files) => search_results.set(Some(files)),
+            Err(e) => {
+                tracing::error!("failed to search files {:?}", e);
+                search_error.set(e.to_string());
+            }
+        }
+    });
+}
+
 async fn handle_file_coroutine<P>(
     mut rx: UnboundedReceiver<HandleFileCommand>,
     db_service: Arc<DatabaseService>,
@@ -162,12 +277,21 @@ async fn handle_file_coroutine<P>(
                     }
                 }
 
-                let uploaded_file =
-                    upload_file(&mut file_service, &keep.file_path, &keep.local_meta).await;
+                // only replace the server file the user has decided on
+                let uploaded_file = upload_file(
+                    &mut file_service,
+                    &keep.file_path,
+                    &keep.local_meta,
+                    Some(keep.remote_file.hash.to_owned()),
+                )
+                .await;
 
                 match uploaded_file {
                     Ok(uploaded_file) => {
-                        if let Err(e) = db_service.add_file(&uploaded_file).await {
+                        if let Err(e) = db_service
+                            .add_file(&uploaded_file, &keep.local_meta.0)
+                            .await
+                        {
                             tracing::error!("failed to add sql entry {:?}", e);
                             let mut files = files.write();
                             let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
@@ -176,11 +300,20 @@ async fn handle_file_coroutine<P>(
                         }
                     }
                     Err(e) => {
-                        tracing::error!("failed to upload file {:?}", e);
+                        let status =
+                            conflict_status(e, &keep.file_path, keep.local_meta.to_owned(), None);
+
                         let mut files = files.write();
                         let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
-                        props.status = FileStatus::Failed;
-                        return;
+
+                        match status {
+                            Ok(status) => props.status = status,
+                            Err(e) => {
+                                tracing::error!("failed to upload file {:?}", e);
+                                props.status = FileStatus::Failed;
+                            }
+                        }
+                        continue;
                     }
                 }
 
@@ -198,7 +331,7 @@ async fn handle_file_coroutine<P>(
                     props.status = FileStatus::WaitingQueue;
                 }
 
-                if let Some(sql_file) = keep.sql_file {
+                if let Some(sql_file) = &keep.sql_file {
                     if let Err(e) = db_service.delete_file_by_id(sql_file.id.to_owned()).await {
                         tracing::error!("failed to delete sql entry {:?}", e);
                         let mut files = files.write();
@@ -216,21 +349,25 @@ async fn handle_file_coroutine<P>(
                     return;
                 }
 
-                if let Err(e) = download_file(
+                let hash = match download_file(
                     &mut file_service,
                     keep.file_path.get_sync_dir(),
                     &keep.remote_file,
+                    keep.sql_file.as_ref(),
                 )
                 .await
                 {
-                    tracing::error!("failed to download file {:?}", e);
-                    let mut files = files.write();
-                    let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
-                    props.status = FileStatus::Failed;
-                    return;
-                }
+                    Ok(hash) => hash,
+                    Err(e) => {
+                        tracing::error!("failed to download file {:?}", e);
+                        let mut files = files.write();
+                        let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
+                        props.status = FileStatus::Failed;
+                        return;
+                    }
+                };
 
-                if let Err(e) = db_service.add_file(&keep.remote_file).await {
+                if let Err(e) = db_service.add_file(&keep.remote_file, &hash).await {
                     tracing::error!("failed to add sql entry {:?}", e);
                     let mut files = files.write();
                     let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
@@ -242,10 +379,83 @@ async fn handle_file_coroutine<P>(
                 let props = files.get_mut(&keep.file_path.to_rel_str()).unwrap();
                 props.status = FileStatus::Success;
             }
+            HandleFileCommand::SetFavorite(path, favorite) => {
+                let path_str = path.to_rel_str();
+                let favorite_res = file_service
+                    .lock()
+                    .await
+                    .set_favorite(&path_str, favorite)
+                    .await;
+
+                match favorite_res {
+                    Ok(api_file) => show_labels(&files, &path_str, api_file),
+                    Err(e) => tracing::error!("failed to set favorite {:?}", e),
+                }
+            }
+            HandleFileCommand::AddTag(path, tag) => {
+                let path_str = path.to_rel_str();
+                let tag_res = file_service.lock().await.add_tag(&path_str, &tag).await;
+
+                match tag_res {
+                    Ok(api_file) => show_labels(&files, &path_str, api_file),
+                    Err(e) => tracing::error!("failed to add tag {:?}", e),
+                }
+            }
+            HandleFileCommand::RemoveTag(path, tag) => {
+                let path_str = path.to_rel_str();
+                let tag_res = file_service.lock().await.remove_tag(&path_str, &tag).await;
+
+                match tag_res {
+                    Ok(api_file) => show_labels(&files, &path_str, api_file),
+                    Err(e) => tracing::error!("failed to remove tag {:?}", e),
+                }
+            }
+            HandleFileCommand::Lock(path) => {
+                let path_str = path.to_rel_str();
+                let lock_res = file_service.lock().await.lock_file(&path_str).await;
+
+                match lock_res {
+                    Ok(lock) => show_lock(&files, &path_str, Some(lock)),
+                    Err(e) => tracing::error!("failed to lock file {:?}", e),
+                }
+            }
+            HandleFileCommand::Unlock(path) => {
+                let path_str = path.to_rel_str();
+                let unlock_res = file_service.lock().await.unlock_file(&path_str).await;
+
+                match unlock_res {
+                    Ok(()) => show_lock(&files, &path_str, None),
+                    Err(e) => tracing::error!("failed to unlock file {:?}", e),
+                }
+            }
         }
     }
 }
 
+/// shows the favorite flag and tags of a server file after they were changed
+fn show_labels(
+    files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
+    path: &str,
+    api_file: proto::File,
+) {
+    let mut files = files.write();
+
+    if let Some(props) = files.get_mut(path) {
+        props.favorite = api_file.favorite;
+        props.tags = api_file.tags;
+    }
+}
+
+fn show_lock(
+    files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
+    path: &str,
+    lock: Option<proto::FileLock>,
+) {
+    if let Some(props) = files.write().get_mut(path) {
+        props.lock = lock;
+    }
+}
+
 async fn on_refresh<P>(
     db_service: &DatabaseService,
     user_service: &mut UserApiService,
@@ -258,12 +468,22 @@ async fn on_refresh<P>(
 {
     files.write().clear();
 
-    sync_local_files(db_service, file_service, files, &sync_dir)
-        .await
-        .ok();
-    sync_api_files(db_service, file_service, files, &sync_dir)
-        .await
-        .ok();
+    let mut deletes = Vec::new();
+
+    if let Err(e) = sync_local_files(db_service, file_service, files, &sync_dir, &mut deletes).await
+    {
+        tracing::error!("failed to sync local files {:?}", e);
+    }
+
+    if let Err(e) = sync_api_files(db_service, file_service, files, &sync_dir, &mut deletes).await {
+        tracing::error!("failed to sync api files {:?}", e);
+    }
+
+    delete_api_files(db_service, file_service, files, deletes).await;
+
+    if let Err(e) = show_locks(file_service, files).await {
+        tracing::error!("failed to get file locks {:?}", e);
+    }
 
     match user_service.get_self().await {
         Ok(u) => {
@@ -284,16 +504,20 @@ async fn on_refresh<P>(
     }
 }
 
+/// syncs the files in the sync dir, their server files are looked up with a few batch
+/// requests up front
 async fn sync_local_files<P>(
     db_service: &DatabaseService,
     file_service: &mut FileApiService,
     files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
     sync_dir: P,
+    deletes: &mut Vec<PendingDelete>,
 ) -> Result<(), anyhow::Error>
 where
     P: AsRef<Path>,
 {
     let walk_dir = WalkDir::new(&sync_dir);
+    let mut local_files = Vec::new();
 
     for entry in walk_dir.into_iter() {
         if let Err(e) = &entry {
@@ -314,9 +538,28 @@ where
         }
 
         let size = metadata.unwrap().len();
-        let file_path = FilePath::from_abs(&sync_dir, entry_path);
+        local_files.push((FilePath::from_abs(&sync_dir, entry_path), size));
+    }
 
-        process_path(db_service, file_service, files, file_path, size).await;
+    let paths = local_files
+        .iter()
+        .map(|(file_path, _)| file_path.to_rel_str())
+        .collect::<Vec<_>>();
+    let mut remote_files = file_service.find_files(&paths).await?;
+
+    for (file_path, size) in local_files {
+        let remote_file = remote_files.remove(&file_path.to_rel_str());
+
+        process_path(
+            db_service,
+            file_service,
+            files,
+            file_path,
+            size,
+            remote_file,
+            deletes,
+        )
+        .await;
     }
 
     Ok(())
@@ -327,25 +570,160 @@ async fn sync_api_files<P>(
     file_service: &mut FileApiService,
     files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
     sync_dir: P,
+    deletes: &mut Vec<PendingDelete>,
 ) -> Result<(), anyhow::Error>
 where
     P: AsRef<Path>,
 {
-    let mut get_resp = file_service.get_client().get_all(()).await?.into_inner();
+    let (api_files, undecryptable) = file_service.get_all_files().await?;
+
+    // shown as failed rather than left out, which would look like they are not on the server
+    for path in undecryptable {
+        files.write().insert(
+            path.to_owned(),
+            FileElementProps {
+                status: FileStatus::Failed,
+                path: FilePath::from_rel(&sync_dir, &path),
+                size: 0,
+                lock: None,
+                favorite: false,
+                tags: Vec::new(),
+            },
+        );
+    }
 
-    while let Some(Ok(api_file)) = get_resp.next().await {
+    for api_file in api_files {
         let file_path = FilePath::from_rel(&sync_dir, &api_file.path);
-        process_path(db_service, file_service, files, file_path, api_file.size).await;
+        let size = api_file.size;
+
+        process_path(
+            db_service,
+            file_service,
+            files,
+            file_path,
+            size,
+            Some(api_file),
+            deletes,
+        )
+        .await;
     }
 
     Ok(())
 }
+
+/// deletes the server files of deleted local files with a few batch requests
+async fn delete_api_files(
+    db_service: &DatabaseService,
+    file_service: &mut FileApiService,
+    files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
+    deletes: Vec<PendingDelete>,
+) {
+    if deletes.is_empty() {
+        return;
+    }
+
+    let requests = deletes
+        .iter()
+        .map(|d| proto::DeleteFileRequest {
+            id: d.sql_file.id.to_owned(),
+            expected_hash: Some(d.sql_file.remote_hash.to_owned()),
+        })
+        .collect();
+
+    let results = match file_service.delete_files(requests).await {
+        Ok(results) => results,
+        Err(e) => {
+            tracing::error!("failed to delete api files {:?}", e);
+            let mut files = files.write();
+
+            for delete in deletes {
+                if let Some(props) = files.get_mut(&delete.file_path.to_rel_str()) {
+                    props.status = FileStatus::Failed;
+                }
+            }
+            return;
+        }
+    };
+
+    for (delete, delete_res) in deletes.into_iter().zip(results) {
+        let rel_path = delete.file_path.to_rel_str();
+        let status = finish_delete(db_service, file_service, delete, delete_res).await;
+
+        let mut files = files.write();
+
+        if let Some(props) = files.get_mut(&rel_path) {
+            match status {
+                Ok(status) => props.status = status,
+                Err(e) => {
+                    tracing::error!("failed to sync file {:?}", e);
+                    props.status = FileStatus::Failed;
+                }
+            }
+        }
+    }
+}
+
+/// removes the local database entry of a deleted server file, a server file that changed
+/// since the last sync is downloaded instead
+async fn finish_delete(
+    db_service: &DatabaseService,
+    file_service: &mut FileApiService,
+    delete: PendingDelete,
+    delete_res: Result<(), anyhow::Error>,
+) -> Result<FileStatus, anyhow::Error> {
+    let PendingDelete {
+        file_path,
+        sql_file,
+    } = delete;
+
+    if let Err(e) = delete_res {
+        match e.downcast::<FileConflict>() {
+            Ok(FileConflict(Some(remote_file))) => {
+                tracing::debug!("api file changed, downloading instead of deleting");
+
+                db_service.delete_file_by_id(sql_file.id.to_owned()).await?;
+                let hash = download_file(
+                    file_service,
+                    file_path.get_sync_dir(),
+                    &remote_file,
+                    Some(&sql_file),
+                )
+                .await?;
+                db_service.add_file(&remote_file, &hash).await?;
+                return Ok(FileStatus::Success);
+            }
+            Ok(conflict) => return Err(conflict.into()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    db_service.delete_file_by_id(sql_file.id).await?;
+    Ok(FileStatus::Deleted)
+}
+
+async fn show_locks(
+    file_service: &mut FileApiService,
+    files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
+) -> Result<(), anyhow::Error> {
+    for lock in file_service.get_locks().await? {
+        let mut files = files.write();
+
+        if let Some(props) = files.get_mut(&lock.path) {
+            props.lock = Some(lock);
+        }
+    }
+
+    Ok(())
+}
+
 async fn process_path(
     db_service: &DatabaseService,
     file_service: &mut FileApiService,
     files: &UseAtomRef<BTreeMap<String, FileElementProps>>,
     file_path: FilePath,
     size: u64,
+    remote_file: Option<proto::File>,
+    deletes: &mut Vec<PendingDelete>,
 ) {
     if files.read().contains_key(&file_path.to_rel_str()) {
         return;
@@ -357,7 +735,7 @@ async fn process_path(
         return;
     }
 
-    if file_name.starts_with(".~download~") {
+    if file_name.starts_with(".~download~") || file_name.starts_with(".~outboard~") {
         return;
     }
 
@@ -367,10 +745,16 @@ async fn process_path(
             status: FileStatus::WaitingQueue,
             path: file_path.clone(),
             size,
+            lock: None,
+            favorite: remote_file.as_ref().is_some_and(|f| f.favorite),
+            tags: remote_file
+                .as_ref()
+                .map(|f| f.tags.clone())
+                .unwrap_or_default(),
         },
     );
 
-    let status = sync_file(db_service, file_service, &file_path).await;
+    let status = sync_file(db_service, file_service, &file_path, remote_file, deletes).await;
 
     match status {
         Ok(status) => {
@@ -390,6 +774,13 @@ async fn process_path(
 /// compares the local file hash to the local database hash to the api hash
 /// and replaces the older file with newer file
 ///
+/// `remote_file` has been looked up by the caller, deletions of api files are only queued
+/// in `deletes`
+///
+/// the local database records the hash of the local file and the hash of the api file at
+/// the last sync, which differ if the file is encrypted end-to-end, so the local file is
+/// compared to the first and the api file to the second
+///
 /// file existence:
 ///
 /// | action                | filesystem | local database | server api |
@@ -436,27 +827,13 @@ async fn sync_file(
     db_service: &DatabaseService,
     file_service: &mut FileApiService,
     file_path: &FilePath,
+    remote_file: Option<proto::File>,
+    deletes: &mut Vec<PendingDelete>,
 ) -> Result<FileStatus, anyhow::Error> {
     tracing::info!("syncing file {:?}", file_path.get_abs());
 
     let sql_file = db_service.find_file_by_path(&file_path.get_rel()).await?;
 
-    let remote_file = file_service
-        .get_client()
-        .find(proto::FindFileRequest {
-            path: file_path.to_rel_str(),
-        })
-        .await
-        .map(|f| Some(f.into_inner()));
-
-    let remote_file = match remote_file {
-        Ok(x) => Ok(x),
-        Err(e) => match e.code() {
-            tonic::Code::NotFound => Ok(None),
-            _ => Err(e),
-        },
-    }?;
-
     let local_meta = match file_path.get_abs().exists() {
         true => Some(path_helper::read_file_meta(file_path.get_abs()).await?),
         false => None,
@@ -471,8 +848,9 @@ async fn sync_file(
             tracing::debug!("downloading api file");
 
             // download file
-            download_file(file_service, file_path.get_sync_dir(), &remote_file).await?;
-            db_service.add_file(&remote_file).await?;
+            let hash =
+                download_file(file_service, file_path.get_sync_dir(), &remote_file, None).await?;
+            db_service.add_file(&remote_file, &hash).await?;
             return Ok(FileStatus::Added);
         }
         (None, Some(sql_file), None) => {
@@ -483,21 +861,29 @@ async fn sync_file(
             return Ok(FileStatus::Success);
         }
         (None, Some(sql_file), Some(remote_file)) => {
-            if sql_file.hash == remote_file.hash {
+            if sql_file.remote_hash == remote_file.hash {
                 tracing::debug!("deleting api file");
 
                 // local file deleted
-                file_service.delete_file(sql_file.id.to_owned()).await?;
-                db_service.delete_file_by_id(sql_file.id).await?;
-                return Ok(FileStatus::Deleted);
+                deletes.push(PendingDelete {
+                    file_path: file_path.clone(),
+                    sql_file,
+                });
+                return Ok(FileStatus::WaitingQueue);
             } else {
                 tracing::debug!("replacing local file with api file");
 
                 // old local file deleted, new file on server
                 // download new file
-                db_service.delete_file_by_id(sql_file.id).await?;
-                download_file(file_service, file_path.get_sync_dir(), &remote_file).await?;
-                db_service.add_file(&remote_file).await?;
+                db_service.delete_file_by_id(sql_file.id.to_owned()).await?;
+                let hash = download_file(
+                    file_service,
+                    file_path.get_sync_dir(),
+                    &remote_file,
+                    Some(&sql_file),
+                )
+                .await?;
+                db_service.add_file(&remote_file, &hash).await?;
                 return Ok(FileStatus::Success);
             }
         }
@@ -505,14 +891,25 @@ async fn sync_file(
             tracing::debug!("uploading local file");
 
             // upload file and add to local db
-            let remote_file = upload_file(file_service, file_path, &local_meta).await?;
-            db_service.add_file(&remote_file).await?;
+            let remote_file = match upload_file(
+                file_service,
+                file_path,
+                &local_meta,
+                Some(String::new()),
+            )
+            .await
+            {
+                Ok(f) => f,
+                Err(e) => return conflict_status(e, file_path, local_meta, None),
+            };
+            db_service.add_file(&remote_file, &local_meta.0).await?;
             return Ok(FileStatus::Added);
         }
         (Some(local_meta), None, Some(remote_file)) => {
-            if local_meta.0 == remote_file.hash {
+            // the server only knows the hash of the encrypted content
+            if local_meta.0 == file_service.plain_hash(&remote_file).await? {
                 tracing::debug!("adding sql entry");
-                db_service.add_file(&remote_file).await?;
+                db_service.add_file(&remote_file, &local_meta.0).await?;
                 return Ok(FileStatus::Success);
             } else {
                 // local file changed while it already existed on the server but has not been synced
@@ -537,30 +934,47 @@ async fn sync_file(
                 tracing::debug!("uploading local file");
 
                 // local file changed
+                let remote_file =
+                    match upload_file(file_service, file_path, &local_meta, Some(String::new()))
+                        .await
+                    {
+                        Ok(f) => f,
+                        Err(e) => return conflict_status(e, file_path, local_meta, Some(sql_file)),
+                    };
                 db_service.delete_file_by_id(sql_file.id).await?;
-                let remote_file = upload_file(file_service, file_path, &local_meta).await?;
-                db_service.add_file(&remote_file).await?;
+                db_service.add_file(&remote_file, &local_meta.0).await?;
                 return Ok(FileStatus::Success);
             }
         }
         (Some(local_meta), Some(sql_file), Some(remote_file)) => {
-            if local_meta.0 == sql_file.hash && local_meta.0 == remote_file.hash {
+            if local_meta.0 == sql_file.hash && sql_file.remote_hash == remote_file.hash {
                 // do nothing
                 tracing::debug!("already synced");
                 return Ok(FileStatus::Success);
             } else if local_meta.0 == sql_file.hash {
                 tracing::debug!("replacing local file with api file");
                 // download
-                download_file(file_service, file_path.get_sync_dir(), &remote_file).await?;
-                db_service.replace_file_hash(&remote_file).await?;
+                let hash = download_file(
+                    file_service,
+                    file_path.get_sync_dir(),
+                    &remote_file,
+                    Some(&sql_file),
+                )
+                .await?;
+                db_service.replace_file_hash(&remote_file, &hash).await?;
                 return Ok(FileStatus::Success);
-            } else if sql_file.hash == remote_file.hash {
+            } else if sql_file.remote_hash == remote_file.hash {
                 tracing::debug!("replacing api file with local file");
 
                 // local file modified, upload new file
+                let expected_hash = Some(remote_file.hash.to_owned());
+                let remote_file =
+                    match upload_file(file_service, file_path, &local_meta, expected_hash).await {
+                        Ok(f) => f,
+                        Err(e) => return conflict_status(e, file_path, local_meta, Some(sql_file)),
+                    };
                 db_service.delete_file_by_id(sql_file.id).await?;
-                let remote_file = upload_file(file_service, file_path, &local_meta).await?;
-                db_service.add_file(&remote_file).await?;
+                db_service.add_file(&remote_file, &local_meta.0).await?;
                 return Ok(FileStatus::Success);
             } else {
                 // local file and api file have been changed since the last sync
@@ -576,20 +990,47 @@ async fn sync_file(
     }
 }
 
+/// asks the user about an upload the server rejected because its file changed since it
+/// was last seen, instead of overwriting it
+fn conflict_status(
+    e: anyhow::Error,
+    file_path: &FilePath,
+    local_meta: (String, u64),
+    sql_file: Option<DbFile>,
+) -> Result<FileStatus, anyhow::Error> {
+    match e.downcast::<FileConflict>() {
+        Ok(FileConflict(Some(remote_file))) => Ok(FileStatus::WaitingUser(FilePromptKeep {
+            file_path: file_path.clone(),
+            local_meta,
+            sql_file,
+            remote_file,
+        })),
+        Ok(conflict) => Err(conflict.into()),
+        Err(e) => Err(e),
+    }
+}
+
+/// `expected_hash` is the hash of the server file that is replaced, an empty hash expects
+/// that there is none
 async fn upload_file(
     file_service: &mut FileApiService,
     file_path: &FilePath,
     file_meta: &(String, u64),
+    expected_hash: Option<String>,
 ) -> Result<proto::File, anyhow::Error> {
-    let fs_file = fs::File::open(file_path.get_abs()).await?;
+    let (mtime, mode) = path_helper::read_file_attributes(file_path.get_abs()).await?;
+
     let api_file = {
         file_service
             .upload_file(
-                fs_file,
+                file_path.get_abs(),
                 proto::UploadInfo {
                     path: file_path.to_rel_str(),
                     hash: file_meta.0.to_owned(),
                     size: file_meta.1,
+                    expected_hash,
+                    mtime,
+                    mode,
                 },
             )
             .await?
@@ -597,30 +1038,24 @@ async fn upload_file(
     Ok(api_file)
 }
 
+/// returns the hash of the downloaded file, the service verified the content against the
+/// server hash while it was downloaded
+///
+/// an unencrypted file is only accepted if `sql_file` records it as uploaded before
+/// encryption was enabled, the server hash of such a file is the hash of the local file
 async fn download_file<P>(
     file_service: &mut FileApiService,
     sync_dir: P,
     api_file: &proto::File,
-) -> Result<(), anyhow::Error>
+    sql_file: Option<&DbFile>,
+) -> Result<String, anyhow::Error>
 where
     P: AsRef<Path>,
 {
-    file_service.download_file(&sync_dir, api_file).await?;
-
-    let absolute_path = path_helper::rel_to_abs_path(&sync_dir, api_file.path.to_owned());
-    let file_meta = path_helper::read_file_meta(&absolute_path).await;
-
-    if let Err(e) = file_meta {
-        fs::remove_file(absolute_path).await?;
-        return Err(e);
-    }
+    let allow_plaintext =
+        sql_file.is_some_and(|f| f.hash == f.remote_hash && f.remote_hash == api_file.hash);
 
-    let file_meta = file_meta.unwrap();
-
-    if api_file.hash != file_meta.0 {
-        fs::remove_file(absolute_path).await?;
-        return Err(anyhow!("downloaded file hash does not match api file hash"));
-    }
-
-    Ok(())
+    file_service
+        .download_file(&sync_dir, api_file, allow_plaintext)
+        .await
 }
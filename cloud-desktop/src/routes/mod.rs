@@ -1,14 +1,20 @@
 use dioxus::prelude::*;
 use dioxus_router::Route;
 
+pub mod activity;
+pub mod duplicates;
 pub mod files;
 pub mod home;
 pub mod setup;
+pub mod usage;
 
 pub fn create_routes(cx: Scope) -> Element {
     cx.render(rsx! {
         Route { to: "/", home::Home {}}
         Route { to: "/setup", rsx! { setup::Setup {} }}
         Route { to: "/files", rsx! { files::Files {} }}
+        Route { to: "/duplicates", rsx! { duplicates::Duplicates {} }}
+        Route { to: "/usage", rsx! { usage::Usage {} }}
+        Route { to: "/activity", rsx! { activity::Activity {} }}
     })
 }
@@ -12,8 +12,10 @@ use crate::{
     config, global_state,
     services::{
         api_service::{AuthApiService, FileApiService, UserApiService},
+        crypto_service::CryptoService,
         database_service::DatabaseService,
     },
+    sso,
 };
 
 #[derive(Clone, Copy)]
@@ -28,6 +30,8 @@ struct SetupData<'a> {
     email_field: &'a UseState<String>,
     password_field: &'a UseState<String>,
     sync_dir_field: &'a UseState<String>,
+    encrypt_names_field: &'a UseState<bool>,
+    identity_providers: &'a UseState<Vec<proto::IdentityProvider>>,
 
     sync_dir_set: &'a AtomState<Option<String>>,
     database_service: &'a AtomState<Option<Arc<DatabaseService>>>,
@@ -64,6 +68,8 @@ pub fn Setup(cx: Scope) -> Element {
                 .map_or(String::new(), |c| c.password.to_owned())
         }),
         sync_dir_field: use_state(cx, || conf.sync_dir.clone().unwrap_or_default()),
+        encrypt_names_field: use_state(cx, || conf.e2e.as_ref().map_or(false, |e| e.encrypt_names)),
+        identity_providers: use_state(cx, Vec::new),
         // access_token: use_atom_state(cx, global_state::ACCESS_TOKEN),
         sync_dir_set: use_atom_state(cx, global_state::SYNC_DIR),
         database_service: use_atom_state(cx, global_state::DATABASE_SERVICE),
@@ -170,6 +176,19 @@ pub fn Setup(cx: Scope) -> Element {
                                     "Log In",
                                 }
                             }
+                            data.identity_providers.get().iter().map(|p| {
+                                let provider_id = p.id.clone();
+                                rsx! {
+                                    button {
+                                        r#type: "button",
+                                        onclick: move |_| { on_click_sso(cx, data, provider_id.clone()) },
+                                        class: "w-full text-gray-900 bg-white border border-gray-300 hover:bg-gray-100 focus:ring-4 focus:outline-none focus:ring-gray-200 font-medium rounded-lg text-sm px-5 py-2.5 text-center dark:bg-gray-800 dark:text-white dark:border-gray-600 dark:hover:bg-gray-700",
+                                        disabled: "{data.is_loading}",
+                                        i { class: "fa-solid fa-key mr-2" }
+                                        "Sign in with {p.display_name}",
+                                    }
+                                }
+                            })
                             div {
                                 class: "text-sm font-medium text-gray-500 dark:text-gray-300",
                                 "Not registered?"
@@ -291,6 +310,36 @@ pub fn Setup(cx: Scope) -> Element {
                                     value: "{data.sync_dir_field}"
                                 }
                             }
+                            div {
+                                label {
+                                    class: "block mb-2 text-sm font-medium text-gray-900",
+                                    "Encryption Passphrase (optional)"
+                                }
+                                input {
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-blue-500 focus:border-blue-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    r#type: "password",
+                                    name: "passphrase",
+                                    disabled: "{data.is_loading}",
+                                }
+                                p {
+                                    class: "mt-1 text-sm text-gray-500",
+                                    "Files are encrypted before they are uploaded. Every device needs the same passphrase, it is not saved and has to be entered on every start."
+                                }
+                            }
+                            div {
+                                class: "flex items-center",
+                                input {
+                                    class: "w-4 h-4 text-blue-600 bg-gray-100 border-gray-300 rounded focus:ring-blue-500",
+                                    r#type: "checkbox",
+                                    name: "encrypt_names",
+                                    disabled: "{data.is_loading}",
+                                    checked: "{data.encrypt_names_field}"
+                                }
+                                label {
+                                    class: "ml-2 text-sm font-medium text-gray-900",
+                                    "Encrypt file names"
+                                }
+                            }
                             div {
                                 div {
                                     class: "text-red-800 text-sm pb-3",
@@ -329,6 +378,7 @@ fn on_submit_url(cx: Scope, data: SetupData, event: Event<FormData>) {
     let is_loading = data.is_loading.clone();
     let step = data.step.clone();
     let api_channel = data.api_channel.clone();
+    let identity_providers = data.identity_providers.clone();
 
     cx.spawn({
         async move {
@@ -356,6 +406,13 @@ fn on_submit_url(cx: Scope, data: SetupData, event: Event<FormData>) {
             let channel = channel.unwrap();
             tracing::info!("Connected to server {:?}", url);
 
+            if let Ok(mut auth_client) = AuthApiService::new(channel.clone()) {
+                match auth_client.get_client().get_identity_providers(()).await {
+                    Ok(res) => identity_providers.set(res.into_inner().providers),
+                    Err(e) => tracing::warn!("failed to get identity providers {:?}", e),
+                }
+            }
+
             step.set(SetupStep::Login);
             api_channel.set(Some(channel));
             is_loading.set(false);
@@ -407,15 +464,55 @@ fn on_submit_login(cx: Scope, data: SetupData, event: Event<FormData>) {
                             login_res.access_token.to_owned(),
                         )))));
 
-                        file_api_service.set(Some(Arc::new(Mutex::new(FileApiService::new(
+                        file_api_service.set(Some(
+                            new_file_api_service(channel.clone(), login_res.access_token).await,
+                        ));
+
+                        step.set(SetupStep::SyncDir);
+                    }
+                    Err(e) => {
+                        error_status.set(e.message().to_owned());
+                    }
+                }
+
+                is_loading.set(false);
+            }
+        });
+    }
+}
+
+fn on_click_sso(cx: Scope, data: SetupData, provider: String) {
+    data.is_loading.set(true);
+    data.error_status.set("".to_owned());
+
+    let channel = data.api_channel.as_ref().unwrap().clone();
+    let auth_client = AuthApiService::new(channel.clone());
+
+    let error_status = data.error_status.clone();
+    let is_loading = data.is_loading.clone();
+    let step = data.step.clone();
+    let user_api_service = data.user_api_service.clone();
+    let file_api_service = data.file_api_service.clone();
+
+    if let Ok(mut auth_client) = auth_client {
+        cx.spawn({
+            async move {
+                match sso::sign_in(&mut auth_client, provider).await {
+                    Ok(login_res) => {
+                        user_api_service.set(Some(Arc::new(Mutex::new(UserApiService::new(
                             channel.clone(),
-                            login_res.access_token,
+                            login_res.access_token.to_owned(),
                         )))));
 
+                        file_api_service.set(Some(
+                            new_file_api_service(channel.clone(), login_res.access_token).await,
+                        ));
+
                         step.set(SetupStep::SyncDir);
                     }
                     Err(e) => {
-                        error_status.set(e.message().to_owned());
+                        tracing::error!("failed to sign in with sso {:?}", e);
+                        error_status.set("Single sign-on failed".to_owned());
                     }
                 }
 
@@ -471,10 +568,9 @@ fn on_submit_register(cx: Scope, data: SetupData, event: Event<FormData>) {
                             register_res.access_token.to_owned(),
                         )))));
 
-                        file_api_service.set(Some(Arc::new(Mutex::new(FileApiService::new(
-                            channel.clone(),
-                            register_res.access_token,
-                        )))));
+                        file_api_service.set(Some(
+                            new_file_api_service(channel.clone(), register_res.access_token).await,
+                        ));
                         step.set(SetupStep::SyncDir);
                     }
                     Err(e) => {
@@ -494,6 +590,12 @@ fn on_submit_dir(cx: Scope, data: SetupData, event: Event<FormData>) {
 
     let dir = event.values.get("sync_dir").unwrap().to_owned();
     let dir_path = Path::new(&dir).to_path_buf();
+    let passphrase = event
+        .values
+        .get("passphrase")
+        .map_or(String::new(), |p| p.to_owned());
+    // unchecked checkboxes are not part of the form values
+    let encrypt_names = event.values.get("encrypt_names").is_some();
 
     if !dir_path.is_absolute() {
         data.error_status.set("The path is not absolute".to_owned());
@@ -518,13 +620,54 @@ fn on_submit_dir(cx: Scope, data: SetupData, event: Event<FormData>) {
     let is_loading = data.is_loading.clone();
     let sync_dir_set = data.sync_dir_set.clone();
     let database_service = data.database_service.clone();
+    let user_api_service = data.user_api_service.as_ref().unwrap().clone();
+    let file_api_service = data.file_api_service.as_ref().unwrap().clone();
     let router = data.router.clone();
 
     cx.spawn({
         async move {
-            let conf_res =
-                config::modify_conf(|c| c.sync_dir = Some(dir_path.to_string_lossy().to_string()))
-                    .await;
+            // the passphrase is not saved, so it has to be entered on every start once
+            // encryption has been enabled
+            let e2e_enabled = config::read_conf().await.map_or(false, |c| c.e2e.is_some());
+
+            if passphrase.is_empty() && e2e_enabled {
+                error_status.set("Enter the encryption passphrase".to_owned());
+                is_loading.set(false);
+                return;
+            }
+
+            let e2e = match passphrase.is_empty() {
+                true => None,
+                false => Some(config::E2eConfiguration { encrypt_names }),
+            };
+
+            let crypto = match &e2e {
+                Some(e2e) => {
+                    let crypto = match user_api_service.lock().await.get_self().await {
+                        Ok(user) => CryptoService::new(&passphrase, &user.id, e2e.encrypt_names),
+                        Err(e) => Err(e),
+                    };
+
+                    match crypto {
+                        Ok(crypto) => Some(Arc::new(crypto)),
+                        Err(e) => {
+                            tracing::error!("failed to derive encryption key {:?}", e);
+                            error_status.set("Could not derive the encryption key".to_owned());
+                            is_loading.set(false);
+                            return;
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            file_api_service.lock().await.set_crypto(crypto);
+
+            let conf_res = config::modify_conf(|c| {
+                c.sync_dir = Some(dir_path.to_string_lossy().to_string());
+                c.e2e = e2e;
+            })
+            .await;
 
             if let Err(e) = conf_res {
                 tracing::error!("failed to modify config {:?}", e);
@@ -548,3 +691,19 @@ fn on_submit_dir(cx: Scope, data: SetupData, event: Event<FormData>) {
         }
     });
 }
+
+/// the file service of a signed in user, set up with the multipart threshold of the config
+/// file, the only place it can be changed
+async fn new_file_api_service(
+    channel: Channel,
+    access_token: String,
+) -> Arc<Mutex<FileApiService>> {
+    let mut file_api_service = FileApiService::new(channel, access_token);
+
+    match config::read_conf().await {
+        Ok(conf) => file_api_service.set_multipart_threshold(conf.multipart_threshold),
+        Err(e) => tracing::error!("failed to read config {:?}", e),
+    }
+
+    Arc::new(Mutex::new(file_api_service))
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use cloud_proto::proto;
+use dioxus::prelude::*;
+use dioxus_router::Link;
+use tokio::sync::Mutex;
+
+use crate::{
+    global_state,
+    services::api_service::{FileApiService, UserApiService},
+};
+
+/// Shows what the storage of the user is used by.
+pub fn Usage(cx: Scope) -> Element {
+    let user_service = fermi::use_atom_state(cx, global_state::USER_API_SERVICE);
+    let file_service = fermi::use_atom_state(cx, global_state::FILE_API_SERVICE);
+    let usage = use_future(cx, (), |_| {
+        let user_service = user_service.get().as_ref().unwrap().clone();
+        let file_service = file_service.get().as_ref().unwrap().clone();
+        load_usage(user_service, file_service)
+    });
+
+    let content = match usage.value() {
+        None => rsx! {
+            p {
+                class: "text-sm text-gray-500 dark:text-gray-400",
+                "Loading..."
+            }
+        },
+        Some(Err(e)) => rsx! {
+            p {
+                class: "text-red-800 text-sm",
+                "{e}"
+            }
+        },
+        Some(Ok(usage)) => {
+            let size = byte_unit::Byte::from_bytes(usage.size.into()).get_appropriate_unit(true);
+            let file_count = usage.file_count;
+
+            rsx! {
+                p {
+                    class: "text-sm text-gray-500 dark:text-gray-400 mb-4",
+                    "{size} in {file_count} files"
+                }
+                UsageBreakdown {
+                    title: "By folder",
+                    entries: usage.by_folder.clone(),
+                    total: usage.size,
+                }
+                UsageBreakdown {
+                    title: "By type",
+                    entries: usage.by_type.clone(),
+                    total: usage.size,
+                }
+                UsageBreakdown {
+                    title: "By extension",
+                    entries: usage.by_extension.clone(),
+                    total: usage.size,
+                }
+                UsageBreakdown {
+                    title: "By last upload",
+                    entries: usage.by_age.clone(),
+                    total: usage.size,
+                }
+            }
+        }
+    };
+
+    cx.render(rsx! {
+        div {
+            class: "w-full h-full p-4 bg-white sm:p-8 dark:bg-gray-800",
+            div {
+                class: "flex items-center justify-between mb-4",
+                h5 {
+                    class: "text-xl font-medium text-gray-900 dark:text-white",
+                    "Storage usage"
+                }
+                Link {
+                    to: "/files",
+                    class: "text-sm font-medium text-blue-600 hover:underline dark:text-blue-500",
+                    "Back to files"
+                }
+            }
+            content
+        }
+    })
+}
+
+#[derive(Debug, PartialEq, Props)]
+struct UsageBreakdownProps {
+    title: &'static str,
+    entries: Vec<proto::UsageEntry>,
+    /// the size the entries are shown in proportion to
+    total: u64,
+}
+
+fn UsageBreakdown(cx: Scope<UsageBreakdownProps>) -> Element {
+    let title = cx.props.title;
+
+    cx.render(rsx! {
+        div {
+            class: "mb-6",
+            h6 {
+                class: "text-lg font-medium text-gray-900 dark:text-white mb-2",
+                "{title}"
+            }
+            cx.props.entries.iter().map(|entry| {
+                let key = match entry.key.as_str() {
+                    "" => "none",
+                    key => key,
+                };
+                let size = byte_unit::Byte::from_bytes(entry.size.into()).get_appropriate_unit(true);
+                let file_count = entry.file_count;
+                let percent = match cx.props.total {
+                    0 => 0,
+                    total => entry.size * 100 / total,
+                };
+
+                rsx! {
+                    div {
+                        key: "{key}",
+                        class: "mb-2",
+                        div {
+                            class: "flex justify-between text-sm text-gray-500 dark:text-gray-400",
+                            span { class: "truncate", "{key}" }
+                            span { "{size} in {file_count} files" }
+                        }
+                        div {
+                            class: "w-full bg-gray-200 rounded-full h-2 dark:bg-gray-700",
+                            div {
+                                class: "bg-blue-600 h-2 rounded-full",
+                                style: "width: {percent}%",
+                            }
+                        }
+                    }
+                }
+            })
+        }
+    })
+}
+
+/// folders with encrypted names are shown with their plaintext names
+async fn load_usage(
+    user_service: Arc<Mutex<UserApiService>>,
+    file_service: Arc<Mutex<FileApiService>>,
+) -> Result<proto::StorageUsage, String> {
+    let usage_res = user_service.lock().await.get_storage_usage().await;
+
+    let mut usage = match usage_res {
+        Ok(usage) => usage,
+        Err(e) => {
+            tracing::error!("failed to get storage usage {:?}", e);
+            return Err(e.to_string());
+        }
+    };
+
+    let file_service = file_service.lock().await;
+
+    for entry in &mut usage.by_folder {
+        match file_service.decrypt_path(&entry.key) {
+            Ok(folder) if !folder.is_empty() => entry.key = folder,
+            Ok(_) => {}
+            Err(e) => tracing::warn!("failed to decrypt folder {} {:?}", entry.key, e),
+        }
+    }
+
+    Ok(usage)
+}
@@ -1,16 +1,90 @@
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::SeekFrom,
+    path::Path,
+    sync::Arc,
+};
 
-use cloud_proto::proto::{
-    self, auth_service_client::AuthServiceClient, file_service_client::FileServiceClient,
-    user_service_client::UserServiceClient,
+use cloud_proto::{
+    prost::Message,
+    proto::{
+        self, auth_service_client::AuthServiceClient, file_service_client::FileServiceClient,
+        user_service_client::UserServiceClient,
+    },
+};
+use futures::{StreamExt, TryStreamExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
-use futures::StreamExt;
-use tokio::{fs, io::AsyncWriteExt};
 use tokio_util::io::ReaderStream;
 use tonic::{codegen::InterceptedService, service::Interceptor, transport::Channel};
 
-use crate::path_helper;
+use crate::{
+    chunking, config,
+    outboard::{self, Outboard},
+    path_helper,
+    services::crypto_service::CryptoService,
+};
+
+/// chunks requested from the server at once while downloading a chunked file
+const DOWNLOAD_CHUNK_BATCH: usize = 64;
+/// part size requested for multipart uploads, the server may choose larger parts
+const MULTIPART_PART_SIZE: u64 = 16 * 1024 * 1024;
+/// parts of a multipart upload that are uploaded at once
+const MULTIPART_CONCURRENCY: usize = 4;
+/// items sent to the server in one batch request
+const BATCH_SIZE: usize = 1000;
+/// files listed per request, the most the server returns at once
+const LIST_PAGE_SIZE: u32 = 1000;
+/// search results shown at once
+const SEARCH_PAGE_SIZE: u32 = 100;
+
+/// The file on the server did not have the expected hash, it holds the current file if
+/// there is one.
+#[derive(Debug)]
+pub struct FileConflict(pub Option<proto::File>);
+
+impl Display for FileConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "file has been changed on the server")
+    }
+}
+
+impl std::error::Error for FileConflict {}
 
+impl From<tonic::Status> for FileConflict {
+    fn from(status: tonic::Status) -> Self {
+        match status.details().is_empty() {
+            true => Self(None),
+            false => Self(proto::File::decode(status.details()).ok()),
+        }
+    }
+}
+
+/// the status the single item rpc would have returned for an item of a batch, `None` if the
+/// item succeeded
+fn batch_status(result: &proto::BatchResult) -> Option<tonic::Status> {
+    match tonic::Code::from(result.code) {
+        tonic::Code::Ok => None,
+        code => Some(tonic::Status::with_details(
+            code,
+            result.message.to_owned(),
+            result.details.to_owned().into(),
+        )),
+    }
+}
+
+/// converts failed preconditions into a [`FileConflict`]
+fn map_conflict(status: tonic::Status) -> anyhow::Error {
+    match status.code() {
+        tonic::Code::FailedPrecondition => FileConflict::from(status).into(),
+        _ => status.into(),
+    }
+}
+
+#[derive(Clone)]
 pub struct AuthInterceptor {
     pub access_token: String,
 }
@@ -26,7 +100,7 @@ impl Interceptor for AuthInterceptor {
         &mut self,
         mut request: tonic::Request<()>,
     ) -> Result<tonic::Request<()>, tonic::Status> {
-        let authorization = format!("Baerer {}", self.access_token);
+        let authorization = format!("Bearer {}", self.access_token);
         let authorization: tonic::metadata::MetadataValue<_> = authorization.parse().unwrap();
 
         request
@@ -76,10 +150,20 @@ impl UserApiService {
 
         Ok(get_res.into_inner())
     }
+
+    /// the folders of the usage carry encrypted paths if names are encrypted, see
+    /// [`FileApiService::decrypt_path`]
+    pub async fn get_storage_usage(&mut self) -> Result<proto::StorageUsage, anyhow::Error> {
+        let get_res = self.client.get_storage_usage(()).await?;
+
+        Ok(get_res.into_inner())
+    }
 }
 
 pub struct FileApiService {
     client: FileServiceClient<InterceptedService<Channel, AuthInterceptor>>,
+    crypto: Option<Arc<CryptoService>>,
+    multipart_threshold: u64,
 }
 
 impl FileApiService {
@@ -89,6 +173,8 @@ impl FileApiService {
                 channel,
                 AuthInterceptor::new(access_token),
             ),
+            crypto: None,
+            multipart_threshold: config::DEFAULT_MULTIPART_THRESHOLD,
         }
     }
 
@@ -98,22 +184,521 @@ impl FileApiService {
         &mut self.client
     }
 
-    pub async fn delete_file(&mut self, file_id: String) -> Result<(), anyhow::Error> {
+    /// encrypts file content and names end-to-end, the files returned by this service carry
+    /// plaintext paths but the hash and size of the encrypted content
+    pub fn set_crypto(&mut self, crypto: Option<Arc<CryptoService>>) {
+        self.crypto = crypto;
+    }
+
+    /// files at least this large are uploaded in parts instead of as a whole or in chunks
+    pub fn set_multipart_threshold(&mut self, multipart_threshold: u64) {
+        self.multipart_threshold = multipart_threshold;
+    }
+
+    fn encrypt_path(&self, path: &str) -> Result<String, anyhow::Error> {
+        match &self.crypto {
+            Some(crypto) => crypto.encrypt_path(path),
+            None => Ok(path.to_owned()),
+        }
+    }
+
+    /// the plaintext of a path returned by the server
+    pub fn decrypt_path(&self, path: &str) -> Result<String, anyhow::Error> {
+        match &self.crypto {
+            Some(crypto) => crypto.decrypt_path(path),
+            None => Ok(path.to_owned()),
+        }
+    }
+
+    fn decrypt_file(&self, mut file: proto::File) -> Result<proto::File, anyhow::Error> {
+        file.path = self.decrypt_path(&file.path)?;
+
+        Ok(file)
+    }
+
+    /// the server file of a conflict carries the encrypted path
+    fn decrypt_conflict(&self, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast::<FileConflict>() {
+            Ok(FileConflict(Some(file))) => match self.decrypt_file(file) {
+                Ok(file) => FileConflict(Some(file)).into(),
+                Err(e) => e,
+            },
+            Ok(conflict) => conflict.into(),
+            Err(e) => e,
+        }
+    }
+
+    pub async fn find_file(&mut self, path: &str) -> Result<Option<proto::File>, anyhow::Error> {
+        let find_res = self
+            .client
+            .find(proto::FindFileRequest {
+                path: self.encrypt_path(path)?,
+            })
+            .await;
+
+        match find_res {
+            Ok(f) => Ok(Some(self.decrypt_file(f.into_inner())?)),
+            Err(e) => match e.code() {
+                tonic::Code::NotFound => Ok(None),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    /// finds the server files at many paths with a few batch requests, paths without a
+    /// server file are missing from the returned map
+    pub async fn find_files(
+        &mut self,
+        paths: &[String],
+    ) -> Result<HashMap<String, proto::File>, anyhow::Error> {
+        let mut files = HashMap::new();
+
+        for batch in paths.chunks(BATCH_SIZE) {
+            let encrypted_paths = batch
+                .iter()
+                .map(|p| self.encrypt_path(p))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let find_res = self
+                .client
+                .find_many(proto::FindManyRequest {
+                    paths: encrypted_paths,
+                })
+                .await?;
+
+            for (path, result) in batch.iter().zip(find_res.into_inner().results) {
+                match (batch_status(&result), result.file) {
+                    (None, Some(file)) => {
+                        files.insert(path.to_owned(), self.decrypt_file(file)?);
+                    }
+                    (Some(status), _) if status.code() == tonic::Code::NotFound => {}
+                    (Some(status), _) => return Err(status.into()),
+                    (None, None) => return Err(anyhow::anyhow!("found file {} is empty", path)),
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// deletes many server files with a few batch requests, the results are in the order of
+    /// `files` and hold a [`FileConflict`] for files that changed
+    pub async fn delete_files(
+        &mut self,
+        files: Vec<proto::DeleteFileRequest>,
+    ) -> Result<Vec<Result<(), anyhow::Error>>, anyhow::Error> {
+        let mut results = Vec::with_capacity(files.len());
+
+        for batch in files.chunks(BATCH_SIZE) {
+            let delete_res = self
+                .client
+                .delete_many(proto::DeleteManyRequest {
+                    files: batch.to_vec(),
+                })
+                .await?;
+
+            for result in delete_res.into_inner().results {
+                results.push(match batch_status(&result) {
+                    Some(status) => Err(self.decrypt_conflict(map_conflict(status))),
+                    None => Ok(()),
+                });
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// all server files and the server paths of the files whose names can not be decrypted,
+    /// e.g. uploaded by a client with another passphrase
+    pub async fn get_all_files(
+        &mut self,
+    ) -> Result<(Vec<proto::File>, Vec<String>), anyhow::Error> {
+        let mut request = proto::ListFilesRequest {
+            directory: "/".to_owned(),
+            recursive: true,
+            page_size: LIST_PAGE_SIZE,
+            ..Default::default()
+        };
+        let mut files = Vec::new();
+        let mut undecryptable = Vec::new();
+
+        loop {
+            let list_resp = self.client.list(request.clone()).await?.into_inner();
+
+            for api_file in list_resp.files {
+                let path = api_file.path.to_owned();
+
+                match self.decrypt_file(api_file) {
+                    Ok(f) => files.push(f),
+                    Err(e) => {
+                        tracing::warn!("failed to decrypt path {} {:?}", path, e);
+                        undecryptable.push(path);
+                    }
+                }
+            }
+
+            if list_resp.next_page_token.is_empty() {
+                return Ok((files, undecryptable));
+            }
+            request.page_token = list_resp.next_page_token;
+        }
+    }
+
+    /// the first page of the files whose name contains `name`, ignoring case
+    pub async fn search_files(&mut self, name: &str) -> Result<Vec<proto::File>, anyhow::Error> {
+        // the server only sees the encrypted names
+        if self.crypto.as_ref().is_some_and(|c| c.encrypts_names()) {
+            return Err(anyhow::anyhow!(
+                "files can not be searched while their names are encrypted"
+            ));
+        }
+
+        let request = proto::SearchFilesRequest {
+            name: name.to_owned(),
+            page_size: SEARCH_PAGE_SIZE,
+            ..Default::default()
+        };
+
+        let mut search_resp = self.client.search(request).await?.into_inner();
+        let mut files = Vec::new();
+
+        while let Some(search_resp) = search_resp.next().await {
+            files.extend(search_resp?.files);
+        }
+
+        Ok(files)
+    }
+
+    /// groups of server files with the same content, those wasting the most space first
+    ///
+    /// encrypted files differ in their encrypted content and are never duplicates, files
+    /// whose names can not be decrypted are skipped
+    pub async fn get_duplicates(&mut self) -> Result<Vec<proto::DuplicateGroup>, anyhow::Error> {
+        let groups = self
+            .client
+            .get_duplicates(proto::GetDuplicatesRequest::default())
+            .await?
+            .into_inner()
+            .groups;
+        let mut decrypted_groups = Vec::with_capacity(groups.len());
+
+        for mut group in groups {
+            let files = std::mem::take(&mut group.files);
+
+            for api_file in files {
+                match self.decrypt_file(api_file) {
+                    Ok(f) => group.files.push(f),
+                    Err(e) => tracing::warn!("skipping file with undecryptable path {:?}", e),
+                }
+            }
+
+            if group.files.len() > 1 {
+                decrypted_groups.push(group);
+            }
+        }
+
+        Ok(decrypted_groups)
+    }
+
+    /// the page of the activity of the user following `page_token`, newest first, and the
+    /// token of the next page, empty on the last page
+    ///
+    /// paths that can not be decrypted are left encrypted
+    pub async fn list_activity(
+        &mut self,
+        page_token: String,
+    ) -> Result<(Vec<proto::Activity>, String), anyhow::Error> {
+        let response = self
+            .client
+            .list_activity(proto::ListActivityRequest {
+                page_token,
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let mut activities = response.activities;
+
+        for activity in &mut activities {
+            match self.decrypt_path(&activity.path) {
+                Ok(path) => activity.path = path,
+                Err(e) => tracing::warn!("failed to decrypt activity path {:?}", e),
+            }
+
+            if let Some(old_path) = &mut activity.old_path {
+                match self.decrypt_path(old_path) {
+                    Ok(path) => *old_path = path,
+                    Err(e) => tracing::warn!("failed to decrypt activity path {:?}", e),
+                }
+            }
+        }
+
+        Ok((activities, response.next_page_token))
+    }
+
+    /// stars or unstars the server file at `path`
+    pub async fn set_favorite(
+        &mut self,
+        path: &str,
+        favorite: bool,
+    ) -> Result<proto::File, anyhow::Error> {
+        let id = self.file_id(path).await?;
+        let api_file = self
+            .client
+            .set_favorite(proto::SetFavoriteRequest { id, favorite })
+            .await?
+            .into_inner();
+
+        self.decrypt_file(api_file)
+    }
+
+    /// tags are not encrypted, the server can read them
+    pub async fn add_tag(&mut self, path: &str, tag: &str) -> Result<proto::File, anyhow::Error> {
+        let id = self.file_id(path).await?;
+        let api_file = self
+            .client
+            .add_tags(proto::FileTagsRequest {
+                id,
+                tags: vec![tag.to_owned()],
+            })
+            .await?
+            .into_inner();
+
+        self.decrypt_file(api_file)
+    }
+
+    pub async fn remove_tag(
+        &mut self,
+        path: &str,
+        tag: &str,
+    ) -> Result<proto::File, anyhow::Error> {
+        let id = self.file_id(path).await?;
+        let api_file = self
+            .client
+            .remove_tags(proto::FileTagsRequest {
+                id,
+                tags: vec![tag.to_owned()],
+            })
+            .await?
+            .into_inner();
+
+        self.decrypt_file(api_file)
+    }
+
+    /// the smallest thumbnail of the server file with the id that is at least `size` pixels
+    /// large, `None` if the file has none or it is not generated yet
+    pub async fn get_thumbnail(
+        &mut self,
+        id: String,
+        size: u32,
+    ) -> Result<Option<proto::Thumbnail>, anyhow::Error> {
+        let thumbnail_res = self
+            .client
+            .get_thumbnail(proto::GetThumbnailRequest { id, size })
+            .await;
+
+        match thumbnail_res {
+            Ok(thumbnail) => Ok(Some(thumbnail.into_inner())),
+            Err(e) => match e.code() {
+                tonic::Code::NotFound => Ok(None),
+                _ => Err(e.into()),
+            },
+        }
+    }
+
+    async fn file_id(&mut self, path: &str) -> Result<String, anyhow::Error> {
+        match self.find_file(path).await? {
+            Some(api_file) => Ok(api_file.id),
+            None => Err(anyhow::anyhow!("file {} is not on the server", path)),
+        }
+    }
+
+    pub async fn get_locks(&mut self) -> Result<Vec<proto::FileLock>, anyhow::Error> {
+        let mut get_resp = self.client.get_locks(()).await?.into_inner();
+        let mut locks = Vec::new();
+
+        while let Some(lock) = get_resp.next().await {
+            let mut lock = lock?;
+
+            if let Some(crypto) = &self.crypto {
+                match crypto.decrypt_path(&lock.path) {
+                    Ok(path) => lock.path = path,
+                    Err(_) => continue,
+                }
+            }
+
+            locks.push(lock);
+        }
+
+        Ok(locks)
+    }
+
+    /// locks the server file at `path` for the default duration of the server, or extends
+    /// the lock if this session already holds it
+    pub async fn lock_file(&mut self, path: &str) -> Result<proto::FileLock, anyhow::Error> {
+        let mut lock = self
+            .client
+            .lock(proto::LockFileRequest {
+                path: self.encrypt_path(path)?,
+                duration: 0,
+            })
+            .await?
+            .into_inner();
+        lock.path = path.to_owned();
+
+        Ok(lock)
+    }
+
+    /// releases the lock this session holds on the server file at `path`
+    pub async fn unlock_file(&mut self, path: &str) -> Result<(), anyhow::Error> {
         self.client
-            .delete(proto::DeleteFileRequest { id: file_id })
+            .unlock(proto::UnlockFileRequest {
+                path: self.encrypt_path(path)?,
+            })
             .await?;
 
         Ok(())
     }
 
+    /// returns the hash of the plaintext content of a server file, which requires a
+    /// download if it is encrypted
+    pub async fn plain_hash(&mut self, api_file: &proto::File) -> Result<String, anyhow::Error> {
+        let crypto = match &self.crypto {
+            Some(crypto) => crypto.clone(),
+            None => return Ok(api_file.hash.to_owned()),
+        };
+
+        let mut download_res = self
+            .client
+            .download(proto::DownloadFileRequest {
+                id: api_file.id.to_owned(),
+            })
+            .await?
+            .into_inner();
+
+        // only called for files that have not been synced yet
+        let mut decryptor = crypto.decryptor(&api_file.path, false);
+        let mut hasher = blake3::Hasher::new();
+
+        while let Some(api_data) = download_res.next().await {
+            hasher.update(&decryptor.update(&api_data?.chunk)?);
+        }
+
+        hasher.update(&decryptor.finish()?);
+        Ok(hasher.finalize().to_string())
+    }
+
+    pub async fn delete_file(
+        &mut self,
+        file_id: String,
+        expected_hash: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        self.client
+            .delete(proto::DeleteFileRequest {
+                id: file_id,
+                expected_hash,
+            })
+            .await
+            .map_err(|e| self.decrypt_conflict(map_conflict(e)))?;
+
+        Ok(())
+    }
+
+    /// downloads a file and verifies it against the server hash while it arrives, encrypted
+    /// files are decrypted while they are written
+    ///
+    /// files stored in chunks reuse the chunks of the local file at the same path, returns
+    /// the hash of the written file. With encryption enabled, a file without the encryption
+    /// header is only accepted if `allow_plaintext` is set.
     pub async fn download_file<P>(
         &mut self,
         sync_dir: P,
         api_file: &proto::File,
-    ) -> Result<(), anyhow::Error>
+        allow_plaintext: bool,
+    ) -> Result<String, anyhow::Error>
     where
         P: AsRef<Path>,
     {
+        let file_name = path_helper::extract_file_name(api_file.path.to_owned());
+        let absolute_path_download =
+            path_helper::rel_to_abs_path(&sync_dir, api_file.path.to_owned())
+                .with_file_name(format!(".~download~{}", file_name));
+        let absolute_path_outboard =
+            absolute_path_download.with_file_name(format!(".~outboard~{}", file_name));
+        let absolute_path = path_helper::rel_to_abs_path(&sync_dir, api_file.path.to_owned());
+
+        if let Some(parent) = absolute_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // encrypted files are uploaded as a whole
+        let chunks = match &self.crypto {
+            Some(_) => Vec::new(),
+            None => {
+                self.client
+                    .get_chunks(proto::GetFileRequest {
+                        id: api_file.id.to_owned(),
+                    })
+                    .await?
+                    .into_inner()
+                    .chunks
+            }
+        };
+
+        let mut fs_file = fs::File::create(&absolute_path_download).await?;
+
+        let write_res = match chunks.is_empty() {
+            true => {
+                self.write_download(
+                    &mut fs_file,
+                    &absolute_path_outboard,
+                    api_file,
+                    allow_plaintext,
+                )
+                .await
+            }
+            false => {
+                self.write_chunks(&mut fs_file, &absolute_path, api_file, chunks)
+                    .await
+            }
+        };
+
+        let hash = match write_res {
+            Ok(hash) => hash,
+            Err(e) => {
+                fs::remove_file(&absolute_path_download).await.ok();
+                return Err(e);
+            }
+        };
+
+        fs::rename(absolute_path_download, &absolute_path).await?;
+
+        // the content is synced even if the attributes can not be restored
+        if let Err(e) = path_helper::write_file_attributes(&absolute_path, api_file).await {
+            tracing::warn!(
+                "failed to restore attributes of {:?}: {:?}",
+                absolute_path,
+                e
+            );
+        }
+
+        Ok(hash)
+    }
+
+    /// writes a file stored as a single blob, every range is verified against the outboard
+    /// tree of the file before it is written and downloaded again if it is corrupted
+    ///
+    /// files without an outboard tree are only verified once they are complete
+    async fn write_download(
+        &mut self,
+        fs_file: &mut fs::File,
+        absolute_path_outboard: &Path,
+        api_file: &proto::File,
+        allow_plaintext: bool,
+    ) -> Result<String, anyhow::Error> {
+        let mut outboard = self
+            .download_outboard(absolute_path_outboard, api_file)
+            .await?;
+
         let mut download_res = self
             .client
             .download(proto::DownloadFileRequest {
@@ -122,33 +707,449 @@ impl FileApiService {
             .await?
             .into_inner();
 
-        let absolute_path_download =
-            path_helper::rel_to_abs_path(&sync_dir, api_file.path.to_owned()).with_file_name(
-                format!(
-                    ".~download~{}",
-                    path_helper::extract_file_name(api_file.path.to_owned())
-                ),
-            );
-        let absolute_path = path_helper::rel_to_abs_path(&sync_dir, api_file.path.to_owned());
+        let mut decryptor = self
+            .crypto
+            .as_ref()
+            .map(|c| c.decryptor(&api_file.path, allow_plaintext));
+        let mut hasher = blake3::Hasher::new();
+        let mut plain_hasher = blake3::Hasher::new();
+        let mut range = Vec::with_capacity(outboard::RANGE_SIZE);
+        let mut offset = 0;
 
-        if let Some(parent) = absolute_path.parent() {
-            fs::create_dir_all(parent).await?;
+        loop {
+            let api_data = download_res.next().await.transpose()?;
+            let done = api_data.is_none();
+
+            if let Some(api_data) = api_data {
+                range.extend_from_slice(&api_data.chunk);
+            }
+
+            while range.len() >= outboard::RANGE_SIZE || (done && !range.is_empty()) {
+                let rest = range.split_off(outboard::RANGE_SIZE.min(range.len()));
+                let data = std::mem::replace(&mut range, rest);
+
+                let data = match &mut outboard {
+                    Some(outboard) => self.verify_range(outboard, api_file, offset, data).await?,
+                    None => {
+                        hasher.update(&data);
+                        data
+                    }
+                };
+                offset += data.len() as u64;
+
+                let plain = match &mut decryptor {
+                    Some(decryptor) => decryptor.update(&data)?,
+                    None => data,
+                };
+                plain_hasher.update(&plain);
+                fs_file.write_all(&plain).await?;
+            }
+
+            if done {
+                break;
+            }
         }
 
-        let mut fs_file = fs::File::create(&absolute_path_download).await?;
-        // let mut downloaded_size = 0;
+        let verified = match outboard {
+            Some(_) => offset == api_file.size,
+            None => hasher.finalize().to_string() == api_file.hash,
+        };
 
-        while let Some(Ok(api_data)) = download_res.next().await {
-            // downloaded_size += api_data.chunk.len() as u64;
-            fs_file.write_all(&api_data.chunk).await?;
+        if !verified {
+            return Err(anyhow::anyhow!(
+                "downloaded file hash does not match api file hash"
+            ));
+        }
+
+        if let Some(decryptor) = decryptor {
+            let plain = decryptor.finish()?;
+            plain_hasher.update(&plain);
+            fs_file.write_all(&plain).await?;
         }
 
         fs_file.shutdown().await?;
-        fs::rename(absolute_path_download, absolute_path).await?;
-        Ok(())
+        Ok(plain_hasher.finalize().to_string())
+    }
+
+    /// downloads the outboard tree of a file to `path`, `None` if the server keeps no tree
+    /// for the file
+    async fn download_outboard(
+        &mut self,
+        path: &Path,
+        api_file: &proto::File,
+    ) -> Result<Option<Outboard>, anyhow::Error> {
+        let download_res = self
+            .client
+            .get_outboard(proto::GetFileRequest {
+                id: api_file.id.to_owned(),
+            })
+            .await;
+
+        let mut download_res = match download_res {
+            Ok(download_res) => download_res.into_inner(),
+            Err(e) if e.code() == tonic::Code::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let write_res: Result<Outboard, anyhow::Error> = async {
+            let mut fs_file = fs::File::create(path).await?;
+
+            while let Some(api_data) = download_res.next().await {
+                fs_file.write_all(&api_data?.chunk).await?;
+            }
+
+            fs_file.shutdown().await?;
+            Outboard::open(path, &api_file.hash)
+        }
+        .await;
+
+        if write_res.is_err() {
+            fs::remove_file(path).await.ok();
+        }
+
+        write_res.map(Some)
+    }
+
+    /// returns `data` if it is the content at `offset`, a corrupted range is downloaded
+    /// again once
+    async fn verify_range(
+        &mut self,
+        outboard: &mut Outboard,
+        api_file: &proto::File,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        if outboard.verify(offset, &data)? {
+            return Ok(data);
+        }
+
+        tracing::warn!(
+            "range at {} of {} is corrupted, downloading it again",
+            offset,
+            api_file.path
+        );
+
+        // a download that ended early has a short last range
+        let length = api_file
+            .size
+            .saturating_sub(offset)
+            .min(outboard::RANGE_SIZE as u64);
+
+        let mut download_res = self
+            .client
+            .download_range(proto::DownloadRangeRequest {
+                id: api_file.id.to_owned(),
+                offset,
+                length,
+            })
+            .await?
+            .into_inner();
+        let mut data = Vec::with_capacity(length as usize);
+
+        while let Some(api_data) = download_res.next().await {
+            data.extend_from_slice(&api_data?.chunk);
+        }
+
+        if !outboard.verify(offset, &data)? {
+            return Err(anyhow::anyhow!(
+                "range at {} of {} is corrupted",
+                offset,
+                api_file.path
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// writes the chunks of a file in order, chunks the local file at `absolute_path`
+    /// contains are copied from it, the others are downloaded in batches
+    ///
+    /// every chunk is verified before it is written, a corrupted chunk is downloaded again
+    async fn write_chunks(
+        &mut self,
+        fs_file: &mut fs::File,
+        absolute_path: &Path,
+        api_file: &proto::File,
+        chunks: Vec<proto::ChunkInfo>,
+    ) -> Result<String, anyhow::Error> {
+        let mut local_chunks = HashMap::new();
+        let mut local_file = None;
+
+        // a local file that can not be read is simply not reused
+        if absolute_path.is_file() {
+            if let Ok(chunked) = chunking::chunk_file(absolute_path).await {
+                local_file = fs::File::open(absolute_path).await.ok();
+                local_chunks = chunked
+                    .chunks
+                    .into_iter()
+                    .map(|c| (c.hash.to_owned(), c))
+                    .collect();
+            }
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        let mut downloaded_size = 0;
+
+        for batch in chunks.chunks(DOWNLOAD_CHUNK_BATCH) {
+            let mut missing = batch
+                .iter()
+                .filter(|c| local_file.is_none() || !local_chunks.contains_key(&c.hash))
+                .map(|c| c.hash.to_owned())
+                .collect::<Vec<_>>();
+            missing.sort();
+            missing.dedup();
+
+            let mut downloaded = HashMap::new();
+
+            if !missing.is_empty() {
+                let mut download_res = self
+                    .client
+                    .download_chunks(proto::DownloadChunksRequest { hashes: missing })
+                    .await?
+                    .into_inner();
+
+                while let Some(chunk) = download_res.next().await {
+                    let chunk = chunk?;
+                    downloaded_size += chunk.data.len() as u64;
+                    downloaded.insert(chunk.hash, chunk.data);
+                }
+            }
+
+            for chunk in batch {
+                let data = match (downloaded.get(&chunk.hash), &mut local_file) {
+                    (Some(data), _) => data.to_owned(),
+                    (None, Some(local_file)) => match local_chunks.get(&chunk.hash) {
+                        Some(local_chunk) => chunking::read_chunk(local_file, local_chunk).await?,
+                        None => Vec::new(),
+                    },
+                    (None, None) => Vec::new(),
+                };
+
+                // the local file may have changed since it was chunked
+                let data = match blake3::hash(&data).to_string() == chunk.hash {
+                    true => data,
+                    false => self.download_chunk(&chunk.hash).await?,
+                };
+
+                hasher.update(&data);
+                fs_file.write_all(&data).await?;
+            }
+        }
+
+        if hasher.finalize().to_string() != api_file.hash {
+            return Err(anyhow::anyhow!(
+                "downloaded file hash does not match api file hash"
+            ));
+        }
+
+        tracing::debug!(
+            "downloaded {} of {} bytes of {}",
+            downloaded_size,
+            api_file.size,
+            api_file.path
+        );
+
+        fs_file.shutdown().await?;
+        Ok(api_file.hash.to_owned())
+    }
+
+    /// downloads a single chunk again and verifies it
+    async fn download_chunk(&mut self, hash: &str) -> Result<Vec<u8>, anyhow::Error> {
+        tracing::warn!("chunk {} is corrupted, downloading it again", hash);
+
+        let mut download_res = self
+            .client
+            .download_chunks(proto::DownloadChunksRequest {
+                hashes: vec![hash.to_owned()],
+            })
+            .await?
+            .into_inner();
+
+        let data = match download_res.next().await {
+            Some(chunk) => chunk?.data,
+            None => Vec::new(),
+        };
+
+        if blake3::hash(&data).to_string() != hash {
+            return Err(anyhow::anyhow!("chunk {} does not match its hash", hash));
+        }
+
+        Ok(data)
     }
 
+    /// uploads the file at `absolute_path`, encrypted files are staged in the temp dir and
+    /// `info` is amended with the encrypted path, hash and size
     pub async fn upload_file(
+        &mut self,
+        absolute_path: &Path,
+        mut info: proto::UploadInfo,
+    ) -> Result<proto::File, anyhow::Error> {
+        let crypto = match &self.crypto {
+            Some(crypto) => crypto.clone(),
+            None if info.size >= self.multipart_threshold => {
+                return self.upload_multipart(absolute_path, info).await;
+            }
+            // every upload encrypts with a new nonce, so only plaintext chunks repeat
+            None => return self.upload_chunks(absolute_path, info).await,
+        };
+
+        let encrypted_path = std::env::temp_dir().join(format!(
+            ".~upload~{}-{}",
+            std::process::id(),
+            blake3::hash(info.path.as_bytes())
+        ));
+
+        let upload_res = async {
+            let (hash, size) = crypto
+                .encrypt_file(absolute_path, &encrypted_path, &info.path)
+                .await?;
+
+            info.path = crypto.encrypt_path(&info.path)?;
+            info.hash = hash;
+            info.size = size;
+
+            if info.size >= self.multipart_threshold {
+                return self.upload_multipart(&encrypted_path, info).await;
+            }
+
+            let fs_file = fs::File::open(&encrypted_path).await?;
+            self.upload_stream(fs_file, info).await
+        }
+        .await;
+
+        fs::remove_file(encrypted_path).await.ok();
+
+        let api_file = upload_res.map_err(|e| self.decrypt_conflict(e))?;
+        self.decrypt_file(api_file)
+    }
+
+    /// uploads only the chunks of the file the server does not have yet and commits the
+    /// file as the list of its chunks
+    async fn upload_chunks(
+        &mut self,
+        absolute_path: &Path,
+        info: proto::UploadInfo,
+    ) -> Result<proto::File, anyhow::Error> {
+        let chunked = chunking::chunk_file(absolute_path).await?;
+
+        if chunked.hash != info.hash || chunked.size != info.size {
+            return Err(anyhow::anyhow!("file changed while it was uploaded"));
+        }
+
+        let hashes = chunked
+            .chunks
+            .iter()
+            .map(|c| c.hash.to_owned())
+            .collect::<Vec<_>>();
+
+        let mut missing = self
+            .client
+            .find_missing_chunks(proto::FindMissingChunksRequest {
+                hashes: hashes.to_owned(),
+            })
+            .await?
+            .into_inner()
+            .hashes
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        // every missing chunk is uploaded once, even if the file contains it repeatedly
+        let upload = chunked
+            .chunks
+            .into_iter()
+            .filter(|c| missing.remove(&c.hash))
+            .collect::<Vec<_>>();
+
+        tracing::debug!(
+            "uploading {} of {} chunks of {}",
+            upload.len(),
+            hashes.len(),
+            info.path
+        );
+
+        if !upload.is_empty() {
+            let mut fs_file = fs::File::open(absolute_path).await?;
+
+            let upload_stream = async_stream::stream! {
+                for chunk in upload {
+                    match chunking::read_chunk(&mut fs_file, &chunk).await {
+                        Ok(data) => yield proto::UploadChunkRequest { hash: chunk.hash, data },
+                        // the commit fails with the chunk missing
+                        Err(e) => {
+                            tracing::error!("failed to read chunk {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            };
+
+            self.client.upload_chunks(upload_stream).await?;
+        }
+
+        let commit_res = self
+            .client
+            .commit_chunks(proto::CommitChunksRequest {
+                path: info.path,
+                hash: info.hash,
+                size: info.size,
+                expected_hash: info.expected_hash,
+                chunks: hashes,
+                mtime: info.mtime,
+                mode: info.mode,
+            })
+            .await
+            .map_err(map_conflict)?;
+
+        Ok(commit_res.into_inner())
+    }
+
+    /// uploads a large file in parts over several concurrent streams, the server verifies
+    /// the whole file once all parts have arrived
+    async fn upload_multipart(
+        &mut self,
+        absolute_path: &Path,
+        info: proto::UploadInfo,
+    ) -> Result<proto::File, anyhow::Error> {
+        let upload = self
+            .client
+            .create_multipart_upload(proto::CreateMultipartUploadRequest {
+                info: Some(info),
+                part_size: MULTIPART_PART_SIZE,
+            })
+            .await
+            .map_err(map_conflict)?
+            .into_inner();
+
+        let parts = (0..upload.part_count)
+            .map(|number| upload_part(self.client.clone(), absolute_path, &upload, number));
+
+        let upload_res = futures::stream::iter(parts)
+            .buffer_unordered(MULTIPART_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await;
+
+        if let Err(e) = upload_res {
+            self.client
+                .abort_multipart_upload(proto::MultipartUploadRequest {
+                    upload_id: upload.id,
+                })
+                .await
+                .ok();
+            return Err(e);
+        }
+
+        let complete_res = self
+            .client
+            .complete_multipart_upload(proto::MultipartUploadRequest {
+                upload_id: upload.id,
+            })
+            .await
+            .map_err(map_conflict)?;
+
+        Ok(complete_res.into_inner())
+    }
+
+    async fn upload_stream(
         &mut self,
         fs_file: fs::File,
         info: proto::UploadInfo,
@@ -172,7 +1173,46 @@ impl FileApiService {
             }
         };
 
-        let upload_response = self.client.upload(upload_stream).await?;
+        let upload_response = self
+            .client
+            .upload(upload_stream)
+            .await
+            .map_err(map_conflict)?;
         Ok(upload_response.into_inner())
     }
 }
+
+/// uploads one part of a multipart upload, read from its offset in the file
+async fn upload_part(
+    mut client: FileServiceClient<InterceptedService<Channel, AuthInterceptor>>,
+    absolute_path: &Path,
+    upload: &proto::MultipartUpload,
+    number: u32,
+) -> Result<(), anyhow::Error> {
+    let mut fs_file = fs::File::open(absolute_path).await?;
+    fs_file
+        .seek(SeekFrom::Start(number as u64 * upload.part_size))
+        .await?;
+
+    let mut part_stream = ReaderStream::new(fs_file.take(upload.part_size));
+    let info = proto::PartInfo {
+        upload_id: upload.id.to_owned(),
+        number,
+    };
+
+    let upload_stream = async_stream::stream! {
+        yield proto::UploadPartRequest {
+            part: Some(proto::upload_part_request::Part::Info(info)),
+        };
+
+        // the server rejects a part that ends early
+        while let Some(Ok(bytes)) = part_stream.next().await {
+            yield proto::UploadPartRequest {
+                part: Some(proto::upload_part_request::Part::Chunk(bytes.to_vec())),
+            };
+        }
+    };
+
+    client.upload_part(upload_stream).await?;
+    Ok(())
+}
@@ -0,0 +1,388 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit, OsRng,
+    },
+    Key, XChaCha20Poly1305, XNonce,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+/// marks files encrypted by the client, followed by the stream nonce
+const MAGIC: &[u8] = b"CLDE2E01";
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+/// the stream construction uses 5 bytes of the nonce for the segment counter
+const STREAM_NONCE_SIZE: usize = 19;
+const HEADER_SIZE: usize = MAGIC.len() + STREAM_NONCE_SIZE;
+
+/// Encrypts file content and, optionally, file names before they leave the client.
+///
+/// The keys are derived from a passphrase, salted with the user id, so every device of a
+/// user derives the same keys. The content of a file is encrypted with a key derived from
+/// its path, so the server can not pass off the content of one file as another.
+pub struct CryptoService {
+    content_key: [u8; 32],
+    names: Option<(XChaCha20Poly1305, [u8; 32])>,
+}
+
+impl CryptoService {
+    pub fn new(
+        passphrase: &str,
+        user_id: &str,
+        encrypt_names: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let salt = format!("cloud-e2e:{}", user_id);
+        let mut master_key = [0; 32];
+
+        argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut master_key)
+            .map_err(|e| anyhow!("failed to derive key: {}", e))?;
+
+        let content_key = blake3::derive_key("cloud-rs e2e content key", &master_key);
+        let names = match encrypt_names {
+            true => {
+                let name_key = blake3::derive_key("cloud-rs e2e name key", &master_key);
+                let name_nonce_key = blake3::derive_key("cloud-rs e2e name nonce key", &master_key);
+                Some((XChaCha20Poly1305::new(&name_key.into()), name_nonce_key))
+            }
+            false => None,
+        };
+
+        Ok(Self { content_key, names })
+    }
+
+    /// the content key of the file at the plaintext `path`
+    fn file_key(&self, path: &str) -> Key {
+        (*blake3::keyed_hash(&self.content_key, path.as_bytes()).as_bytes()).into()
+    }
+
+    pub fn encrypts_names(&self) -> bool {
+        self.names.is_some()
+    }
+
+    /// encrypts every component of an absolute path
+    ///
+    /// the nonce is derived from the name, so a path always encrypts to the same remote path
+    /// and can be looked up on the server
+    pub fn encrypt_path(&self, path: &str) -> Result<String, anyhow::Error> {
+        let (cipher, nonce_key) = match &self.names {
+            Some(n) => n,
+            None => return Ok(path.to_owned()),
+        };
+
+        let mut encrypted = String::new();
+
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let hash = blake3::keyed_hash(nonce_key, name.as_bytes());
+            let nonce = XNonce::from_slice(&hash.as_bytes()[..NONCE_SIZE]);
+
+            let mut sealed = nonce.to_vec();
+            sealed.extend(
+                cipher
+                    .encrypt(nonce, name.as_bytes())
+                    .map_err(|_| anyhow!("failed to encrypt file name"))?,
+            );
+
+            encrypted.push('/');
+            encrypted.push_str(&URL_SAFE_NO_PAD.encode(sealed));
+        }
+
+        Ok(encrypted)
+    }
+
+    pub fn decrypt_path(&self, path: &str) -> Result<String, anyhow::Error> {
+        let (cipher, _) = match &self.names {
+            Some(n) => n,
+            None => return Ok(path.to_owned()),
+        };
+
+        let mut decrypted = String::new();
+
+        for name in path.split('/').filter(|n| !n.is_empty()) {
+            let sealed = URL_SAFE_NO_PAD.decode(name)?;
+
+            if sealed.len() < NONCE_SIZE {
+                bail!("file name is not encrypted");
+            }
+
+            let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+            let name = cipher
+                .decrypt(XNonce::from_slice(nonce), ciphertext)
+                .map_err(|_| anyhow!("failed to decrypt file name"))?;
+
+            decrypted.push('/');
+            decrypted.push_str(&String::from_utf8(name)?);
+        }
+
+        Ok(decrypted)
+    }
+
+    /// encrypts the file at `src` into `dst` for the plaintext `path` and returns the hash
+    /// and size of the encrypted file, which the server verifies the upload against
+    pub async fn encrypt_file(
+        &self,
+        src: &Path,
+        dst: &Path,
+        path: &str,
+    ) -> Result<(String, u64), anyhow::Error> {
+        let mut stream_nonce = [0; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut stream_nonce);
+
+        let mut encryptor = EncryptorBE32::from_aead(
+            XChaCha20Poly1305::new(&self.file_key(path)),
+            GenericArray::from_slice(&stream_nonce),
+        );
+
+        let mut src_file = fs::File::open(src).await?;
+        let mut dst_file = fs::File::create(dst).await?;
+        let mut hasher = blake3::Hasher::new();
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&stream_nonce);
+        dst_file.write_all(&header).await?;
+        hasher.update(&header);
+        let mut size = header.len() as u64;
+
+        let mut buf = vec![0; SEGMENT_SIZE];
+        let mut len = read_segment(&mut src_file, &mut buf).await?;
+
+        // a segment is only sealed once it is known whether another one follows
+        loop {
+            let mut next_buf = vec![0; SEGMENT_SIZE];
+            let next_len = match len {
+                SEGMENT_SIZE => read_segment(&mut src_file, &mut next_buf).await?,
+                _ => 0,
+            };
+
+            if next_len == 0 {
+                let sealed = encryptor
+                    .encrypt_last(&buf[..len])
+                    .map_err(|_| anyhow!("failed to encrypt file"))?;
+                dst_file.write_all(&sealed).await?;
+                hasher.update(&sealed);
+                size += sealed.len() as u64;
+                break;
+            }
+
+            let sealed = encryptor
+                .encrypt_next(&buf[..len])
+                .map_err(|_| anyhow!("failed to encrypt file"))?;
+            dst_file.write_all(&sealed).await?;
+            hasher.update(&sealed);
+            size += sealed.len() as u64;
+
+            buf = next_buf;
+            len = next_len;
+        }
+
+        dst_file.shutdown().await?;
+        Ok((hasher.finalize().to_string(), size))
+    }
+
+    /// decrypts the file at the plaintext `path`, content without the encryption header is
+    /// only passed through as it is if `allow_plaintext` is set
+    pub fn decryptor(&self, path: &str, allow_plaintext: bool) -> FileDecryptor {
+        FileDecryptor {
+            file_key: self.file_key(path),
+            decryptor: None,
+            allow_plaintext,
+            plaintext: false,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Decrypts a file that arrives in chunks of arbitrary size.
+///
+/// Files without the encryption header are rejected unless they are known to have been
+/// uploaded before encryption was enabled, otherwise the server could replace an encrypted
+/// file with any content.
+pub struct FileDecryptor {
+    file_key: Key,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    allow_plaintext: bool,
+    /// set once the file turned out not to be encrypted
+    plaintext: bool,
+    buf: Vec<u8>,
+}
+
+impl FileDecryptor {
+    /// returns the plaintext of all segments that are complete and known not to be the last
+    pub fn update(&mut self, bytes: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        self.buf.extend_from_slice(bytes);
+
+        if self.plaintext {
+            return Ok(std::mem::take(&mut self.buf));
+        }
+
+        if self.decryptor.is_none() {
+            let magic_len = self.buf.len().min(MAGIC.len());
+
+            if self.buf[..magic_len] != MAGIC[..magic_len] {
+                if !self.allow_plaintext {
+                    bail!("file is not encrypted");
+                }
+
+                self.plaintext = true;
+                return Ok(std::mem::take(&mut self.buf));
+            }
+
+            if self.buf.len() < HEADER_SIZE {
+                return Ok(Vec::new());
+            }
+
+            let header = self.buf.drain(..HEADER_SIZE).collect::<Vec<_>>();
+
+            self.decryptor = Some(DecryptorBE32::from_aead(
+                XChaCha20Poly1305::new(&self.file_key),
+                GenericArray::from_slice(&header[MAGIC.len()..]),
+            ));
+        }
+
+        let decryptor = self.decryptor.as_mut().unwrap();
+        let sealed_size = SEGMENT_SIZE + TAG_SIZE;
+        let mut plaintext = Vec::new();
+
+        while self.buf.len() > sealed_size {
+            let rest = self.buf.split_off(sealed_size);
+            plaintext.extend(
+                decryptor
+                    .decrypt_next(self.buf.as_slice())
+                    .map_err(|_| anyhow!("file failed authentication"))?,
+            );
+            self.buf = rest;
+        }
+
+        Ok(plaintext)
+    }
+
+    /// decrypts the last segment, fails if the file was truncated
+    pub fn finish(self) -> Result<Vec<u8>, anyhow::Error> {
+        // a file shorter than the magic, e.g. an empty one, can not be encrypted
+        let unencrypted = self.decryptor.is_none() && self.buf.len() < MAGIC.len();
+
+        if self.plaintext || (unencrypted && self.allow_plaintext) {
+            return Ok(self.buf);
+        }
+
+        let decryptor = self
+            .decryptor
+            .ok_or_else(|| anyhow!("file was truncated"))?;
+
+        decryptor
+            .decrypt_last(self.buf.as_slice())
+            .map_err(|_| anyhow!("file failed authentication"))
+    }
+}
+
+/// fills the buffer unless the file ends first and returns the number of bytes read
+async fn read_segment(file: &mut fs::File, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+    let mut len = 0;
+
+    while len < buf.len() {
+        match file.read(&mut buf[len..]).await? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::crypto_service::CryptoService;
+
+    #[test]
+    fn encrypt_path() {
+        let crypto = CryptoService::new("passphrase", "user", true).unwrap();
+
+        let encrypted = crypto.encrypt_path("/dir/file.txt").unwrap();
+        assert_ne!("/dir/file.txt", encrypted);
+        assert!(encrypted.starts_with('/'));
+        assert_eq!(3, encrypted.split('/').count());
+        assert_eq!(encrypted, crypto.encrypt_path("/dir/file.txt").unwrap());
+        assert_eq!("/dir/file.txt", crypto.decrypt_path(&encrypted).unwrap());
+
+        let other = CryptoService::new("other", "user", true).unwrap();
+        assert!(other.decrypt_path(&encrypted).is_err());
+
+        let plain = CryptoService::new("passphrase", "user", false).unwrap();
+        assert_eq!(
+            "/dir/file.txt",
+            plain.encrypt_path("/dir/file.txt").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypt_file() {
+        let crypto = CryptoService::new("passphrase", "user", false).unwrap();
+        let dir = std::env::temp_dir().join(format!("cloud-e2e-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        for len in [0, 10, 64 * 1024, 64 * 1024 * 2 + 7] {
+            let plaintext = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            tokio::fs::write(dir.join("plain"), &plaintext)
+                .await
+                .unwrap();
+
+            let (hash, size) = crypto
+                .encrypt_file(&dir.join("plain"), &dir.join("sealed"), "/a.txt")
+                .await
+                .unwrap();
+
+            let sealed = tokio::fs::read(dir.join("sealed")).await.unwrap();
+            assert_eq!(blake3::hash(&sealed).to_string(), hash);
+            assert_eq!(sealed.len() as u64, size);
+
+            // feed the sealed file in chunks unrelated to the segments
+            let mut decryptor = crypto.decryptor("/a.txt", false);
+            let mut decrypted = Vec::new();
+
+            for chunk in sealed.chunks(1000) {
+                decrypted.extend(decryptor.update(chunk).unwrap());
+            }
+
+            decrypted.extend(decryptor.finish().unwrap());
+            assert_eq!(plaintext, decrypted);
+
+            let mut truncated = crypto.decryptor("/a.txt", false);
+            truncated.update(&sealed[..sealed.len() - 1]).unwrap();
+            assert!(truncated.finish().is_err());
+
+            // the content of another file
+            let mut moved = crypto.decryptor("/b.txt", false);
+            let moved_res = moved.update(&sealed).and_then(|_| moved.finish());
+            assert!(moved_res.is_err());
+
+            // unencrypted content is only accepted if it is expected
+            let mut unexpected = crypto.decryptor("/a.txt", false);
+            let unexpected_res = unexpected
+                .update(&plaintext)
+                .and_then(|_| unexpected.finish());
+            assert!(unexpected_res.is_err());
+
+            let mut unencrypted = crypto.decryptor("/a.txt", true);
+            let mut passed = Vec::new();
+
+            for chunk in plaintext.chunks(3) {
+                passed.extend(unencrypted.update(chunk).unwrap());
+            }
+
+            passed.extend(unencrypted.finish().unwrap());
+            assert_eq!(plaintext, passed);
+        }
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
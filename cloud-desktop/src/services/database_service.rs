@@ -30,11 +30,29 @@ impl DatabaseService {
                 id TEXT NOT NULL,
                 path TEXT NOT NULL,
                 hash TEXT NOT NULL,
+                remote_hash TEXT NOT NULL,
                 CONSTRAINT files_PK PRIMARY KEY (id))"
         )
         .execute(&mut db)
         .await?;
 
+        // databases created before end-to-end encryption only stored a single hash
+        let has_remote_hash = sqlx::query("SELECT remote_hash FROM files LIMIT 0")
+            .execute(&mut db)
+            .await
+            .is_ok();
+
+        if !has_remote_hash {
+            tracing::info!("adding remote_hash column to sqlite database");
+
+            sqlx::query("ALTER TABLE files ADD COLUMN remote_hash TEXT NOT NULL DEFAULT ''")
+                .execute(&mut db)
+                .await?;
+            sqlx::query("UPDATE files SET remote_hash = hash")
+                .execute(&mut db)
+                .await?;
+        }
+
         Ok(DatabaseService { pool })
     }
 
@@ -51,14 +69,17 @@ impl DatabaseService {
         Ok(())
     }
 
-    pub async fn add_file(&self, file: &proto::File) -> Result<(), sqlx::Error> {
+    /// `hash` is the hash of the local file, which differs from the server hash if the
+    /// file is encrypted end-to-end
+    pub async fn add_file(&self, file: &proto::File, hash: &str) -> Result<(), sqlx::Error> {
         let mut db = self.pool.acquire().await?;
 
         sqlx::query!(
-            "INSERT INTO files (id, path, hash)
-            VALUES (?1, ?2, ?3)",
+            "INSERT INTO files (id, path, hash, remote_hash)
+            VALUES (?1, ?2, ?3, ?4)",
             file.id,
             file.path,
+            hash,
             file.hash
         )
         .execute(&mut db)
@@ -67,13 +88,18 @@ impl DatabaseService {
         Ok(())
     }
 
-    pub async fn replace_file_hash(&self, file: &proto::File) -> Result<(), sqlx::Error> {
+    pub async fn replace_file_hash(
+        &self,
+        file: &proto::File,
+        hash: &str,
+    ) -> Result<(), sqlx::Error> {
         let mut db = self.pool.acquire().await?;
 
         sqlx::query!(
             "UPDATE files
-            SET hash = ?1
-            WHERE id = ?2",
+            SET hash = ?1, remote_hash = ?2
+            WHERE id = ?3",
+            hash,
             file.hash,
             file.id,
         )
@@ -147,4 +173,6 @@ pub struct DbFile {
     pub id: String,
     pub path: String,
     pub hash: String,
+    /// hash of the server file at the last sync
+    pub remote_hash: String,
 }
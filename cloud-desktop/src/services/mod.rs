@@ -1,2 +1,3 @@
 pub mod api_service;
+pub mod crypto_service;
 pub mod database_service;
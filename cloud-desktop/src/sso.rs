@@ -0,0 +1,112 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::anyhow;
+use cloud_proto::proto;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use url::Url;
+
+use crate::services::api_service::AuthApiService;
+
+const CALLBACK_PATH: &str = "/callback";
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// signs in through an identity provider of the server
+///
+/// the authorization page is opened in the system browser, which redirects back to a
+/// listener on the loopback interface once the user has signed in
+pub async fn sign_in(
+    auth_service: &mut AuthApiService,
+    provider: String,
+) -> Result<proto::AuthLoginResponse, anyhow::Error> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = format!("http://{}{}", listener.local_addr()?, CALLBACK_PATH);
+
+    let begin_res = auth_service
+        .get_client()
+        .begin_sso(proto::BeginSsoRequest {
+            provider,
+            redirect_uri,
+        })
+        .await?
+        .into_inner();
+
+    open::that(&begin_res.authorization_url)?;
+
+    let (code, state) = tokio::time::timeout(SIGN_IN_TIMEOUT, wait_for_redirect(&listener))
+        .await
+        .map_err(|_| anyhow!("sign-in timed out"))??;
+
+    if state != begin_res.state {
+        return Err(anyhow!("sign-in state does not match"));
+    }
+
+    let login_res = auth_service
+        .get_client()
+        .complete_sso(proto::CompleteSsoRequest { state, code })
+        .await?
+        .into_inner();
+
+    Ok(login_res)
+}
+
+/// accepts connections until the browser is redirected to the callback and returns its
+/// authorization code and state
+async fn wait_for_redirect(listener: &TcpListener) -> Result<(String, String), anyhow::Error> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut buf = vec![0; 8192];
+        let len = stream.read(&mut buf).await?;
+        let req = String::from_utf8_lossy(&buf[..len]);
+
+        let target = req
+            .lines()
+            .next()
+            .and_then(|l| l.split_whitespace().nth(1))
+            .unwrap_or("/");
+        let url = Url::parse(&format!("http://127.0.0.1{}", target))?;
+
+        if url.path() != CALLBACK_PATH {
+            respond(&mut stream, "404 Not Found", "").await?;
+            continue;
+        }
+
+        let mut params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+
+        if let Some(error) = params.remove("error") {
+            respond(&mut stream, "200 OK", "Sign-in failed, return to cloud.").await?;
+            return Err(anyhow!("identity provider returned {}", error));
+        }
+
+        match (params.remove("code"), params.remove("state")) {
+            (Some(code), Some(state)) => {
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    "Signed in, you can close this window and return to cloud.",
+                )
+                .await?;
+                return Ok((code, state));
+            }
+            _ => {
+                respond(&mut stream, "400 Bad Request", "").await?;
+            }
+        }
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> Result<(), anyhow::Error> {
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(res.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use std::error::Error;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    tonic_build::compile_protos("proto/admin.proto")?;
     tonic_build::compile_protos("proto/auth.proto")?;
     tonic_build::compile_protos("proto/file.proto")?;
     tonic_build::compile_protos("proto/user.proto")?;
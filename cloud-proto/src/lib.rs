@@ -1,7 +1,9 @@
 pub mod proto {
+    tonic::include_proto!("admin");
     tonic::include_proto!("auth");
     tonic::include_proto!("file");
     tonic::include_proto!("user");
 }
 
+pub use prost;
 pub use prost_types;